@@ -5,15 +5,17 @@ async function addToCart(product_id, quantity) {
     quantity: Number(quantity),
   };
 
-  const token = getBearerToken();
+  // Visitors that aren't logged in can still fill up a guest cart
+  if (getCookie("access_token") === undefined && getCookie("cart_token") === undefined) {
+    await startGuestCart();
+  }
 
-  fetch("http://127.0.0.1:3000/add_to_cart", {
+  authorizedFetch("http://127.0.0.1:3000/add_to_cart", {
     method: "POST",
     body: JSON.stringify(cartItem),
-    headers: {
+    headers: cartHeaders({
       "Content-Type": "application/json",
-      "Authorization": `Bearer ${token}`,
-    }
+    })
   })
     .then((response) => {
       if (response.status == 400 || response.status == 401) {
@@ -29,16 +31,96 @@ async function addToCart(product_id, quantity) {
     })
 }
 
+async function startGuestCart() {
+  const response = await fetch("http://127.0.0.1:3000/cart/guest", {
+    method: "POST",
+    headers: {
+      "Accept": "application/json",
+    }
+  });
+  const guestCart = await response.json();
+
+  document.cookie = `cart_token=[${guestCart.cart_token}]; max-age=${30 * 24 * 60 * 60}`;
+}
+
+// Logged in users use their own cart, otherwise the guest cart is used if one was started.
+// The guest cart is moved into the user's cart when they login.
+function cartHeaders(headers) {
+  const token = getCookie("access_token");
+  const cartToken = getCookie("cart_token");
+  if (token !== undefined) {
+    headers["Authorization"] = `Bearer ${token}`;
+  } else if (cartToken !== undefined) {
+    headers["X-Cart-Token"] = cartToken;
+  }
+
+  return headers;
+}
+
+// Amounts come as {minor_units, currency}, e.g. {minor_units: 1250, currency: "MYR"} is RM 12.50
+function formatMoney(money) {
+  const format = new Intl.NumberFormat(undefined, { style: "currency", currency: money.currency });
+  const decimalPlaces = format.resolvedOptions().maximumFractionDigits;
+
+  return format.format(money.minor_units / 10 ** decimalPlaces);
+}
+
 function getBearerToken() {
-  const cookie = `; ${document.cookie}`;
-  const parts = cookie.split("; access_token=");
-  let token;
-  if (parts.length === 2) {
-    token = parts.pop().split(';').shift();
-  } else {
+  const token = getCookie("access_token");
+  if (token === undefined) {
     window.location.replace("/login.html");
   }
-  token = token.replace(/^\[|\]$/g, '');  // trim the leading and trailing brackets from the token
 
   return token;
+}
+
+function getCookie(name) {
+  const cookie = `; ${document.cookie}`;
+  const parts = cookie.split(`; ${name}=`);
+  if (parts.length !== 2) {
+    return undefined;
+  }
+  const value = parts.pop().split(';').shift();
+
+  return value.replace(/^\[|\]$/g, '');  // trim the leading and trailing brackets from the value
+}
+
+function setTokenCookies(tokens) {
+  document.cookie = `access_token=[${tokens.access_token}]`;
+  document.cookie = `refresh_token=[${tokens.refresh_token}]`;
+}
+
+// Access tokens only last a few minutes, so when one is rejected we swap the
+// refresh token for a new pair of tokens and try the request one more time
+async function authorizedFetch(url, options) {
+  let response = await fetch(url, options);
+
+  if (response.status == 401 && await refreshTokens()) {
+    options.headers["Authorization"] = `Bearer ${getBearerToken()}`;
+    response = await fetch(url, options);
+  }
+
+  return response;
+}
+
+async function refreshTokens() {
+  const refreshToken = getCookie("refresh_token");
+  if (refreshToken === undefined) {
+    return false;
+  }
+
+  const response = await fetch("http://127.0.0.1:3000/token/refresh", {
+    method: "POST",
+    body: JSON.stringify({ refresh_token: refreshToken }),
+    headers: {
+      "Content-Type": "application/json",
+    }
+  });
+  if (response.status != 200) {
+    return false;
+  }
+
+  setTokenCookies(await response.json());
+
+  return true;
 }
\ No newline at end of file
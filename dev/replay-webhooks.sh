@@ -0,0 +1,40 @@
+#!/usr/bin/env bash
+# Replays recorded payment provider events against the server, signed the same way the
+# provider signs them. Sending an event more than once shows that it's only applied once.
+#
+# Usage: dev/replay-webhooks.sh <provider_reference> <event file>...
+# e.g.   dev/replay-webhooks.sh fake_0f8fad5bd9cb469fa16570867728950e dev/webhooks/payment-authorized.json
+#
+# {{reference}} in the event files is replaced with the given provider reference.
+# The secret is read from PAYMENT_WEBHOOK_SECRET, or from the .env file if it isn't set.
+set -euo pipefail
+
+if [ "$#" -lt 2 ]; then
+  echo "Usage: $0 <provider_reference> <event file>..." >&2
+  exit 1
+fi
+
+if [ -z "${PAYMENT_WEBHOOK_SECRET:-}" ] && [ -f .env ]; then
+  PAYMENT_WEBHOOK_SECRET=$(grep '^PAYMENT_WEBHOOK_SECRET=' .env | cut -d '=' -f 2-)
+fi
+if [ -z "${PAYMENT_WEBHOOK_SECRET:-}" ]; then
+  echo "PAYMENT_WEBHOOK_SECRET isn't set" >&2
+  exit 1
+fi
+
+webhook_url=${WEBHOOK_URL:-http://127.0.0.1:3000/webhooks/payments}
+reference=$1
+shift
+
+for event_file in "$@"; do
+  body=$(sed "s/{{reference}}/${reference}/g" "$event_file")
+  timestamp=$(date +%s)
+  signature=$(printf '%s.%s' "$timestamp" "$body" | openssl dgst -sha256 -hmac "$PAYMENT_WEBHOOK_SECRET" | sed 's/^.* //')
+
+  echo -n "$(basename "$event_file"): "
+  curl --silent --write-out ' (%{http_code})\n' \
+    --request POST "$webhook_url" \
+    --header 'Content-Type: application/json' \
+    --header "X-Webhook-Signature: t=${timestamp},v1=${signature}" \
+    --data-binary "$body"
+done
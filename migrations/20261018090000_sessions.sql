@@ -0,0 +1,18 @@
+-- Add migration script here
+
+CREATE TABLE IF NOT EXISTS sessions (
+	session_id CHAR(32) PRIMARY KEY NOT NULL,
+	token_hash CHAR(64) UNIQUE NOT NULL,
+	user_id CHAR(32) NOT NULL,
+	created_at TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP NOT NULL,
+	last_seen_at TIMESTAMP NOT NULL,
+	user_agent TEXT,
+	ip_address VARCHAR(45),
+	CONSTRAINT fk_users
+		FOREIGN KEY (user_id)
+			REFERENCES users(user_id)
+			ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_expires_at ON sessions(expires_at);
@@ -0,0 +1,20 @@
+-- Add migration script here
+
+-- Every refresh token handed out for a session is kept so that reuse of an
+-- already rotated token can be detected. The session is the token family.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+	token_hash CHAR(64) PRIMARY KEY NOT NULL,
+	session_id CHAR(32) NOT NULL,
+	created_at TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP NOT NULL,
+	used_at TIMESTAMP,
+	CONSTRAINT fk_sessions
+		FOREIGN KEY (session_id)
+			REFERENCES sessions(session_id)
+			ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_session_id ON refresh_tokens(session_id);
+
+-- Sessions created before refresh tokens existed can never be refreshed
+DELETE FROM sessions;
@@ -0,0 +1,51 @@
+-- Add migration script here
+
+CREATE TABLE IF NOT EXISTS roles (
+	role_name VARCHAR(20) PRIMARY KEY NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS permissions (
+	permission_name VARCHAR(30) PRIMARY KEY NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+	role_name VARCHAR(20) NOT NULL,
+	permission_name VARCHAR(30) NOT NULL,
+	PRIMARY KEY (role_name, permission_name),
+	CONSTRAINT fk_roles
+		FOREIGN KEY (role_name)
+			REFERENCES roles(role_name)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_permissions
+		FOREIGN KEY (permission_name)
+			REFERENCES permissions(permission_name)
+			ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+	user_id CHAR(32) NOT NULL,
+	role_name VARCHAR(20) NOT NULL,
+	PRIMARY KEY (user_id, role_name),
+	CONSTRAINT fk_users
+		FOREIGN KEY (user_id)
+			REFERENCES users(user_id)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_roles
+		FOREIGN KEY (role_name)
+			REFERENCES roles(role_name)
+			ON DELETE CASCADE
+);
+
+INSERT INTO roles (role_name) VALUES
+	('customer'),
+	('admin');
+
+INSERT INTO permissions (permission_name) VALUES
+	('manage_users');
+
+INSERT INTO role_permissions (role_name, permission_name) VALUES
+	('admin', 'manage_users');
+
+-- Everyone who signed up before roles existed is a customer
+INSERT INTO user_roles (user_id, role_name)
+SELECT user_id, 'customer' FROM users;
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- Archived products are hidden from the catalog but kept for existing orders
+ALTER TABLE products ADD COLUMN archived_at TIMESTAMP;
+
+INSERT INTO permissions (permission_name) VALUES
+	('manage_products');
+
+INSERT INTO role_permissions (role_name, permission_name) VALUES
+	('admin', 'manage_products');
@@ -0,0 +1,31 @@
+-- Add migration script here
+
+-- A full-text index over the product names and descriptions. It only stores the index,
+-- the text itself is read from the products table, so the triggers keep the two in sync.
+CREATE VIRTUAL TABLE IF NOT EXISTS products_fts USING fts5(
+	product_name,
+	product_description,
+	content='products',
+	content_rowid='product_id',
+	tokenize='porter unicode61'
+);
+
+INSERT INTO products_fts (rowid, product_name, product_description)
+SELECT product_id, product_name, product_description FROM products;
+
+CREATE TRIGGER IF NOT EXISTS products_fts_insert AFTER INSERT ON products BEGIN
+	INSERT INTO products_fts (rowid, product_name, product_description)
+	VALUES (new.product_id, new.product_name, new.product_description);
+END;
+
+CREATE TRIGGER IF NOT EXISTS products_fts_delete AFTER DELETE ON products BEGIN
+	INSERT INTO products_fts (products_fts, rowid, product_name, product_description)
+	VALUES ('delete', old.product_id, old.product_name, old.product_description);
+END;
+
+CREATE TRIGGER IF NOT EXISTS products_fts_update AFTER UPDATE OF product_name, product_description ON products BEGIN
+	INSERT INTO products_fts (products_fts, rowid, product_name, product_description)
+	VALUES ('delete', old.product_id, old.product_name, old.product_description);
+	INSERT INTO products_fts (rowid, product_name, product_description)
+	VALUES (new.product_id, new.product_name, new.product_description);
+END;
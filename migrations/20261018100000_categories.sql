@@ -0,0 +1,34 @@
+-- Add migration script here
+
+CREATE TABLE IF NOT EXISTS categories (
+	category_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	parent_id INT,
+	category_name VARCHAR(30) NOT NULL,
+	slug VARCHAR(40) UNIQUE NOT NULL,
+	display_order INT NOT NULL DEFAULT 0,
+	CONSTRAINT fk_categories
+		FOREIGN KEY (parent_id)
+			REFERENCES categories(category_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_categories_parent_id ON categories(parent_id);
+
+-- The categories that used to be hard-coded
+INSERT INTO categories (category_name, slug, display_order) VALUES
+	('Meat', 'meat', 1),
+	('Seafood', 'seafood', 2),
+	('Vegetable', 'vegetable', 3),
+	('Fruit', 'fruit', 4);
+
+-- Point every product at its category row, then drop the old text column and its CHECK
+-- constraint. SQLite only lets a column with a REFERENCES clause be added if it can be NULL.
+ALTER TABLE products ADD COLUMN category_id INT REFERENCES categories(category_id);
+
+UPDATE products
+SET category_id = (
+	SELECT category_id FROM categories WHERE categories.category_name = products.product_category
+);
+
+ALTER TABLE products DROP COLUMN product_category;
+
+CREATE INDEX IF NOT EXISTS idx_products_category_id ON products(category_id);
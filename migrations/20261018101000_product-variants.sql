@@ -0,0 +1,97 @@
+-- Add migration script here
+
+-- The versions of a product that can actually be bought, e.g. "Beef 500g" and "Beef 1kg".
+-- options is a JSON object of option names to values, e.g. {"weight": "500g"}
+CREATE TABLE IF NOT EXISTS product_variants (
+	variant_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	product_id INT NOT NULL,
+	sku VARCHAR(40) UNIQUE NOT NULL,
+	options TEXT NOT NULL DEFAULT '{}',
+	price REAL NOT NULL,
+	stock INT NOT NULL,
+	img_path TEXT,
+	is_default BOOLEAN NOT NULL DEFAULT FALSE,
+	CONSTRAINT fk_products
+		FOREIGN KEY (product_id)
+			REFERENCES products(product_id)
+			ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_product_variants_product_id ON product_variants(product_id);
+
+-- A product can only have one default variant
+CREATE UNIQUE INDEX IF NOT EXISTS idx_product_variants_default
+ON product_variants(product_id) WHERE is_default;
+
+-- products.price and products.stock are kept as a summary for listing, filtering and sorting.
+-- The price is the default variant's price and the stock is the total across all variants.
+CREATE TRIGGER IF NOT EXISTS product_variants_insert AFTER INSERT ON product_variants BEGIN
+	UPDATE products
+	SET price = COALESCE((SELECT price FROM product_variants WHERE product_id = new.product_id AND is_default), price),
+		stock = (SELECT COALESCE(SUM(stock), 0) FROM product_variants WHERE product_id = new.product_id)
+	WHERE product_id = new.product_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS product_variants_update AFTER UPDATE OF price, stock, is_default ON product_variants BEGIN
+	UPDATE products
+	SET price = COALESCE((SELECT price FROM product_variants WHERE product_id = new.product_id AND is_default), price),
+		stock = (SELECT COALESCE(SUM(stock), 0) FROM product_variants WHERE product_id = new.product_id)
+	WHERE product_id = new.product_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS product_variants_delete AFTER DELETE ON product_variants BEGIN
+	UPDATE products
+	SET stock = (SELECT COALESCE(SUM(stock), 0) FROM product_variants WHERE product_id = old.product_id)
+	WHERE product_id = old.product_id;
+END;
+
+-- Every existing product becomes a single default variant
+INSERT INTO product_variants (product_id, sku, price, stock, is_default)
+SELECT product_id, printf('MI-%05d', product_id), price, stock, TRUE FROM products;
+
+-- Carts and orders now point at the variant that was picked.
+-- Neither table has anything referencing it, so they can be safely rebuilt.
+CREATE TABLE IF NOT EXISTS new_cart_items (
+	user_id CHAR(32) NOT NULL,
+	variant_id INT NOT NULL,
+	quantity INT NOT NULL,
+	CONSTRAINT fk_users
+		FOREIGN KEY (user_id)
+			REFERENCES users(user_id)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_product_variants
+		FOREIGN KEY (variant_id)
+			REFERENCES product_variants(variant_id)
+			ON DELETE CASCADE
+);
+
+INSERT INTO new_cart_items (user_id, variant_id, quantity)
+SELECT cart_items.user_id, product_variants.variant_id, cart_items.quantity
+FROM cart_items
+INNER JOIN product_variants ON product_variants.product_id = cart_items.product_id AND product_variants.is_default;
+
+DROP TABLE cart_items;
+ALTER TABLE new_cart_items RENAME TO cart_items;
+
+-- Variants that have been ordered can't be deleted, so order history is never lost
+CREATE TABLE IF NOT EXISTS new_order_items (
+	order_id INT NOT NULL,
+	variant_id INT NOT NULL,
+	quantity SMALLINT NOT NULL,
+	PRIMARY KEY (order_id, variant_id),
+	CONSTRAINT fk_orders
+		FOREIGN KEY (order_id)
+			REFERENCES orders(order_id)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_product_variants
+		FOREIGN KEY (variant_id)
+			REFERENCES product_variants(variant_id)
+);
+
+INSERT INTO new_order_items (order_id, variant_id, quantity)
+SELECT order_items.order_id, product_variants.variant_id, order_items.quantity
+FROM order_items
+INNER JOIN product_variants ON product_variants.product_id = order_items.product_id AND product_variants.is_default;
+
+DROP TABLE order_items;
+ALTER TABLE new_order_items RENAME TO order_items;
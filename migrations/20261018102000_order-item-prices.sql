@@ -0,0 +1,14 @@
+-- Add migration script here
+
+-- What was actually paid for each line, recorded when the order is placed.
+-- line_subtotal is unit_price * quantity, and the line total is line_subtotal - discount + tax.
+ALTER TABLE order_items ADD COLUMN unit_price REAL NOT NULL DEFAULT 0;
+ALTER TABLE order_items ADD COLUMN line_subtotal REAL NOT NULL DEFAULT 0;
+ALTER TABLE order_items ADD COLUMN discount REAL NOT NULL DEFAULT 0;
+ALTER TABLE order_items ADD COLUMN tax REAL NOT NULL DEFAULT 0;
+
+-- The prices paid for existing orders were never recorded, so the current prices are the best guess
+UPDATE order_items
+SET unit_price = (SELECT price FROM product_variants WHERE product_variants.variant_id = order_items.variant_id);
+
+UPDATE order_items SET line_subtotal = unit_price * quantity;
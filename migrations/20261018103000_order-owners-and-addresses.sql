@@ -0,0 +1,24 @@
+-- Add migration script here
+
+-- Orders placed before this have no owner, since it was never recorded
+ALTER TABLE orders ADD COLUMN user_id CHAR(32) REFERENCES users(user_id);
+
+CREATE INDEX IF NOT EXISTS idx_orders_user_id ON orders(user_id);
+
+-- A copy of the addresses chosen at checkout, so changing or removing an
+-- address later doesn't change where an order was sent
+CREATE TABLE IF NOT EXISTS order_addresses (
+	order_id INT NOT NULL,
+	address_type TEXT NOT NULL CHECK (address_type IN ('Shipping', 'Billing')),
+	unit VARCHAR(20) NOT NULL,
+	street VARCHAR(30) NOT NULL,
+	city VARCHAR(20) NOT NULL,
+	postal_code INT NOT NULL,
+	state_province VARCHAR(20) NOT NULL,
+	country VARCHAR(20) NOT NULL,
+	PRIMARY KEY (order_id, address_type),
+	CONSTRAINT fk_orders
+		FOREIGN KEY (order_id)
+			REFERENCES orders(order_id)
+			ON DELETE CASCADE
+);
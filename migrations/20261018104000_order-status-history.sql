@@ -0,0 +1,43 @@
+-- Add migration script here
+
+-- Swap in a new order_status column with the full set of statuses. Rebuilding the table
+-- instead would delete every order item, since dropping orders cascades to them.
+ALTER TABLE orders RENAME COLUMN order_status TO old_order_status;
+
+ALTER TABLE orders ADD COLUMN order_status TEXT NOT NULL DEFAULT 'Pending'
+	CHECK (order_status IN ('Pending', 'Paid', 'Processing', 'Shipped', 'Delivered', 'Cancelled', 'Refunded'));
+
+UPDATE orders SET order_status = old_order_status;
+
+ALTER TABLE orders DROP COLUMN old_order_status;
+
+-- Every status an order has been in. from_status is NULL when the order was placed,
+-- and changed_by is NULL when the change wasn't made by a user.
+CREATE TABLE IF NOT EXISTS order_status_history (
+	history_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	order_id INT NOT NULL,
+	from_status TEXT,
+	to_status TEXT NOT NULL,
+	changed_by CHAR(32),
+	changed_at TIMESTAMP NOT NULL,
+	note TEXT,
+	CONSTRAINT fk_orders
+		FOREIGN KEY (order_id)
+			REFERENCES orders(order_id)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_users
+		FOREIGN KEY (changed_by)
+			REFERENCES users(user_id)
+			ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_order_status_history_order_id ON order_status_history(order_id);
+
+INSERT INTO order_status_history (order_id, from_status, to_status, changed_by, changed_at)
+SELECT order_id, NULL, order_status, user_id, creation_time FROM orders;
+
+INSERT INTO permissions (permission_name) VALUES
+	('manage_orders');
+
+INSERT INTO role_permissions (role_name, permission_name) VALUES
+	('admin', 'manage_orders');
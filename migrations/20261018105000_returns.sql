@@ -0,0 +1,59 @@
+-- Add migration script here
+
+-- A customer asking to send back some of the items from a delivered order
+CREATE TABLE IF NOT EXISTS returns (
+	return_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	order_id INT NOT NULL,
+	user_id CHAR(32) NOT NULL,
+	return_status TEXT NOT NULL CHECK (return_status IN ('Requested', 'Approved', 'Rejected')),
+	comment TEXT,
+	requested_at TIMESTAMP NOT NULL,
+	resolved_by CHAR(32),
+	resolved_at TIMESTAMP,
+	resolution_note TEXT,
+	CONSTRAINT fk_orders
+		FOREIGN KEY (order_id)
+			REFERENCES orders(order_id)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_users
+		FOREIGN KEY (user_id)
+			REFERENCES users(user_id)
+			ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_returns_order_id ON returns(order_id);
+
+CREATE TABLE IF NOT EXISTS return_items (
+	return_id INT NOT NULL,
+	variant_id INT NOT NULL,
+	quantity INT NOT NULL CHECK (quantity > 0),
+	reason_code TEXT NOT NULL CHECK (reason_code IN ('Damaged', 'WrongItem', 'NotAsDescribed', 'NoLongerNeeded', 'Other')),
+	PRIMARY KEY (return_id, variant_id),
+	CONSTRAINT fk_returns
+		FOREIGN KEY (return_id)
+			REFERENCES returns(return_id)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_product_variants
+		FOREIGN KEY (variant_id)
+			REFERENCES product_variants(variant_id)
+);
+
+-- Money given back to a customer. return_id is set when the refund is for a return.
+CREATE TABLE IF NOT EXISTS refunds (
+	refund_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	order_id INT NOT NULL,
+	return_id INT UNIQUE,
+	amount REAL NOT NULL,
+	created_by CHAR(32),
+	created_at TIMESTAMP NOT NULL,
+	CONSTRAINT fk_orders
+		FOREIGN KEY (order_id)
+			REFERENCES orders(order_id)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_returns
+		FOREIGN KEY (return_id)
+			REFERENCES returns(return_id)
+			ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_refunds_order_id ON refunds(order_id);
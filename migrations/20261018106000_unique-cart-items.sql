@@ -0,0 +1,27 @@
+-- Add migration script here
+
+-- Each variant only appears once in a cart, adding it again increases the quantity instead.
+-- Nothing references cart_items, so it can be safely rebuilt.
+CREATE TABLE IF NOT EXISTS new_cart_items (
+	user_id CHAR(32) NOT NULL,
+	variant_id INT NOT NULL,
+	quantity INT NOT NULL CHECK (quantity > 0),
+	PRIMARY KEY (user_id, variant_id),
+	CONSTRAINT fk_users
+		FOREIGN KEY (user_id)
+			REFERENCES users(user_id)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_product_variants
+		FOREIGN KEY (variant_id)
+			REFERENCES product_variants(variant_id)
+			ON DELETE CASCADE
+);
+
+INSERT INTO new_cart_items (user_id, variant_id, quantity)
+SELECT user_id, variant_id, SUM(quantity)
+FROM cart_items
+GROUP BY user_id, variant_id
+HAVING SUM(quantity) > 0;
+
+DROP TABLE cart_items;
+ALTER TABLE new_cart_items RENAME TO cart_items;
@@ -0,0 +1,7 @@
+-- Add migration script here
+
+-- The price when the item was put in the cart, so the shopper can be told if it has changed
+ALTER TABLE cart_items ADD COLUMN price_when_added REAL;
+
+UPDATE cart_items
+SET price_when_added = (SELECT price FROM product_variants WHERE product_variants.variant_id = cart_items.variant_id);
@@ -0,0 +1,49 @@
+-- Add migration script here
+
+-- A cart belongs to either a logged in user or a guest. Guests are given a random cart token,
+-- only the hash of which is stored, and their carts expire if they aren't used for a while.
+CREATE TABLE IF NOT EXISTS carts (
+	cart_id CHAR(32) PRIMARY KEY NOT NULL,
+	user_id CHAR(32) UNIQUE,
+	token_hash CHAR(64) UNIQUE,
+	created_at TIMESTAMP NOT NULL,
+	expires_at TIMESTAMP,
+	CHECK ((user_id IS NULL) != (token_hash IS NULL)),
+	CONSTRAINT fk_users
+		FOREIGN KEY (user_id)
+			REFERENCES users(user_id)
+			ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_carts_expires_at ON carts(expires_at);
+
+INSERT INTO carts (cart_id, user_id, created_at)
+SELECT lower(hex(randomblob(16))), user_id, CURRENT_TIMESTAMP
+FROM cart_items
+GROUP BY user_id;
+
+-- Cart items now belong to a cart rather than a user.
+-- Nothing references cart_items, so it can be safely rebuilt.
+CREATE TABLE IF NOT EXISTS new_cart_items (
+	cart_id CHAR(32) NOT NULL,
+	variant_id INT NOT NULL,
+	quantity INT NOT NULL CHECK (quantity > 0),
+	price_when_added REAL,
+	PRIMARY KEY (cart_id, variant_id),
+	CONSTRAINT fk_carts
+		FOREIGN KEY (cart_id)
+			REFERENCES carts(cart_id)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_product_variants
+		FOREIGN KEY (variant_id)
+			REFERENCES product_variants(variant_id)
+			ON DELETE CASCADE
+);
+
+INSERT INTO new_cart_items (cart_id, variant_id, quantity, price_when_added)
+SELECT carts.cart_id, cart_items.variant_id, cart_items.quantity, cart_items.price_when_added
+FROM cart_items
+INNER JOIN carts ON carts.user_id = cart_items.user_id;
+
+DROP TABLE cart_items;
+ALTER TABLE new_cart_items RENAME TO cart_items;
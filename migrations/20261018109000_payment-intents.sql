@@ -0,0 +1,27 @@
+-- Add migration script here
+
+-- An attempt to pay for an order with the payment provider.
+-- Card details are never stored, only the last four digits to show the customer.
+-- provider_reference is missing when the provider couldn't be reached.
+CREATE TABLE IF NOT EXISTS payment_intents (
+	payment_intent_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	order_id INT NOT NULL,
+	provider VARCHAR(32) NOT NULL,
+	provider_reference VARCHAR(64),
+	payment_status TEXT NOT NULL CHECK (payment_status IN ('RequiresAction', 'Authorized', 'Captured', 'Voided', 'Declined', 'Refunded')),
+	amount REAL NOT NULL,
+	amount_captured REAL NOT NULL DEFAULT 0,
+	amount_refunded REAL NOT NULL DEFAULT 0,
+	card_last4 CHAR(4) NOT NULL,
+	next_action TEXT,
+	failure_message TEXT,
+	created_at TIMESTAMP NOT NULL,
+	updated_at TIMESTAMP NOT NULL,
+	UNIQUE (provider, provider_reference),
+	CONSTRAINT fk_orders
+		FOREIGN KEY (order_id)
+			REFERENCES orders(order_id)
+			ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_payment_intents_order_id ON payment_intents(order_id);
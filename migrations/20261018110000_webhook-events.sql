@@ -0,0 +1,16 @@
+-- Add migration script here
+
+-- Webhook events from the payment provider that have already been handled.
+-- Providers send an event again if they don't hear back, so the same one can arrive more than once.
+CREATE TABLE IF NOT EXISTS processed_webhook_events (
+	provider VARCHAR(32) NOT NULL,
+	event_id VARCHAR(64) NOT NULL,
+	event_type VARCHAR(64) NOT NULL,
+	payment_intent_id INT,
+	processed_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (provider, event_id),
+	CONSTRAINT fk_payment_intents
+		FOREIGN KEY (payment_intent_id)
+			REFERENCES payment_intents(payment_intent_id)
+			ON DELETE SET NULL
+);
@@ -0,0 +1,63 @@
+-- Add migration script here
+
+-- Refunds can now be made for an order directly, not only when a return is approved.
+-- Every refund has a credit note number, which is printed on the credit note the customer gets.
+ALTER TABLE refunds ADD COLUMN credit_note_number VARCHAR(16);
+ALTER TABLE refunds ADD COLUMN restocked BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE refunds ADD COLUMN note TEXT;
+
+UPDATE refunds SET credit_note_number = printf('CN-%06d', refund_id);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_refunds_credit_note_number ON refunds(credit_note_number);
+
+-- The items each refund was for. A refund of an amount that isn't for any items in particular,
+-- e.g. to make up for a late delivery, doesn't have any.
+-- amount is the item's share of what was paid for the line, including its tax.
+CREATE TABLE IF NOT EXISTS refund_items (
+	refund_id INT NOT NULL,
+	order_id INT NOT NULL,
+	variant_id INT NOT NULL,
+	quantity INT NOT NULL CHECK (quantity > 0),
+	amount REAL NOT NULL,
+	tax REAL NOT NULL,
+	PRIMARY KEY (refund_id, variant_id),
+	CONSTRAINT fk_refunds
+		FOREIGN KEY (refund_id)
+			REFERENCES refunds(refund_id)
+			ON DELETE CASCADE,
+	CONSTRAINT fk_order_items
+		FOREIGN KEY (order_id, variant_id)
+			REFERENCES order_items(order_id, variant_id)
+			ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_refund_items_order_id ON refund_items(order_id, variant_id);
+
+-- Refunds made for approved returns were for the returned items
+INSERT INTO refund_items (refund_id, order_id, variant_id, quantity, amount, tax)
+SELECT
+refunds.refund_id,
+refunds.order_id,
+return_items.variant_id,
+return_items.quantity,
+ROUND((order_items.line_subtotal - order_items.discount + order_items.tax) * return_items.quantity / order_items.quantity, 2),
+ROUND(order_items.tax * return_items.quantity / order_items.quantity, 2)
+FROM refunds
+INNER JOIN return_items ON return_items.return_id = refunds.return_id
+INNER JOIN order_items ON order_items.order_id = refunds.order_id AND order_items.variant_id = return_items.variant_id;
+
+-- total_cost stays what the customer paid, and amount_refunded is how much of it they've had back
+ALTER TABLE orders ADD COLUMN amount_refunded REAL NOT NULL DEFAULT 0;
+
+UPDATE orders
+SET amount_refunded = (SELECT COALESCE(SUM(amount), 0) FROM refunds WHERE refunds.order_id = orders.order_id);
+
+CREATE TRIGGER IF NOT EXISTS refunds_insert AFTER INSERT ON refunds BEGIN
+	UPDATE refunds
+	SET credit_note_number = printf('CN-%06d', new.refund_id)
+	WHERE refund_id = new.refund_id AND credit_note_number IS NULL;
+
+	UPDATE orders
+	SET amount_refunded = amount_refunded + new.amount
+	WHERE order_id = new.order_id;
+END;
@@ -0,0 +1,106 @@
+-- Add migration script here
+
+-- Amounts of money are stored as a whole number of the currency's smallest unit, e.g. cents,
+-- instead of floats that can't hold most amounts exactly. The existing amounts are all in
+-- ringgit, which has 2 decimal places.
+-- SQLite can't change a column's type, so each one is copied into a new INTEGER column that then
+-- takes its name. The triggers that use the columns have to go while that happens.
+DROP TRIGGER IF EXISTS product_variants_insert;
+DROP TRIGGER IF EXISTS product_variants_update;
+DROP TRIGGER IF EXISTS refunds_insert;
+
+ALTER TABLE products ADD COLUMN price_minor INTEGER NOT NULL DEFAULT 0;
+UPDATE products SET price_minor = CAST(ROUND(price * 100) AS INTEGER);
+ALTER TABLE products DROP COLUMN price;
+ALTER TABLE products RENAME COLUMN price_minor TO price;
+
+ALTER TABLE product_variants ADD COLUMN price_minor INTEGER NOT NULL DEFAULT 0;
+UPDATE product_variants SET price_minor = CAST(ROUND(price * 100) AS INTEGER);
+ALTER TABLE product_variants DROP COLUMN price;
+ALTER TABLE product_variants RENAME COLUMN price_minor TO price;
+
+ALTER TABLE cart_items ADD COLUMN price_when_added_minor INTEGER;
+UPDATE cart_items SET price_when_added_minor = CAST(ROUND(price_when_added * 100) AS INTEGER);
+ALTER TABLE cart_items DROP COLUMN price_when_added;
+ALTER TABLE cart_items RENAME COLUMN price_when_added_minor TO price_when_added;
+
+ALTER TABLE orders ADD COLUMN total_cost_minor INTEGER;
+ALTER TABLE orders ADD COLUMN amount_refunded_minor INTEGER NOT NULL DEFAULT 0;
+UPDATE orders
+SET total_cost_minor = CAST(ROUND(total_cost * 100) AS INTEGER),
+	amount_refunded_minor = CAST(ROUND(amount_refunded * 100) AS INTEGER);
+ALTER TABLE orders DROP COLUMN total_cost;
+ALTER TABLE orders DROP COLUMN amount_refunded;
+ALTER TABLE orders RENAME COLUMN total_cost_minor TO total_cost;
+ALTER TABLE orders RENAME COLUMN amount_refunded_minor TO amount_refunded;
+
+ALTER TABLE order_items ADD COLUMN unit_price_minor INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE order_items ADD COLUMN line_subtotal_minor INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE order_items ADD COLUMN discount_minor INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE order_items ADD COLUMN tax_minor INTEGER NOT NULL DEFAULT 0;
+UPDATE order_items
+SET unit_price_minor = CAST(ROUND(unit_price * 100) AS INTEGER),
+	line_subtotal_minor = CAST(ROUND(line_subtotal * 100) AS INTEGER),
+	discount_minor = CAST(ROUND(discount * 100) AS INTEGER),
+	tax_minor = CAST(ROUND(tax * 100) AS INTEGER);
+ALTER TABLE order_items DROP COLUMN unit_price;
+ALTER TABLE order_items DROP COLUMN line_subtotal;
+ALTER TABLE order_items DROP COLUMN discount;
+ALTER TABLE order_items DROP COLUMN tax;
+ALTER TABLE order_items RENAME COLUMN unit_price_minor TO unit_price;
+ALTER TABLE order_items RENAME COLUMN line_subtotal_minor TO line_subtotal;
+ALTER TABLE order_items RENAME COLUMN discount_minor TO discount;
+ALTER TABLE order_items RENAME COLUMN tax_minor TO tax;
+
+ALTER TABLE payment_intents ADD COLUMN amount_minor INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE payment_intents ADD COLUMN amount_captured_minor INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE payment_intents ADD COLUMN amount_refunded_minor INTEGER NOT NULL DEFAULT 0;
+UPDATE payment_intents
+SET amount_minor = CAST(ROUND(amount * 100) AS INTEGER),
+	amount_captured_minor = CAST(ROUND(amount_captured * 100) AS INTEGER),
+	amount_refunded_minor = CAST(ROUND(amount_refunded * 100) AS INTEGER);
+ALTER TABLE payment_intents DROP COLUMN amount;
+ALTER TABLE payment_intents DROP COLUMN amount_captured;
+ALTER TABLE payment_intents DROP COLUMN amount_refunded;
+ALTER TABLE payment_intents RENAME COLUMN amount_minor TO amount;
+ALTER TABLE payment_intents RENAME COLUMN amount_captured_minor TO amount_captured;
+ALTER TABLE payment_intents RENAME COLUMN amount_refunded_minor TO amount_refunded;
+
+ALTER TABLE refunds ADD COLUMN amount_minor INTEGER NOT NULL DEFAULT 0;
+UPDATE refunds SET amount_minor = CAST(ROUND(amount * 100) AS INTEGER);
+ALTER TABLE refunds DROP COLUMN amount;
+ALTER TABLE refunds RENAME COLUMN amount_minor TO amount;
+
+ALTER TABLE refund_items ADD COLUMN amount_minor INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE refund_items ADD COLUMN tax_minor INTEGER NOT NULL DEFAULT 0;
+UPDATE refund_items
+SET amount_minor = CAST(ROUND(amount * 100) AS INTEGER),
+	tax_minor = CAST(ROUND(tax * 100) AS INTEGER);
+ALTER TABLE refund_items DROP COLUMN amount;
+ALTER TABLE refund_items DROP COLUMN tax;
+ALTER TABLE refund_items RENAME COLUMN amount_minor TO amount;
+ALTER TABLE refund_items RENAME COLUMN tax_minor TO tax;
+
+CREATE TRIGGER IF NOT EXISTS product_variants_insert AFTER INSERT ON product_variants BEGIN
+	UPDATE products
+	SET price = COALESCE((SELECT price FROM product_variants WHERE product_id = new.product_id AND is_default), price),
+		stock = (SELECT COALESCE(SUM(stock), 0) FROM product_variants WHERE product_id = new.product_id)
+	WHERE product_id = new.product_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS product_variants_update AFTER UPDATE OF price, stock, is_default ON product_variants BEGIN
+	UPDATE products
+	SET price = COALESCE((SELECT price FROM product_variants WHERE product_id = new.product_id AND is_default), price),
+		stock = (SELECT COALESCE(SUM(stock), 0) FROM product_variants WHERE product_id = new.product_id)
+	WHERE product_id = new.product_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS refunds_insert AFTER INSERT ON refunds BEGIN
+	UPDATE refunds
+	SET credit_note_number = printf('CN-%06d', new.refund_id)
+	WHERE refund_id = new.refund_id AND credit_note_number IS NULL;
+
+	UPDATE orders
+	SET amount_refunded = amount_refunded + new.amount
+	WHERE order_id = new.order_id;
+END;
@@ -0,0 +1,24 @@
+-- Add migration script here
+
+-- Prices are stored in the store's currency and converted for shoppers that want to see them
+-- in another one. rate is how much of the currency 1 of the store's currency is worth,
+-- e.g. 0.21 USD. Only currencies with a rate are offered.
+CREATE TABLE IF NOT EXISTS exchange_rates (
+	currency CHAR(3) PRIMARY KEY NOT NULL,
+	rate REAL NOT NULL CHECK (rate > 0),
+	updated_by CHAR(32),
+	updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	CONSTRAINT fk_users
+		FOREIGN KEY (updated_by)
+			REFERENCES users(user_id)
+			ON DELETE SET NULL
+);
+
+-- The currency a user wants to see prices in when a request doesn't ask for one
+ALTER TABLE users ADD COLUMN preferred_currency CHAR(3);
+
+-- The currency the shopper saw the order in and the rate used, so what they were shown can be
+-- worked out again later. The amounts themselves are still in the store's currency.
+-- Orders placed before this were all shown in ringgit.
+ALTER TABLE orders ADD COLUMN currency CHAR(3) NOT NULL DEFAULT 'MYR';
+ALTER TABLE orders ADD COLUMN exchange_rate REAL NOT NULL DEFAULT 1;
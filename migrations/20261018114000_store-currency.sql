@@ -0,0 +1,12 @@
+-- Add migration script here
+
+-- Amounts are stored without their currency. 20261018112000_money-minor-units converted the
+-- existing ones assuming they were ringgit, which has 2 decimal places, and the sample products
+-- are priced in ringgit too. The server refuses to start when STORE_CURRENCY isn't the currency
+-- kept here, rather than reading every amount as if it were in another currency, e.g. 100 times
+-- too much in yen. To run the store in another currency, reprice everything and then change it.
+CREATE TABLE IF NOT EXISTS store_currency (
+	currency CHAR(3) PRIMARY KEY NOT NULL
+);
+
+INSERT INTO store_currency (currency) VALUES ('MYR');
@@ -0,0 +1,8 @@
+-- Add migration script here
+
+-- Exchange rates used to come with manage_products, so admins keep being able to set them
+INSERT INTO permissions (permission_name) VALUES
+	('manage_exchange_rates');
+
+INSERT INTO role_permissions (role_name, permission_name) VALUES
+	('admin', 'manage_exchange_rates');
@@ -18,7 +18,7 @@ async fn run() {
 
     // Use Axum to serve up the pages
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await
         .expect("Unable to start the Axum webserver");
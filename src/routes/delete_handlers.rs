@@ -0,0 +1,188 @@
+use axum::{extract::Path, http::StatusCode, Extension};
+use sqlx::{Pool, Sqlite};
+
+use crate::routes::map_db_error;
+use crate::utils::carts::{self, CartOwner};
+use crate::utils::money::Currency;
+
+pub async fn revoke_role(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path((user_id, role_name)): Path<(String, String)>,
+) -> Result<String, (StatusCode, String)> {
+    let result = sqlx::query!(
+        "DELETE FROM user_roles WHERE user_id = $1 AND role_name = $2",
+        user_id,
+        role_name,
+    )
+    .execute(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "The user does not have that role".to_owned(),
+        ));
+    }
+
+    Ok("Role revoked successfully".to_owned())
+}
+
+// Only empty categories can be deleted, so products are never left without a category
+pub async fn delete_category(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(category_id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    let usage = sqlx::query!(
+        r#"
+        SELECT
+        (SELECT COUNT(*) FROM categories WHERE parent_id = $1) AS "subcategories!: i64",
+        (SELECT COUNT(*) FROM products WHERE category_id = $1) AS "products!: i64"
+        "#,
+        category_id,
+    )
+    .fetch_one(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    if usage.subcategories > 0 || usage.products > 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            format!(
+                "The category still has {} subcategories and {} products, move them first",
+                usage.subcategories, usage.products
+            ),
+        ));
+    }
+
+    let result = sqlx::query!("DELETE FROM categories WHERE category_id = $1", category_id)
+        .execute(&db_pool)
+        .await
+        .map_err(map_db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "Category not found".to_owned()));
+    }
+
+    Ok("Category deleted successfully".to_owned())
+}
+
+// Variants that have been ordered are kept so order history stays intact
+pub async fn delete_variant(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(variant_id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    let variant_option = sqlx::query!(
+        r#"
+        SELECT
+        is_default,
+        (SELECT COUNT(*) FROM order_items WHERE variant_id = $1) AS "orders!: i64"
+        FROM product_variants
+        WHERE variant_id = $1
+        "#,
+        variant_id,
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(variant) = variant_option else {
+        return Err((StatusCode::NOT_FOUND, "Variant not found".to_owned()));
+    };
+
+    if variant.is_default {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The default variant can't be deleted, make another variant the default first"
+                .to_owned(),
+        ));
+    }
+    if variant.orders > 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            "The variant has already been ordered, set its stock to 0 instead".to_owned(),
+        ));
+    }
+
+    // Removing the variant also removes it from any carts
+    sqlx::query!(
+        "DELETE FROM product_variants WHERE variant_id = $1",
+        variant_id
+    )
+    .execute(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok("Variant deleted successfully".to_owned())
+}
+
+pub async fn remove_cart_item(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    cart_owner: CartOwner,
+    Path(variant_id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    let cart_id = carts::find_cart(&db_pool, &cart_owner)
+        .await
+        .map_err(map_db_error)?;
+
+    let result = sqlx::query!(
+        "DELETE FROM cart_items WHERE cart_id = $1 AND variant_id = $2",
+        cart_id,
+        variant_id,
+    )
+    .execute(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "The item isn't in your cart".to_owned(),
+        ));
+    }
+
+    Ok("Cart item removed successfully".to_owned())
+}
+
+pub async fn clear_cart(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    cart_owner: CartOwner,
+) -> Result<String, (StatusCode, String)> {
+    let cart_id = carts::find_cart(&db_pool, &cart_owner)
+        .await
+        .map_err(map_db_error)?;
+
+    sqlx::query!("DELETE FROM cart_items WHERE cart_id = $1", cart_id)
+        .execute(&db_pool)
+        .await
+        .map_err(map_db_error)?;
+
+    Ok("Cart cleared successfully".to_owned())
+}
+
+// Stops offering prices in the currency. Users that prefer it see the store's currency instead.
+pub async fn delete_exchange_rate(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(currency): Path<String>,
+) -> Result<String, (StatusCode, String)> {
+    let currency = currency
+        .parse::<Currency>()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    let result = sqlx::query!("DELETE FROM exchange_rates WHERE currency = $1", currency)
+        .execute(&db_pool)
+        .await
+        .map_err(map_db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("There is no exchange rate for {}", currency),
+        ));
+    }
+
+    Ok(format!(
+        "Exchange rate for {} removed successfully",
+        currency
+    ))
+}
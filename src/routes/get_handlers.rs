@@ -1,50 +1,446 @@
 use axum::{
-    extract::Path,
-    headers::{authorization::Bearer, Authorization},
-    Extension, Json, TypedHeader,
+    extract::{Path, Query},
+    Extension, Json,
 };
 
-use http::StatusCode;
-use sqlx::{Pool, Sqlite};
+use chrono::NaiveDateTime;
+use http::{header::CONTENT_TYPE, HeaderName, StatusCode};
+use sqlx::{Pool, QueryBuilder, Sqlite};
 
 use crate::routes::map_db_error;
-use crate::utils::auth;
+use crate::utils::auth::AuthUser;
+use crate::utils::carts::{self, CartOwner};
+use crate::utils::categories;
+use crate::utils::currencies::{self, Conversion};
 use crate::utils::models;
+use crate::utils::money::{Currency, Money};
+use crate::utils::refunds;
+use crate::utils::search;
 
-use super::ActiveUsers;
+// How many products are returned per page when the client doesn't say
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+// Products with this many or fewer left are shown as running low
+const LOW_STOCK_THRESHOLD: i64 = 10;
+const RELATED_PRODUCTS_LIMIT: i64 = 4;
+
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 50;
 
 pub async fn get_products(
     Extension(db_pool): Extension<Pool<Sqlite>>,
-) -> Result<Json<Vec<models::Product>>, (StatusCode, String)> {
-    let products = sqlx::query_as!(
-        models::Product,
+    conversion: Conversion,
+    Query(product_query): Query<models::ProductQuery>,
+) -> Result<Json<models::Page<models::Product>>, (StatusCode, String)> {
+    let page = product_query.page.unwrap_or(1);
+    let per_page = product_query.per_page.unwrap_or(DEFAULT_PAGE_SIZE);
+
+    if page < 1 || !(1..=MAX_PAGE_SIZE).contains(&per_page) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "page must be at least 1 and per_page must be between 1 and {}",
+                MAX_PAGE_SIZE
+            ),
+        ));
+    }
+    let Some(offset) = (page - 1).checked_mul(per_page) else {
+        return Err((StatusCode::BAD_REQUEST, "page is too large".to_owned()));
+    };
+
+    // The prices are compared in the store's currency, to the ones that are shown as in range
+    let min_price = parse_price(product_query.min_price.as_deref(), conversion)?
+        .map(|min_price| conversion.lowest_price_shown_as(min_price));
+    let max_price = parse_price(product_query.max_price.as_deref(), conversion)?
+        .map(|max_price| conversion.highest_price_shown_as(max_price));
+
+    let mut count_query = QueryBuilder::new("SELECT COUNT(*) FROM products");
+    push_product_filters(&mut count_query, &product_query, min_price, max_price);
+
+    let (total,): (i64,) = count_query
+        .build_query_as()
+        .fetch_one(&db_pool)
+        .await
+        .map_err(map_db_error)?;
+
+    let mut products_query = QueryBuilder::new(
+        "
+        SELECT
+        products.product_id,
+        products.product_name,
+        products.product_description,
+        products.category_id,
+        categories.category_name,
+        products.stock,
+        products.price,
+        products.img_path
+        FROM products
+        LEFT JOIN categories ON categories.category_id = products.category_id
+        ",
+    );
+    push_product_filters(&mut products_query, &product_query, min_price, max_price);
+
+    // The product id breaks ties so rows don't move between pages
+    let order = product_query.order.keyword();
+    products_query
+        .push(format_args!(
+            " ORDER BY {} {}, product_id {}",
+            product_query.sort.column(),
+            order,
+            order
+        ))
+        .push(" LIMIT ")
+        .push_bind(per_page)
+        .push(" OFFSET ")
+        .push_bind(offset);
+
+    let mut products = products_query
+        .build_query_as::<models::Product>()
+        .fetch_all(&db_pool)
+        .await
+        .map_err(map_db_error)?;
+    for product in &mut products {
+        product.price = conversion.convert(product.price);
+    }
+
+    let page_count = (total + per_page - 1) / per_page;
+    let next_page = match page < page_count {
+        true => Some(page + 1),
+        false => None,
+    };
+
+    Ok(Json(models::Page {
+        items: products,
+        total,
+        page,
+        per_page,
+        next_page,
+    }))
+}
+
+fn parse_price(
+    price: Option<&str>,
+    conversion: Conversion,
+) -> Result<Option<Money>, (StatusCode, String)> {
+    price
+        .map(|price| Money::parse(price, conversion.currency))
+        .transpose()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))
+}
+
+// Adds the WHERE clause shared by the count and the listing of products.
+// The prices are in the store's currency.
+fn push_product_filters(
+    query: &mut QueryBuilder<Sqlite>,
+    product_query: &models::ProductQuery,
+    min_price: Option<Money>,
+    max_price: Option<Money>,
+) {
+    query.push(" WHERE archived_at IS NULL");
+
+    // Walk down the tree so that a category also includes all of its subcategories
+    if let Some(category) = &product_query.category {
+        query
+            .push(
+                "
+                AND products.category_id IN (
+                    WITH RECURSIVE subtree(category_id) AS (
+                        SELECT category_id FROM categories WHERE slug = ",
+            )
+            .push_bind(category.clone())
+            .push(
+                "
+                        UNION
+                        SELECT categories.category_id FROM categories
+                        INNER JOIN subtree ON categories.parent_id = subtree.category_id
+                    )
+                    SELECT category_id FROM subtree
+                )",
+            );
+    }
+    if let Some(min_price) = min_price {
+        query.push(" AND price >= ").push_bind(min_price);
+    }
+    if let Some(max_price) = max_price {
+        query.push(" AND price <= ").push_bind(max_price);
+    }
+    if product_query.in_stock {
+        query.push(" AND stock > 0");
+    }
+}
+
+pub async fn get_product(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    conversion: Conversion,
+    Path(product_id): Path<i64>,
+) -> Result<Json<models::ProductDetail>, (StatusCode, String)> {
+    let product_option = sqlx::query!(
         r#"
-        SELECT 
+        SELECT
+        products.product_id,
+        products.product_name,
+        products.product_description,
+        products.category_id,
+        categories.category_name AS "category_name?",
+        products.stock,
+        products.price AS "price: Money",
+        products.img_path,
+        products.archived_at
+        FROM products
+        LEFT JOIN categories ON categories.category_id = products.category_id
+        WHERE products.product_id = $1
+        "#,
         product_id,
-        product_name,
-        product_description,
-        product_category AS "product_category: models::ProductCategory",
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(row) = product_option else {
+        return Err((StatusCode::NOT_FOUND, "Product not found".to_owned()));
+    };
+
+    let availability = match row.stock {
+        _ if row.archived_at.is_some() => models::Availability::Discontinued,
+        stock if stock <= 0 => models::Availability::OutOfStock,
+        stock if stock <= LOW_STOCK_THRESHOLD => models::Availability::LowStock,
+        _ => models::Availability::InStock,
+    };
+
+    let mut variants = sqlx::query_as!(
+        models::ProductVariant,
+        r#"
+        SELECT
+        variant_id,
+        product_id,
+        sku,
+        options AS "options: models::VariantOptions",
+        price AS "price: Money",
         stock,
-        price,
-        img_path
+        img_path,
+        is_default
+        FROM product_variants
+        WHERE product_id = $1
+        ORDER BY is_default DESC, variant_id
+        "#,
+        row.product_id,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let mut related_products = sqlx::query_as!(
+        models::Product,
+        r#"
+        SELECT
+        products.product_id,
+        products.product_name,
+        products.product_description,
+        products.category_id,
+        categories.category_name AS "category_name?",
+        products.stock,
+        products.price AS "price: Money",
+        products.img_path
         FROM products
+        LEFT JOIN categories ON categories.category_id = products.category_id
+        WHERE products.category_id = $1
+        AND products.product_id != $2
+        AND products.archived_at IS NULL
+        ORDER BY products.product_id
+        LIMIT $3
+        "#,
+        row.category_id,
+        row.product_id,
+        RELATED_PRODUCTS_LIMIT,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    for variant in &mut variants {
+        variant.price = conversion.convert(variant.price);
+    }
+    for product in &mut related_products {
+        product.price = conversion.convert(product.price);
+    }
+
+    Ok(Json(models::ProductDetail {
+        product: models::Product {
+            product_id: row.product_id,
+            product_name: row.product_name,
+            product_description: row.product_description,
+            category_id: row.category_id,
+            category_name: row.category_name,
+            stock: row.stock,
+            price: conversion.convert(row.price),
+            img_path: row.img_path,
+        },
+        availability,
+        variants,
+        related_products,
+    }))
+}
+
+pub async fn search_products(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    conversion: Conversion,
+    Query(search_query): Query<models::SearchQuery>,
+) -> Result<Json<models::SearchResults>, (StatusCode, String)> {
+    let limit = search_query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("limit must be between 1 and {}", MAX_SEARCH_LIMIT),
+        ));
+    }
+
+    let terms = search::search_terms(&search_query.q);
+    if terms.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Enter something to search for".to_owned(),
+        ));
+    }
+
+    // Matches in the name count for more than matches in the description
+    let match_expression = search::fts_match_expression(&terms);
+    let matches = sqlx::query!(
+        r#"
+        SELECT
+        products.product_id AS "product_id!",
+        products.product_name AS "product_name!",
+        products.product_description,
+        products.category_id,
+        categories.category_name AS "category_name?",
+        products.stock AS "stock!",
+        products.price AS "price!: Money",
+        products.img_path AS "img_path!",
+        highlight(products_fts, 0, '<mark>', '</mark>') AS "highlighted_name!: String",
+        snippet(products_fts, 1, '<mark>', '</mark>', '...', 12) AS "snippet: String"
+        FROM products_fts
+        INNER JOIN products ON products.product_id = products_fts.rowid
+        LEFT JOIN categories ON categories.category_id = products.category_id
+        WHERE products_fts MATCH $1 AND products.archived_at IS NULL
+        ORDER BY bm25(products_fts, 10.0, 1.0)
+        LIMIT $2
+        "#,
+        match_expression,
+        limit,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    if !matches.is_empty() {
+        let results = matches
+            .into_iter()
+            .map(|row| models::SearchResult {
+                product: models::Product {
+                    product_id: row.product_id,
+                    product_name: row.product_name,
+                    product_description: row.product_description,
+                    category_id: row.category_id,
+                    category_name: row.category_name,
+                    stock: row.stock,
+                    price: conversion.convert(row.price),
+                    img_path: row.img_path,
+                },
+                highlighted_name: row.highlighted_name,
+                snippet: row.snippet,
+            })
+            .collect();
+
+        return Ok(Json(models::SearchResults {
+            query: search_query.q,
+            fuzzy: false,
+            results,
+        }));
+    }
+
+    // Nothing matched exactly, so look for products that are only a typo or two away.
+    // The catalog is small enough to compare against every product.
+    let products = sqlx::query_as!(
+        models::Product,
+        r#"
+        SELECT
+        products.product_id,
+        products.product_name,
+        products.product_description,
+        products.category_id,
+        categories.category_name AS "category_name?",
+        products.stock,
+        products.price AS "price: Money",
+        products.img_path
+        FROM products
+        LEFT JOIN categories ON categories.category_id = products.category_id
+        WHERE products.archived_at IS NULL
         "#
     )
     .fetch_all(&db_pool)
     .await
     .map_err(map_db_error)?;
 
-    Ok(Json(products))
+    let mut fuzzy_matches: Vec<(usize, models::Product)> = products
+        .into_iter()
+        .filter_map(|product| {
+            let text = format!(
+                "{} {}",
+                product.product_name,
+                product.product_description.clone().unwrap_or_default()
+            );
+            search::fuzzy_distance(&terms, &text).map(|distance| (distance, product))
+        })
+        .collect();
+    fuzzy_matches.sort_by_key(|(distance, _)| *distance);
+
+    let results = fuzzy_matches
+        .into_iter()
+        .take(limit as usize)
+        .map(|(_, product)| models::SearchResult {
+            highlighted_name: product.product_name.clone(),
+            snippet: None,
+            product: models::Product {
+                price: conversion.convert(product.price),
+                ..product
+            },
+        })
+        .collect();
+
+    Ok(Json(models::SearchResults {
+        query: search_query.q,
+        fuzzy: true,
+        results,
+    }))
+}
+
+pub async fn get_categories(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+) -> Result<Json<Vec<models::CategoryTree>>, (StatusCode, String)> {
+    let all_categories = sqlx::query_as!(
+        models::Category,
+        "
+        SELECT
+        category_id,
+        parent_id,
+        category_name,
+        slug,
+        display_order
+        FROM categories
+        ORDER BY display_order, category_name
+        ",
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(Json(categories::build_tree(all_categories)))
 }
 
 pub async fn get_addresses(
     Extension(db_pool): Extension<Pool<Sqlite>>,
-    Extension(active_users): Extension<ActiveUsers>,
-    authorization: TypedHeader<Authorization<Bearer>>,
+    auth_user: AuthUser,
 ) -> Result<Json<Vec<models::Address>>, (StatusCode, String)> {
-    let authed_user_id =
-        auth::authenticate_user(authorization.token().to_string(), active_users).await?;
-
     let addresses = sqlx::query_as!(
         models::Address,
         "SELECT
@@ -56,7 +452,7 @@ pub async fn get_addresses(
         state_province,
         country
         FROM addresses WHERE user_id = $1",
-        authed_user_id,
+        auth_user.user_id,
     )
     .fetch_all(&db_pool)
     .await
@@ -67,12 +463,8 @@ pub async fn get_addresses(
 
 pub async fn get_personal_info(
     Extension(db_pool): Extension<Pool<Sqlite>>,
-    Extension(active_users): Extension<ActiveUsers>,
-    authorization: TypedHeader<Authorization<Bearer>>,
+    auth_user: AuthUser,
 ) -> Result<Json<models::PersonalInfo>, (StatusCode, String)> {
-    let authed_user_id =
-        auth::authenticate_user(authorization.token().to_string(), active_users).await?;
-
     let personal_info_option = sqlx::query_as!(
         models::PersonalInfo,
         r#"
@@ -82,7 +474,7 @@ pub async fn get_personal_info(
         gender AS "gender: models::Gender"
         FROM personal_info WHERE user_id = $1
         "#,
-        authed_user_id,
+        auth_user.user_id,
     )
     .fetch_optional(&db_pool)
     .await
@@ -94,79 +486,450 @@ pub async fn get_personal_info(
     }
 }
 
+pub async fn get_preferences(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
+) -> Result<Json<models::Preferences>, (StatusCode, String)> {
+    let preferences = sqlx::query_as!(
+        models::Preferences,
+        r#"SELECT preferred_currency AS "currency: Currency" FROM users WHERE user_id = $1"#,
+        auth_user.user_id,
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    match preferences {
+        Some(preferences) => Ok(Json(preferences)),
+        None => Err((StatusCode::NOT_FOUND, "User not found".to_owned())),
+    }
+}
+
+pub async fn get_currencies(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+) -> Result<Json<models::Currencies>, (StatusCode, String)> {
+    let exchange_rates = currencies::get_exchange_rates(&db_pool)
+        .await
+        .map_err(map_db_error)?;
+
+    Ok(Json(models::Currencies {
+        base: Currency::base(),
+        exchange_rates,
+    }))
+}
+
 pub async fn get_cart(
     Extension(db_pool): Extension<Pool<Sqlite>>,
-    Extension(active_users): Extension<ActiveUsers>,
-    authorization: TypedHeader<Authorization<Bearer>>,
-) -> Result<Json<Vec<models::DisplayCartItem>>, (StatusCode, String)> {
-    let authed_user_id =
-        auth::authenticate_user(authorization.token().to_string(), active_users).await?;
+    cart_owner: CartOwner,
+    conversion: Conversion,
+) -> Result<Json<models::Cart>, (StatusCode, String)> {
+    let cart_id = carts::find_cart(&db_pool, &cart_owner)
+        .await
+        .map_err(map_db_error)?;
 
-    let cart = sqlx::query_as!(
-        models::DisplayCartItem,
-        "
+    // Visitors that haven't added anything yet don't have a cart
+    let Some(cart_id) = cart_id else {
+        return Ok(Json(models::Cart::empty(
+            conversion.currency,
+            conversion.rate,
+        )));
+    };
+
+    let cart = carts::load_cart(&db_pool, &cart_id, &conversion)
+        .await
+        .map_err(map_db_error)?;
+
+    Ok(Json(cart))
+}
+
+pub async fn get_orders(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
+) -> Result<Json<Vec<models::Order>>, (StatusCode, String)> {
+    let orders = sqlx::query_as!(
+        models::Order,
+        r#"
         SELECT
-        products.product_name,
-        products.price,
-        cart_items.quantity
-        from products
-        INNER JOIN cart_items ON cart_items.product_id = products.product_id
-        WHERE cart_items.user_id = $1
-        ",
-        authed_user_id,
+        order_id,
+        user_id,
+        creation_time AS "creation_time: NaiveDateTime",
+        total_cost AS "total_cost: Money",
+        amount_refunded AS "amount_refunded: Money",
+        order_status AS "order_status: models::OrderStatus",
+        currency AS "currency: Currency",
+        exchange_rate
+        FROM orders
+        WHERE user_id = $1
+        ORDER BY creation_time DESC
+        "#,
+        auth_user.user_id,
     )
     .fetch_all(&db_pool)
     .await
     .map_err(map_db_error)?;
 
-    Ok(Json(cart))
+    Ok(Json(orders))
 }
 
-// pub async fn get_orders(
-//     Extension(db_pool): Extension<Pool<Sqlite>>,
-//     Extension(active_users): Extension<ActiveUsers>,
-//     authorization: TypedHeader<Authorization<Bearer>>,
-// ) -> Result<Json<Vec<models::Order>>, (StatusCode, String)> {
-//     let authed_user_id =
-//         auth::authenticate_user(authorization.token().to_string(), active_users).await?;
-
-//     let orders = sqlx::query_as!(
-//         models::Order,
-//         r#"
-//         SELECT
-//         orders.order_id,
-//         orders.creation_time AS "creation_time: NaiveDateTime",
-//         orders.total_cost,
-//         orders.order_status AS "order_status: models::OrderStatus"
-//         FROM orders
-//         INNER JOIN addresses ON addresses.address_id = orders.address_id
-//         WHERE addresses.user_id = $1
-//         "#,
-//         authed_user_id,
-//     )
-//     .fetch_all(&db_pool)
-//     .await
-//     .map_err(map_db_error)?;
-
-//     Ok(Json(orders))
-// }
-
-pub async fn get_order_items(
-    Extension(db_pool): Extension<Pool<Sqlite>>,
-    Extension(active_users): Extension<ActiveUsers>,
-    authorization: TypedHeader<Authorization<Bearer>>,
+pub async fn get_order(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
     Path(order_id): Path<i64>,
-) -> Result<Json<Vec<models::OrderItem>>, (StatusCode, String)> {
-    auth::authenticate_user(authorization.token().to_string(), active_users).await?;
+) -> Result<Json<models::OrderDetail>, (StatusCode, String)> {
+    // Other users' orders are treated as if they don't exist
+    let order_option = sqlx::query_as!(
+        models::Order,
+        r#"
+        SELECT
+        order_id,
+        user_id,
+        creation_time AS "creation_time: NaiveDateTime",
+        total_cost AS "total_cost: Money",
+        amount_refunded AS "amount_refunded: Money",
+        order_status AS "order_status: models::OrderStatus",
+        currency AS "currency: Currency",
+        exchange_rate
+        FROM orders
+        WHERE order_id = $1 AND user_id = $2
+        "#,
+        order_id,
+        auth_user.user_id,
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(order) = order_option else {
+        return Err((StatusCode::NOT_FOUND, "Order not found".to_owned()));
+    };
+
+    let addresses = sqlx::query_as!(
+        models::OrderAddress,
+        r#"
+        SELECT
+        address_type AS "address_type: models::AddressType",
+        unit,
+        street,
+        city,
+        postal_code,
+        state_province,
+        country
+        FROM order_addresses
+        WHERE order_id = $1
+        ORDER BY address_type DESC
+        "#,
+        order_id,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
 
-    let order_items = sqlx::query_as!(
+    let items = sqlx::query_as!(
         models::OrderItem,
-        "SELECT * FROM order_items WHERE order_id = $1",
+        r#"
+        SELECT
+        order_items.order_id,
+        product_variants.product_id,
+        order_items.variant_id,
+        order_items.quantity,
+        order_items.unit_price AS "unit_price: Money",
+        order_items.line_subtotal AS "line_subtotal: Money",
+        order_items.discount AS "discount: Money",
+        order_items.tax AS "tax: Money"
+        FROM order_items
+        INNER JOIN product_variants ON product_variants.variant_id = order_items.variant_id
+        WHERE order_items.order_id = $1
+        "#,
         order_id,
     )
     .fetch_all(&db_pool)
     .await
     .map_err(map_db_error)?;
 
-    Ok(Json(order_items))
+    let status_history = sqlx::query_as!(
+        models::OrderStatusChange,
+        r#"
+        SELECT
+        from_status AS "from_status: models::OrderStatus",
+        to_status AS "to_status: models::OrderStatus",
+        changed_by,
+        changed_at AS "changed_at: NaiveDateTime",
+        note
+        FROM order_status_history
+        WHERE order_id = $1
+        ORDER BY history_id
+        "#,
+        order_id,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(Json(models::OrderDetail {
+        order,
+        addresses,
+        items,
+        status_history,
+    }))
+}
+
+// Every user's orders, newest first, for admins working through them
+pub async fn get_all_orders(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Query(order_query): Query<models::OrderQuery>,
+) -> Result<Json<Vec<models::Order>>, (StatusCode, String)> {
+    let orders = sqlx::query_as!(
+        models::Order,
+        r#"
+        SELECT
+        order_id,
+        user_id,
+        creation_time AS "creation_time: NaiveDateTime",
+        total_cost AS "total_cost: Money",
+        amount_refunded AS "amount_refunded: Money",
+        order_status AS "order_status: models::OrderStatus",
+        currency AS "currency: Currency",
+        exchange_rate
+        FROM orders
+        WHERE $1 IS NULL OR order_status = $1
+        ORDER BY creation_time DESC
+        "#,
+        order_query.status,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(Json(orders))
+}
+
+pub async fn get_order_returns(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
+    Path(order_id): Path<i64>,
+) -> Result<Json<Vec<models::ReturnDetail>>, (StatusCode, String)> {
+    check_order_owner(&db_pool, order_id, &auth_user.user_id).await?;
+
+    let returns = sqlx::query_as!(
+        models::Return,
+        r#"
+        SELECT
+        returns.return_id,
+        returns.order_id,
+        returns.user_id,
+        returns.return_status AS "return_status: models::ReturnStatus",
+        returns.comment,
+        returns.requested_at AS "requested_at: NaiveDateTime",
+        returns.resolved_at AS "resolved_at: NaiveDateTime",
+        returns.resolution_note,
+        refunds.amount AS "refund_amount?: Money"
+        FROM returns
+        LEFT JOIN refunds ON refunds.return_id = returns.return_id
+        WHERE returns.order_id = $1
+        ORDER BY returns.return_id
+        "#,
+        order_id,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let return_items = sqlx::query_as!(
+        models::ReturnItem,
+        r#"
+        SELECT
+        return_items.return_id,
+        return_items.variant_id,
+        return_items.quantity,
+        return_items.reason_code AS "reason_code: models::ReturnReason"
+        FROM return_items
+        INNER JOIN returns ON returns.return_id = return_items.return_id
+        WHERE returns.order_id = $1
+        "#,
+        order_id,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(Json(with_return_items(returns, return_items)))
+}
+
+pub async fn get_order_refunds(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
+    Path(order_id): Path<i64>,
+) -> Result<Json<Vec<models::RefundDetail>>, (StatusCode, String)> {
+    check_order_owner(&db_pool, order_id, &auth_user.user_id).await?;
+
+    Ok(Json(refunds::get_refunds(&db_pool, order_id).await?))
+}
+
+pub async fn get_credit_note(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
+    Path((order_id, refund_id)): Path<(i64, i64)>,
+) -> Result<([(HeaderName, &'static str); 1], String), (StatusCode, String)> {
+    check_order_owner(&db_pool, order_id, &auth_user.user_id).await?;
+
+    let credit_note = refunds::credit_note(&db_pool, order_id, refund_id).await?;
+
+    Ok(([(CONTENT_TYPE, "text/plain; charset=utf-8")], credit_note))
+}
+
+pub async fn get_refunds(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(order_id): Path<i64>,
+) -> Result<Json<Vec<models::RefundDetail>>, (StatusCode, String)> {
+    Ok(Json(refunds::get_refunds(&db_pool, order_id).await?))
+}
+
+pub async fn get_any_credit_note(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path((order_id, refund_id)): Path<(i64, i64)>,
+) -> Result<([(HeaderName, &'static str); 1], String), (StatusCode, String)> {
+    let credit_note = refunds::credit_note(&db_pool, order_id, refund_id).await?;
+
+    Ok(([(CONTENT_TYPE, "text/plain; charset=utf-8")], credit_note))
+}
+
+pub async fn get_returns(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Query(return_query): Query<models::ReturnQuery>,
+) -> Result<Json<Vec<models::ReturnDetail>>, (StatusCode, String)> {
+    let returns = sqlx::query_as!(
+        models::Return,
+        r#"
+        SELECT
+        returns.return_id,
+        returns.order_id,
+        returns.user_id,
+        returns.return_status AS "return_status: models::ReturnStatus",
+        returns.comment,
+        returns.requested_at AS "requested_at: NaiveDateTime",
+        returns.resolved_at AS "resolved_at: NaiveDateTime",
+        returns.resolution_note,
+        refunds.amount AS "refund_amount?: Money"
+        FROM returns
+        LEFT JOIN refunds ON refunds.return_id = returns.return_id
+        WHERE $1 IS NULL OR returns.return_status = $1
+        ORDER BY returns.return_id
+        "#,
+        return_query.status,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let return_items = sqlx::query_as!(
+        models::ReturnItem,
+        r#"
+        SELECT
+        return_items.return_id,
+        return_items.variant_id,
+        return_items.quantity,
+        return_items.reason_code AS "reason_code: models::ReturnReason"
+        FROM return_items
+        INNER JOIN returns ON returns.return_id = return_items.return_id
+        WHERE $1 IS NULL OR returns.return_status = $1
+        "#,
+        return_query.status,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(Json(with_return_items(returns, return_items)))
+}
+
+// Puts each return item with the return it belongs to
+fn with_return_items(
+    returns: Vec<models::Return>,
+    mut return_items: Vec<models::ReturnItem>,
+) -> Vec<models::ReturnDetail> {
+    returns
+        .into_iter()
+        .map(|order_return| {
+            let (items, remaining_items) = return_items
+                .drain(..)
+                .partition(|item| item.return_id == order_return.return_id);
+            return_items = remaining_items;
+
+            models::ReturnDetail {
+                order_return,
+                items,
+            }
+        })
+        .collect()
+}
+
+// Other users' orders are treated as if they don't exist
+async fn check_order_owner(
+    db_pool: &Pool<Sqlite>,
+    order_id: i64,
+    user_id: &str,
+) -> Result<(), (StatusCode, String)> {
+    let order_exists = sqlx::query!(
+        "SELECT order_id FROM orders WHERE order_id = $1 AND user_id = $2",
+        order_id,
+        user_id,
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    match order_exists {
+        Some(_) => Ok(()),
+        None => Err((StatusCode::NOT_FOUND, "Order not found".to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    async fn list_products(
+        db_pool: &Pool<Sqlite>,
+        page: i64,
+        per_page: i64,
+    ) -> Result<Json<models::Page<models::Product>>, (StatusCode, String)> {
+        let product_query = serde_json::from_value(serde_json::json!({
+            "page": page,
+            "per_page": per_page,
+        }))
+        .unwrap();
+
+        get_products(
+            Extension(db_pool.clone()),
+            Conversion::none(),
+            Query(product_query),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn pages_too_far_along_to_count_to_are_rejected() {
+        let db_pool = testing::test_db_pool().await;
+
+        let result = list_products(&db_pool, i64::MAX, MAX_PAGE_SIZE).await;
+        assert_eq!(result.unwrap_err().0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn only_pages_with_products_have_a_next_page() {
+        let db_pool = testing::test_db_pool().await;
+        let Json(first_page) = list_products(&db_pool, 1, 1).await.unwrap();
+        let total = first_page.total;
+        assert_eq!(first_page.next_page, Some(2));
+
+        let Json(last_page) = list_products(&db_pool, total, 1).await.unwrap();
+        assert_eq!(last_page.items.len(), 1);
+        assert_eq!(last_page.next_page, None);
+
+        // Far past the end, where page * per_page would overflow
+        let Json(past_the_end) = list_products(&db_pool, i64::MAX / 2, 1).await.unwrap();
+        assert!(past_the_end.items.is_empty());
+        assert_eq!(past_the_end.next_page, None);
+    }
 }
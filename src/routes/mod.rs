@@ -1,57 +1,227 @@
+mod delete_handlers;
 mod get_handlers;
 mod post_handlers;
+mod put_handlers;
 
 use axum::{
-    extract::Extension,
+    extract::{DefaultBodyLimit, Extension},
     http::{StatusCode, Uri},
-    routing::{get, get_service, post},
+    middleware,
+    routing::{delete, get, get_service, post, put},
     Router,
 };
 use http::{
-    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    header::{HeaderName, ACCEPT, AUTHORIZATION, CONTENT_TYPE},
     Method,
 };
 use sqlx::{migrate::MigrateDatabase, sqlite::SqlitePoolOptions, Pool, Sqlite};
-use std::{collections::HashMap, env, sync::Arc};
-use tokio::sync::Mutex;
+use std::{env, sync::Arc};
 use tower_http::{
     cors::{Any, CorsLayer},
     services::ServeDir,
 };
 
-pub type ActiveUsers = Arc<Mutex<HashMap<String, String>>>;
+use crate::utils::carts;
+use crate::utils::currencies;
+use crate::utils::images::{ImageStorage, LocalImageStorage, MAX_IMAGE_BYTES};
+use crate::utils::money;
+use crate::utils::payments::{self, FakePaymentGateway, PaymentProvider};
+use crate::utils::permissions::{require_permission, Permission};
+use crate::utils::sessions::{self, SessionStore};
 
 pub async fn create_router() -> Router {
     // Create the database pool
     let db_pool = create_db_pool().await;
+    money::check_store_currency(&db_pool)
+        .await
+        .unwrap_or_else(|error| panic!("error: {}", error));
+
+    // Takes card payments. The fake gateway never charges anyone, so swap it for a real provider
+    let payment_provider: Arc<dyn PaymentProvider> = Arc::new(FakePaymentGateway);
+
+    // Clear out sessions, guest carts and unpaid orders that have expired
+    sessions::spawn_pruning_task(SessionStore::new(db_pool.clone()));
+    carts::spawn_pruning_task(db_pool.clone());
+    payments::spawn_expiry_task(db_pool.clone(), payment_provider.clone());
+
+    app(db_pool, payment_provider)
+}
 
+// Every route, along with everything the handlers need from the layers
+fn app(db_pool: Pool<Sqlite>, payment_provider: Arc<dyn PaymentProvider>) -> Router {
     // The Cors Layer tells the client what methods are supported and from where
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
         .allow_origin(Any)
-        .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]);
+        .allow_headers([
+            AUTHORIZATION,
+            ACCEPT,
+            CONTENT_TYPE,
+            HeaderName::from_static(carts::CART_TOKEN_HEADER),
+            HeaderName::from_static(currencies::CURRENCY_HEADER),
+        ]);
+
+    // Sessions of users that are currently logged in, backed by the database
+    let session_store = SessionStore::new(db_pool.clone());
+
+    // Where uploaded product images are kept, which is also where /images/products serves them from
+    let local_image_storage = LocalImageStorage::from_env();
+    let product_images = ServeDir::new(local_image_storage.directory());
+    let image_storage: Arc<dyn ImageStorage> = Arc::new(local_image_storage);
+
+    // Routes that need a permission, on top of being logged in
+    let user_admin_routes = Router::new()
+        .route(
+            "/admin/users/:user_id/roles",
+            post(post_handlers::grant_role),
+        )
+        .route(
+            "/admin/users/:user_id/roles/:role_name",
+            delete(delete_handlers::revoke_role),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            Permission::Users,
+            require_permission,
+        ));
+
+    let product_admin_routes = Router::new()
+        .route("/admin/products", post(post_handlers::create_product))
+        .route(
+            "/admin/products/:product_id",
+            put(put_handlers::update_product),
+        )
+        .route(
+            "/admin/products/:product_id/image",
+            // Leave some room for the rest of the multipart body
+            post(post_handlers::upload_product_image)
+                .layer(DefaultBodyLimit::max(MAX_IMAGE_BYTES + 64 * 1024)),
+        )
+        .route(
+            "/admin/products/:product_id/archive",
+            post(post_handlers::archive_product),
+        )
+        .route(
+            "/admin/products/:product_id/restore",
+            post(post_handlers::restore_product),
+        )
+        .route(
+            "/admin/products/:product_id/variants",
+            post(post_handlers::create_variant),
+        )
+        .route(
+            "/admin/variants/:variant_id",
+            put(put_handlers::update_variant).delete(delete_handlers::delete_variant),
+        )
+        .route("/admin/categories", post(post_handlers::create_category))
+        .route(
+            "/admin/categories/:category_id",
+            put(put_handlers::update_category).delete(delete_handlers::delete_category),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            Permission::Products,
+            require_permission,
+        ));
+
+    // Exchange rates change what every shopper sees, so they have a permission of their own
+    let exchange_rate_admin_routes = Router::new()
+        .route(
+            "/admin/exchange_rates/:currency",
+            put(put_handlers::set_exchange_rate).delete(delete_handlers::delete_exchange_rate),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            Permission::ExchangeRates,
+            require_permission,
+        ));
 
-    // A hashmap to hold users that are currently logged in
-    let active_users = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+    let order_admin_routes = Router::new()
+        .route("/admin/orders", get(get_handlers::get_all_orders))
+        .route(
+            "/admin/orders/:order_id/status",
+            post(post_handlers::change_order_status),
+        )
+        .route(
+            "/admin/orders/:order_id/refunds",
+            get(get_handlers::get_refunds).post(post_handlers::create_refund),
+        )
+        .route(
+            "/admin/orders/:order_id/refunds/:refund_id/credit_note",
+            get(get_handlers::get_any_credit_note),
+        )
+        .route("/admin/returns", get(get_handlers::get_returns))
+        .route(
+            "/admin/returns/:return_id/approve",
+            post(post_handlers::approve_return),
+        )
+        .route(
+            "/admin/returns/:return_id/reject",
+            post(post_handlers::reject_return),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            Permission::Orders,
+            require_permission,
+        ));
 
     Router::new()
         .route("/create_user", post(post_handlers::create_user))
         .route("/login", post(post_handlers::login))
+        .route("/token/refresh", post(post_handlers::refresh_token))
         .route("/logout", post(post_handlers::logout))
         .route("/get_products", get(get_handlers::get_products))
+        .route("/products/search", get(get_handlers::search_products))
+        .route("/products/:product_id", get(get_handlers::get_product))
+        .route("/categories", get(get_handlers::get_categories))
+        .route("/currencies", get(get_handlers::get_currencies))
         .route("/get_addresses", get(get_handlers::get_addresses))
         .route("/create_address", post(post_handlers::create_address))
         .route("/get_personal_info", get(get_handlers::get_personal_info))
         .route("/add_personal_info", post(post_handlers::add_personal_info))
+        .route(
+            "/preferences",
+            get(get_handlers::get_preferences).put(put_handlers::update_preferences),
+        )
         .route("/get_cart", get(get_handlers::get_cart))
         .route("/add_to_cart", post(post_handlers::add_to_cart))
-        // .route("/get_orders", get(get_handlers::get_orders))
-        .route("/orders/:order_id", get(get_handlers::get_order_items))
+        .route("/cart/guest", post(post_handlers::start_guest_cart))
+        .route("/cart", delete(delete_handlers::clear_cart))
+        .route(
+            "/cart/items/:variant_id",
+            put(put_handlers::update_cart_item).delete(delete_handlers::remove_cart_item),
+        )
+        .route("/orders", get(get_handlers::get_orders))
+        .route("/orders/:order_id", get(get_handlers::get_order))
+        .route(
+            "/orders/:order_id/cancel",
+            post(post_handlers::cancel_order),
+        )
+        .route(
+            "/orders/:order_id/returns",
+            get(get_handlers::get_order_returns).post(post_handlers::request_return),
+        )
+        .route(
+            "/orders/:order_id/refunds",
+            get(get_handlers::get_order_refunds),
+        )
+        .route(
+            "/orders/:order_id/refunds/:refund_id/credit_note",
+            get(get_handlers::get_credit_note),
+        )
         .route("/create_order", post(post_handlers::create_order))
+        .route(
+            "/payments/:payment_intent_id/confirm",
+            post(post_handlers::confirm_payment),
+        )
+        .route("/webhooks/payments", post(post_handlers::payment_webhook))
+        .merge(user_admin_routes)
+        .merge(product_admin_routes)
+        .merge(exchange_rate_admin_routes)
+        .merge(order_admin_routes)
         .nest_service("/", get_service(ServeDir::new("assets/pages")))
+        .nest_service("/images/products", get_service(product_images))
         .nest_service("/images", get_service(ServeDir::new("assets/images")))
         .nest_service("/scripts", get_service(ServeDir::new("assets/scripts")))
-        .layer(Extension(active_users))
+        .layer(Extension(session_store))
+        .layer(Extension(image_storage))
+        .layer(Extension(payment_provider))
         .layer(Extension(db_pool))
         .layer(cors)
         .fallback(handler_404) // Fallback for get requests for pages that don't exist
@@ -93,7 +263,7 @@ async fn handler_404(uri: Uri) -> (StatusCode, String) {
 }
 
 // Maps database errors to a status code and a message
-fn map_db_error(error: sqlx::Error) -> (StatusCode, String) {
+pub(crate) fn map_db_error(error: sqlx::Error) -> (StatusCode, String) {
     let error_message = format!("Database error: {}", error);
     eprint!("{}", error_message);
     (
@@ -101,3 +271,134 @@ fn map_db_error(error: sqlx::Error) -> (StatusCode, String) {
         "Something went wrong. Please try again later".to_owned(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::utils::{jwt, testing};
+
+    // Routes from each group of admin routes, which need a different permission each
+    const ADMIN_ROUTES: [(Method, &str); 4] = [
+        (Method::GET, "/admin/orders"),
+        (Method::GET, "/admin/returns"),
+        (Method::DELETE, "/admin/users/nobody/roles/customer"),
+        (Method::DELETE, "/admin/exchange_rates/USD"),
+    ];
+
+    async fn send(
+        db_pool: &Pool<Sqlite>,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+    ) -> StatusCode {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        app(db_pool.clone(), Arc::new(FakePaymentGateway))
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    async fn login_token(db_pool: &Pool<Sqlite>, role: &str) -> String {
+        env::set_var("JWT_SECRET", "testsecret");
+        let user_id = testing::create_user(db_pool, &[role]).await;
+
+        jwt::create_jwt(&user_id, "testsession", vec![role.to_owned()]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn customers_are_forbidden_from_admin_routes() {
+        let db_pool = testing::test_db_pool().await;
+        let token = login_token(&db_pool, "customer").await;
+
+        for (method, uri) in ADMIN_ROUTES {
+            let status = send(&db_pool, method, uri, Some(&token)).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{}", uri);
+        }
+
+        let status = send(&db_pool, Method::PUT, "/admin/products/1", Some(&token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn admins_are_let_through_to_admin_routes() {
+        let db_pool = testing::test_db_pool().await;
+        let token = login_token(&db_pool, "admin").await;
+
+        assert_eq!(
+            send(&db_pool, Method::GET, "/admin/orders", Some(&token)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(&db_pool, Method::GET, "/admin/returns", Some(&token)).await,
+            StatusCode::OK
+        );
+
+        // The handler runs and finds the user doesn't have the role
+        let status = send(
+            &db_pool,
+            Method::DELETE,
+            "/admin/users/nobody/roles/customer",
+            Some(&token),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // No rate has been set for the currency yet
+        let status = send(
+            &db_pool,
+            Method::DELETE,
+            "/admin/exchange_rates/USD",
+            Some(&token),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn managing_products_doesnt_include_exchange_rates() {
+        let db_pool = testing::test_db_pool().await;
+        sqlx::query!(
+            "
+            INSERT INTO roles (role_name) VALUES ('catalogue');
+            INSERT INTO role_permissions (role_name, permission_name)
+            VALUES ('catalogue', 'manage_products');
+            "
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+        let token = login_token(&db_pool, "catalogue").await;
+
+        // Let through to the handler, which wants a body
+        let status = send(&db_pool, Method::PUT, "/admin/products/1", Some(&token)).await;
+        assert_ne!(status, StatusCode::FORBIDDEN);
+
+        let status = send(
+            &db_pool,
+            Method::DELETE,
+            "/admin/exchange_rates/USD",
+            Some(&token),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_a_login() {
+        let db_pool = testing::test_db_pool().await;
+
+        for (method, uri) in ADMIN_ROUTES {
+            let status = send(&db_pool, method, uri, None).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", uri);
+        }
+    }
+}
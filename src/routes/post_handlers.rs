@@ -1,19 +1,30 @@
 use axum::{
-    extract::Extension,
-    headers::{authorization::Bearer, Authorization},
-    http::StatusCode,
+    body::Bytes,
+    extract::{ConnectInfo, Extension, Multipart, Path},
+    headers::UserAgent,
+    http::{HeaderMap, StatusCode},
     Json, TypedHeader,
 };
-use chrono::Local;
+use chrono::{Local, Utc};
 use pwhash::bcrypt;
-use sqlx::{Pool, Sqlite};
+use sqlx::{Pool, Sqlite, Transaction};
+use std::{net::SocketAddr, sync::Arc};
 
 use crate::routes::map_db_error;
-use crate::utils::auth;
-use crate::utils::jwt;
+use crate::utils::auth::{self, AuthUser};
+use crate::utils::carts::{self, CartOwner};
+use crate::utils::categories;
+use crate::utils::currencies::Conversion;
+use crate::utils::images::{self, ImageStorage};
 use crate::utils::models;
-
-use super::ActiveUsers;
+use crate::utils::money::{Currency, Money};
+use crate::utils::orders;
+use crate::utils::payments::{self, AuthorizeOutcome, PaymentError, PaymentProvider};
+use crate::utils::pricing::{self, LinePrice};
+use crate::utils::refunds::{self, RefundRequest, RefundScope};
+use crate::utils::sessions::{RefreshOutcome, SessionInfo, SessionStore};
+use crate::utils::variants;
+use crate::utils::webhooks;
 
 pub async fn create_user(
     Extension(db_pool): Extension<Pool<Sqlite>>,
@@ -29,6 +40,8 @@ pub async fn create_user(
     // Create a new user with a new UUID and hashed password
     let user = models::User::new(&new_user);
 
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
     sqlx::query_as!(
         models::User,
         "
@@ -40,23 +53,45 @@ pub async fn create_user(
         user.user_email,
         user.user_password_hash,
     )
-    .execute(&db_pool)
+    .execute(&mut transaction)
     .await
     .map_err(map_db_error)?;
 
+    // Every new user starts out as a customer
+    sqlx::query!(
+        "INSERT INTO user_roles (user_id, role_name) VALUES ($1, 'customer')",
+        user.user_id,
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
     Ok("User created successfully".to_owned())
 }
 
 pub async fn login(
     Extension(db_pool): Extension<Pool<Sqlite>>,
-    Extension(active_users): Extension<ActiveUsers>,
+    Extension(session_store): Extension<SessionStore>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
     Json(request_user): Json<models::RequestUser>,
-) -> Result<String, (StatusCode, String)> {
+) -> Result<Json<models::TokenPair>, (StatusCode, String)> {
     // Emails are typically not case sensitive, so we lowercase them
     let user_email_lowercase = request_user.user_email.to_lowercase();
     let user_option = sqlx::query_as!(
         models::User,
-        "SELECT * FROM users WHERE user_email=$1;",
+        r#"
+        SELECT
+        user_id,
+        username,
+        user_email,
+        user_password_hash,
+        preferred_currency AS "preferred_currency: Currency"
+        FROM users WHERE user_email=$1;
+        "#,
         user_email_lowercase
     )
     .fetch_optional(&db_pool)
@@ -65,15 +100,29 @@ pub async fn login(
 
     match user_option {
         Some(user) if bcrypt::verify(request_user.user_password, &user.user_password_hash) => {
-            let new_active_user_id = user.user_id;
-            let new_active_user_token = jwt::create_jwt()?;
+            let session_info = SessionInfo {
+                user_agent: user_agent.map(|user_agent| user_agent.as_str().to_owned()),
+                ip_address: Some(client_addr.ip().to_string()),
+            };
+
+            // Anything the user put in their cart before logging in is kept. It's merged
+            // first so a failed merge doesn't leave a session behind that nobody got tokens for.
+            if let Some(cart_token) = carts::cart_token(&headers) {
+                carts::merge_guest_cart(
+                    &db_pool,
+                    &cart_token,
+                    &user.user_id,
+                    carts::MergePolicy::from_env(),
+                )
+                .await?;
+            }
 
-            active_users.lock().await.insert(
-                new_active_user_token.to_owned(),
-                new_active_user_id.to_owned(),
-            );
+            let new_session = session_store
+                .insert(&user.user_id, session_info)
+                .await
+                .map_err(map_db_error)?;
 
-            Ok(new_active_user_token)
+            Ok(Json(auth::create_token_pair(&db_pool, new_session).await?))
         }
         Some(_) => Err((
             StatusCode::UNAUTHORIZED,
@@ -86,30 +135,51 @@ pub async fn login(
     }
 }
 
+pub async fn refresh_token(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Extension(session_store): Extension<SessionStore>,
+    Json(refresh_request): Json<models::RefreshRequest>,
+) -> Result<Json<models::TokenPair>, (StatusCode, String)> {
+    let refresh_outcome = session_store
+        .rotate(&refresh_request.refresh_token)
+        .await
+        .map_err(map_db_error)?;
+
+    match refresh_outcome {
+        RefreshOutcome::Rotated(session) => {
+            Ok(Json(auth::create_token_pair(&db_pool, session).await?))
+        }
+        RefreshOutcome::Reused => Err((
+            StatusCode::UNAUTHORIZED,
+            "This refresh token has already been used, so its session has been ended. Please login again".to_owned(),
+        )),
+        RefreshOutcome::Invalid => Err((
+            StatusCode::UNAUTHORIZED,
+            "The refresh token provided is invalid or has expired. Please login again".to_owned(),
+        )),
+    }
+}
+
 pub async fn logout(
-    Extension(active_users): Extension<ActiveUsers>,
-    authorization: TypedHeader<Authorization<Bearer>>,
+    Extension(session_store): Extension<SessionStore>,
+    auth_user: AuthUser,
 ) -> Result<String, (StatusCode, String)> {
-    auth::remove_active_user(authorization.token().to_owned(), active_users).await?;
+    auth::remove_active_user(&auth_user, session_store).await?;
 
     Ok("Successfully logged out".to_owned())
 }
 
 pub async fn create_address(
     Extension(db_pool): Extension<Pool<Sqlite>>,
-    Extension(active_users): Extension<ActiveUsers>,
-    authorization: TypedHeader<Authorization<Bearer>>,
+    auth_user: AuthUser,
     Json(address): Json<models::Address>,
 ) -> Result<String, (StatusCode, String)> {
-    let authed_user_id =
-        auth::authenticate_user(authorization.token().to_owned(), active_users).await?;
-
     sqlx::query!(
         "
         INSERT INTO addresses (user_id, unit, street, city, postal_code, state_province, country)
         VALUES ($1, $2, $3, $4, $5, $6, $7);
         ",
-        authed_user_id,
+        auth_user.user_id,
         address.unit,
         address.street,
         address.city,
@@ -126,16 +196,12 @@ pub async fn create_address(
 
 pub async fn add_personal_info(
     Extension(db_pool): Extension<Pool<Sqlite>>,
-    Extension(active_users): Extension<ActiveUsers>,
-    authorization: TypedHeader<Authorization<Bearer>>,
+    auth_user: AuthUser,
     Json(personal_info): Json<models::PersonalInfo>,
 ) -> Result<String, (StatusCode, String)> {
-    let authed_user_id =
-        auth::authenticate_user(authorization.token().to_owned(), active_users).await?;
-
     let personal_info_exists = sqlx::query!(
         "SELECT user_id FROM personal_info WHERE user_id = $1",
-        authed_user_id
+        auth_user.user_id
     )
     .fetch_optional(&db_pool)
     .await
@@ -147,7 +213,7 @@ pub async fn add_personal_info(
             INSERT INTO personal_info (user_id, first_name, last_name, gender)
             VALUES ($1, $2, $3, $4)
             ",
-            authed_user_id,
+            auth_user.user_id,
             personal_info.first_name,
             personal_info.last_name,
             personal_info.gender,
@@ -169,7 +235,7 @@ pub async fn add_personal_info(
             personal_info.first_name,
             personal_info.last_name,
             personal_info.gender,
-            authed_user_id,
+            auth_user.user_id,
         )
         .execute(&db_pool)
         .await
@@ -179,77 +245,100 @@ pub async fn add_personal_info(
     }
 }
 
+pub async fn start_guest_cart(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+) -> Result<Json<models::GuestCart>, (StatusCode, String)> {
+    let guest_cart = carts::start_guest_cart(&db_pool)
+        .await
+        .map_err(map_db_error)?;
+
+    Ok(Json(guest_cart))
+}
+
 pub async fn add_to_cart(
     Extension(db_pool): Extension<Pool<Sqlite>>,
-    Extension(active_users): Extension<ActiveUsers>,
-    authorization: TypedHeader<Authorization<Bearer>>,
+    cart_owner: CartOwner,
     Json(cart_item): Json<models::CartItem>,
 ) -> Result<String, (StatusCode, String)> {
-    let authed_user_id =
-        auth::authenticate_user(authorization.token().to_owned(), active_users).await?;
+    let variant_option =
+        carts::find_variant(&db_pool, Some(cart_item.product_id), cart_item.variant_id)
+            .await
+            .map_err(map_db_error)?;
+
+    let Some(variant) = variant_option else {
+        return Err((StatusCode::NOT_FOUND, "Product not found".to_owned()));
+    };
+
+    carts::check_quantity(cart_item.quantity, variant.stock)?;
+
+    let cart_id = carts::find_or_create_cart(&db_pool, &cart_owner).await?;
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
 
-    let quantity_exists = sqlx::query!(
-        "SELECT quantity FROM cart_items WHERE user_id = $1 AND product_id = $2",
-        authed_user_id,
-        cart_item.product_id,
+    // Adding a variant that is already in the cart adds to its quantity
+    let new_quantity = sqlx::query!(
+        r#"
+        INSERT INTO cart_items (cart_id, variant_id, quantity, price_when_added)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (cart_id, variant_id) DO UPDATE SET quantity = quantity + excluded.quantity
+        RETURNING quantity AS "quantity!"
+        "#,
+        cart_id,
+        variant.variant_id,
+        cart_item.quantity,
+        variant.price,
     )
-    .fetch_optional(&db_pool)
+    .fetch_one(&mut transaction)
     .await
-    .map_err(map_db_error)?;
+    .map_err(map_db_error)?
+    .quantity;
 
-    match quantity_exists {
-        Some(quantity) => {
-            let new_quantity = quantity.quantity + cart_item.quantity;
-
-            sqlx::query!(
-                "
-                UPDATE cart_items
-                SET quantity = $1
-                WHERE user_id = $2 AND product_id = $3
-                ",
-                new_quantity,
-                authed_user_id,
-                cart_item.product_id,
-            )
-            .execute(&db_pool)
-            .await
-            .map_err(map_db_error)?;
+    carts::check_quantity(new_quantity, variant.stock)?;
 
-            Ok("Cart item updated successfully".to_owned())
-        }
-        None => {
-            sqlx::query!(
-                "
-                INSERT INTO cart_items (user_id, product_id, quantity)
-                VALUES ($1, $2, $3)
-                ",
-                authed_user_id,
-                cart_item.product_id,
-                cart_item.quantity,
-            )
-            .execute(&db_pool)
-            .await
-            .map_err(map_db_error)?;
+    transaction.commit().await.map_err(map_db_error)?;
 
-            Ok("Cart item added successfully".to_owned())
-        }
-    }
+    Ok("Cart item added successfully".to_owned())
 }
 
 pub async fn create_order(
     Extension(db_pool): Extension<Pool<Sqlite>>,
-    Extension(active_users): Extension<ActiveUsers>,
-    authorization: TypedHeader<Authorization<Bearer>>,
-) -> Result<String, (StatusCode, String)> {
-    let authed_user_id =
-        auth::authenticate_user(authorization.token().to_owned(), active_users).await?;
+    Extension(payment_provider): Extension<Arc<dyn PaymentProvider>>,
+    auth_user: AuthUser,
+    conversion: Conversion,
+    Json(checkout): Json<models::Checkout>,
+) -> Result<(StatusCode, Json<models::PaymentIntent>), (StatusCode, String)> {
+    checkout
+        .payment
+        .validate()
+        .map_err(|error| (StatusCode::BAD_REQUEST, error))?;
+
+    let billing_address_id = checkout
+        .billing_address_id
+        .unwrap_or(checkout.shipping_address_id);
+
+    // Everything happens in a single transaction, so if anything fails the order is
+    // never created. The cart is only emptied once the payment has been authorized.
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
 
-    let user_cart_items = sqlx::query_as!(
-        models::CartItem,
-        "SELECT * FROM cart_items WHERE user_id = $1",
-        authed_user_id,
+    let user_cart_items = sqlx::query!(
+        r#"
+        SELECT
+        cart_items.variant_id,
+        cart_items.quantity,
+        product_variants.sku,
+        product_variants.price AS "price: Money",
+        product_variants.stock,
+        products.product_name,
+        products.archived_at
+        FROM cart_items
+        INNER JOIN product_variants ON product_variants.variant_id = cart_items.variant_id
+        INNER JOIN products ON products.product_id = product_variants.product_id
+        INNER JOIN carts ON carts.cart_id = cart_items.cart_id
+        WHERE carts.user_id = $1
+        "#,
+        auth_user.user_id,
     )
-    .fetch_all(&db_pool)
+    .fetch_all(&mut transaction)
     .await
     .map_err(map_db_error)?;
 
@@ -257,75 +346,1212 @@ pub async fn create_order(
         return Err((StatusCode::BAD_REQUEST, "Cart is empty".to_owned()));
     }
 
+    let unavailable_items: Vec<String> = user_cart_items
+        .iter()
+        .filter(|cart_item| cart_item.archived_at.is_some() || cart_item.quantity > cart_item.stock)
+        .map(|cart_item| match cart_item.archived_at {
+            Some(_) => format!(
+                "{} ({}) is no longer sold",
+                cart_item.product_name, cart_item.sku
+            ),
+            None => format!(
+                "{} ({}) has {} left but {} were ordered",
+                cart_item.product_name,
+                cart_item.sku,
+                cart_item.stock.max(0),
+                cart_item.quantity
+            ),
+        })
+        .collect();
+
+    if !unavailable_items.is_empty() {
+        return Err((
+            StatusCode::CONFLICT,
+            format!(
+                "Some items in your cart are out of stock: {}",
+                unavailable_items.join("; ")
+            ),
+        ));
+    }
+
     let local_time_now = Local::now().naive_local();
+    // The order is charged in the store's currency, but the currency the shopper saw it in
+    // and the rate used are kept with it
     let new_order_id = sqlx::query!(
         "
-        INSERT INTO orders (creation_time, order_status)
-        VALUES ($1, $2)
-        RETURNING order_id
+        INSERT INTO orders (user_id, creation_time, order_status, currency, exchange_rate)
+        VALUES ($1, $2, $3, $4, $5)
         ",
+        auth_user.user_id,
         local_time_now,
         models::OrderStatus::Pending,
+        conversion.currency,
+        conversion.rate,
     )
-    .fetch_one(&db_pool)
+    .execute(&mut transaction)
     .await
     .map_err(map_db_error)?
-    .order_id;
+    .last_insert_rowid();
 
-    // Add cart items to the order and tally up the total cost
-    let mut total_cost = 0.0;
+    sqlx::query!(
+        "
+        INSERT INTO order_status_history (order_id, to_status, changed_by, changed_at)
+        VALUES ($1, $2, $3, $4)
+        ",
+        new_order_id,
+        models::OrderStatus::Pending,
+        auth_user.user_id,
+        local_time_now,
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    // Copy the addresses onto the order, only the user's own addresses can be used
+    let addresses = [
+        (models::AddressType::Shipping, checkout.shipping_address_id),
+        (models::AddressType::Billing, billing_address_id),
+    ];
+    for (address_type, address_id) in addresses {
+        let address_result = sqlx::query!(
+            "
+            INSERT INTO order_addresses
+            (order_id, address_type, unit, street, city, postal_code, state_province, country)
+            SELECT $1, $2, unit, street, city, postal_code, state_province, country
+            FROM addresses
+            WHERE address_id = $3 AND user_id = $4
+            ",
+            new_order_id,
+            address_type,
+            address_id,
+            auth_user.user_id,
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(map_db_error)?;
+
+        if address_result.rows_affected() == 0 {
+            return Err((StatusCode::NOT_FOUND, "Address not found".to_owned()));
+        }
+    }
+
+    // Take the stock and add the cart items to the order along with the prices as they
+    // are right now, so the order is never affected by later price changes
+    let tax_rate = pricing::tax_rate();
     for cart_item in user_cart_items {
-        let product_cost_option = sqlx::query!(
+        // Checking the stock again here means another order placed since the cart
+        // was read can't take the stock count below zero
+        let stock_result = sqlx::query!(
             "
-            SELECT SUM(cart_items.quantity * products.price) AS product_cost
-            FROM cart_items
-            INNER JOIN products ON cart_items.product_id = products.product_id
-            WHERE cart_items.product_id = $1
+            UPDATE product_variants
+            SET stock = stock - $1
+            WHERE variant_id = $2 AND stock >= $1
             ",
-            cart_item.product_id,
+            cart_item.quantity,
+            cart_item.variant_id,
         )
-        .fetch_one(&db_pool)
+        .execute(&mut transaction)
         .await
-        .map_err(map_db_error)?
-        .product_cost;
+        .map_err(map_db_error)?;
 
-        // product_cost_option is an Option because it comes from a JOIN sql statement
-        // It should, in theory, never be None, but just in case, we handle it
-        match product_cost_option {
-            Some(product_cost) => total_cost += product_cost,
-            None => total_cost += 0.0,
+        if stock_result.rows_affected() == 0 {
+            return Err((
+                StatusCode::CONFLICT,
+                format!(
+                    "Some items in your cart are out of stock: {} ({}) sold out while ordering",
+                    cart_item.product_name, cart_item.sku
+                ),
+            ));
         }
 
+        // There aren't any discounts to apply yet
+        let line_price = LinePrice::new(
+            cart_item.price,
+            cart_item.quantity,
+            Money::zero(cart_item.price.currency()),
+            tax_rate,
+        );
+
         sqlx::query!(
             "
-            INSERT INTO order_items (order_id, product_id, quantity)
-            VALUES ($1, $2, $3)
+            INSERT INTO order_items
+            (order_id, variant_id, quantity, unit_price, line_subtotal, discount, tax)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
             ",
             new_order_id,
-            cart_item.product_id,
+            cart_item.variant_id,
             cart_item.quantity,
+            line_price.unit_price,
+            line_price.line_subtotal,
+            line_price.discount,
+            line_price.tax,
         )
-        .execute(&db_pool)
+        .execute(&mut transaction)
         .await
         .map_err(map_db_error)?;
     }
 
-    sqlx::query!(
-        "UPDATE orders SET total_cost = $1 WHERE order_id = $2",
-        total_cost,
+    // The total only ever comes from the prices recorded on the order items
+    let total_cost = sqlx::query!(
+        r#"
+        UPDATE orders
+        SET total_cost = (
+            SELECT SUM(line_subtotal - discount + tax) FROM order_items WHERE order_id = $1
+        )
+        WHERE order_id = $1
+        RETURNING total_cost AS "total_cost!: Money"
+        "#,
         new_order_id,
     )
+    .fetch_one(&mut transaction)
+    .await
+    .map_err(map_db_error)?
+    .total_cost;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    // The order holds on to its stock while the payment is authorized, and it's
+    // cancelled again if the payment doesn't go through
+    let authorization = payment_provider
+        .authorize(total_cost, &checkout.payment)
+        .await;
+
+    let payment_intent = payments::record_authorization(
+        &db_pool,
+        payment_provider.as_ref(),
+        new_order_id,
+        total_cost,
+        &checkout.payment.last4(),
+        authorization,
+    )
+    .await?;
+
+    payment_response(payment_intent)
+}
+
+// Finishes a payment that needed the customer to pass a 3-D Secure challenge
+pub async fn confirm_payment(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Extension(payment_provider): Extension<Arc<dyn PaymentProvider>>,
+    auth_user: AuthUser,
+    Path(payment_intent_id): Path<i64>,
+    Json(challenge_response): Json<models::ChallengeResponse>,
+) -> Result<(StatusCode, Json<models::PaymentIntent>), (StatusCode, String)> {
+    let payment_intent_option =
+        payments::find_user_intent(&db_pool, payment_intent_id, &auth_user.user_id)
+            .await
+            .map_err(map_db_error)?;
+
+    let Some(payment_intent) = payment_intent_option else {
+        return Err((StatusCode::NOT_FOUND, "Payment not found".to_owned()));
+    };
+
+    if payment_intent.payment_status != models::PaymentStatus::RequiresAction {
+        return Err((
+            StatusCode::CONFLICT,
+            "The payment doesn't need to be confirmed".to_owned(),
+        ));
+    }
+
+    let outcome = payment_provider
+        .confirm_challenge(
+            payment_intent
+                .provider_reference
+                .as_deref()
+                .unwrap_or_default(),
+            &challenge_response.code,
+        )
+        .await
+        .unwrap_or_else(|PaymentError(message)| AuthorizeOutcome::Declined(message));
+
+    let payment_intent =
+        payments::record_challenge_result(&db_pool, &payment_intent, outcome).await?;
+
+    payment_response(payment_intent)
+}
+
+// Called by the payment provider when something happens to a payment, e.g. when the
+// customer passes a challenge on the bank's page or a refund goes through
+pub async fn payment_webhook(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Extension(payment_provider): Extension<Arc<dyn PaymentProvider>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<String, (StatusCode, String)> {
+    let Some(webhook_secret) = webhooks::webhook_secret() else {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Payment webhooks haven't been set up".to_owned(),
+        ));
+    };
+
+    let signature_header = headers
+        .get(webhooks::SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or((
+            StatusCode::UNAUTHORIZED,
+            "The webhook isn't signed".to_owned(),
+        ))?;
+
+    webhooks::verify_signature(
+        &webhook_secret,
+        signature_header,
+        &body,
+        Utc::now().timestamp(),
+    )
+    .map_err(|error| (StatusCode::UNAUTHORIZED, error))?;
+
+    let event: models::PaymentEvent = serde_json::from_slice(&body).map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            "The webhook isn't a payment event we understand".to_owned(),
+        )
+    })?;
+
+    match payments::process_event(&db_pool, payment_provider.name(), &event).await? {
+        true => Ok("Event processed successfully".to_owned()),
+        false => Ok("Event was already processed".to_owned()),
+    }
+}
+
+// Authorized payments have placed the order, while ones waiting on a challenge need
+// the customer to confirm them. Declined payments have had their order cancelled.
+fn payment_response(
+    payment_intent: models::PaymentIntent,
+) -> Result<(StatusCode, Json<models::PaymentIntent>), (StatusCode, String)> {
+    match payment_intent.payment_status {
+        models::PaymentStatus::RequiresAction => Ok((StatusCode::ACCEPTED, Json(payment_intent))),
+        models::PaymentStatus::Declined => Err((
+            StatusCode::PAYMENT_REQUIRED,
+            format!(
+                "The payment was declined: {}. Order {} has been cancelled",
+                payment_intent.failure_message.unwrap_or_default(),
+                payment_intent.order_id
+            ),
+        )),
+        _ => Ok((StatusCode::CREATED, Json(payment_intent))),
+    }
+}
+
+pub async fn change_order_status(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Extension(payment_provider): Extension<Arc<dyn PaymentProvider>>,
+    auth_user: AuthUser,
+    Path(order_id): Path<i64>,
+    Json(status_change): Json<models::StatusChange>,
+) -> Result<String, (StatusCode, String)> {
+    // Refunding an order has to pay the money back, which creating a refund takes care of
+    if status_change.order_status == models::OrderStatus::Refunded {
+        return Err((
+            StatusCode::CONFLICT,
+            "Create a refund for the order instead".to_owned(),
+        ));
+    }
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    orders::change_status(
+        &mut transaction,
+        order_id,
+        status_change.order_status,
+        Some(&auth_user.user_id),
+        status_change.note.as_deref(),
+    )
+    .await?;
+
+    payments::settle_for_status(
+        payment_provider.as_ref(),
+        &mut transaction,
+        order_id,
+        status_change.order_status,
+    )
+    .await?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok("Order status changed successfully".to_owned())
+}
+
+pub async fn create_refund(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Extension(payment_provider): Extension<Arc<dyn PaymentProvider>>,
+    auth_user: AuthUser,
+    Path(order_id): Path<i64>,
+    Json(new_refund): Json<models::NewRefund>,
+) -> Result<(StatusCode, Json<models::Refund>), (StatusCode, String)> {
+    let scope = match (new_refund.items.is_empty(), new_refund.amount) {
+        (false, None) => RefundScope::Items(new_refund.items),
+        (true, Some(amount)) => RefundScope::Amount(amount),
+        (true, None) => RefundScope::Everything,
+        (false, Some(_)) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Refund either items or an amount, not both".to_owned(),
+            ));
+        }
+    };
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    let refund = refunds::create_refund(
+        payment_provider.as_ref(),
+        &mut transaction,
+        order_id,
+        RefundRequest {
+            scope,
+            restock: new_refund.restock,
+            note: new_refund.note.as_deref(),
+            return_id: None,
+        },
+        Some(&auth_user.user_id),
+    )
+    .await?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok((StatusCode::CREATED, Json(refund)))
+}
+
+pub async fn cancel_order(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Extension(payment_provider): Extension<Arc<dyn PaymentProvider>>,
+    auth_user: AuthUser,
+    Path(order_id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    let order_exists = sqlx::query!(
+        "SELECT order_id FROM orders WHERE order_id = $1 AND user_id = $2",
+        order_id,
+        auth_user.user_id,
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    if order_exists.is_none() {
+        return Err((StatusCode::NOT_FOUND, "Order not found".to_owned()));
+    }
+
+    // The state machine stops orders that have already shipped from being cancelled
+    orders::change_status(
+        &mut transaction,
+        order_id,
+        models::OrderStatus::Cancelled,
+        Some(&auth_user.user_id),
+        Some("Cancelled by the customer"),
+    )
+    .await?;
+
+    payments::settle_for_status(
+        payment_provider.as_ref(),
+        &mut transaction,
+        order_id,
+        models::OrderStatus::Cancelled,
+    )
+    .await?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok("Order cancelled successfully".to_owned())
+}
+
+pub async fn request_return(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
+    Path(order_id): Path<i64>,
+    Json(new_return): Json<models::NewReturn>,
+) -> Result<String, (StatusCode, String)> {
+    if new_return.items.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Choose at least one item to return".to_owned(),
+        ));
+    }
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    let order_option = sqlx::query!(
+        r#"
+        SELECT order_status AS "order_status: models::OrderStatus"
+        FROM orders
+        WHERE order_id = $1 AND user_id = $2
+        "#,
+        order_id,
+        auth_user.user_id,
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(order) = order_option else {
+        return Err((StatusCode::NOT_FOUND, "Order not found".to_owned()));
+    };
+
+    if order.order_status != models::OrderStatus::Delivered {
+        return Err((
+            StatusCode::CONFLICT,
+            "Only delivered orders can be returned".to_owned(),
+        ));
+    }
+
+    for (index, item) in new_return.items.iter().enumerate() {
+        if item.quantity < 1 {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "The quantity to return must be at least 1".to_owned(),
+            ));
+        }
+        if new_return.items[..index]
+            .iter()
+            .any(|other_item| other_item.variant_id == item.variant_id)
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Each item can only be listed once".to_owned(),
+            ));
+        }
+
+        // Items that are already part of a return that wasn't rejected can't be returned twice
+        let order_item_option = sqlx::query!(
+            r#"
+            SELECT
+            quantity,
+            (
+                SELECT COALESCE(SUM(return_items.quantity), 0)
+                FROM return_items
+                INNER JOIN returns ON returns.return_id = return_items.return_id
+                WHERE returns.order_id = $1
+                AND returns.return_status != 'Rejected'
+                AND return_items.variant_id = $2
+            ) AS "returned_quantity!: i64"
+            FROM order_items
+            WHERE order_id = $1 AND variant_id = $2
+            "#,
+            order_id,
+            item.variant_id,
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .map_err(map_db_error)?;
+
+        let Some(order_item) = order_item_option else {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Variant {} isn't part of this order", item.variant_id),
+            ));
+        };
+
+        if order_item.returned_quantity + item.quantity > order_item.quantity {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Only {} more of variant {} can be returned",
+                    order_item.quantity - order_item.returned_quantity,
+                    item.variant_id
+                ),
+            ));
+        }
+    }
+
+    let local_time_now = Local::now().naive_local();
+    let new_return_id = sqlx::query!(
+        "
+        INSERT INTO returns (order_id, user_id, return_status, comment, requested_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING return_id
+        ",
+        order_id,
+        auth_user.user_id,
+        models::ReturnStatus::Requested,
+        new_return.comment,
+        local_time_now,
+    )
+    .fetch_one(&mut transaction)
+    .await
+    .map_err(map_db_error)?
+    .return_id;
+
+    for item in new_return.items {
+        sqlx::query!(
+            "
+            INSERT INTO return_items (return_id, variant_id, quantity, reason_code)
+            VALUES ($1, $2, $3, $4)
+            ",
+            new_return_id,
+            item.variant_id,
+            item.quantity,
+            item.reason_code,
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(map_db_error)?;
+    }
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok(format!(
+        "Return requested successfully. Return ID: {}",
+        new_return_id
+    ))
+}
+
+pub async fn approve_return(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Extension(payment_provider): Extension<Arc<dyn PaymentProvider>>,
+    auth_user: AuthUser,
+    Path(return_id): Path<i64>,
+    Json(decision): Json<models::ReturnDecision>,
+) -> Result<String, (StatusCode, String)> {
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    let order_id = resolve_return(
+        &mut transaction,
+        return_id,
+        models::ReturnStatus::Approved,
+        &auth_user.user_id,
+        decision.note.as_deref(),
+    )
+    .await?;
+
+    let return_items = sqlx::query_as!(
+        models::NewRefundItem,
+        "SELECT variant_id, quantity FROM return_items WHERE return_id = $1",
+        return_id,
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    let refund = refunds::create_refund(
+        payment_provider.as_ref(),
+        &mut transaction,
+        order_id,
+        RefundRequest {
+            scope: RefundScope::Items(return_items),
+            restock: decision.restock,
+            note: decision.note.as_deref(),
+            return_id: Some(return_id),
+        },
+        Some(&auth_user.user_id),
+    )
+    .await?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok(format!(
+        "Return approved successfully. Refunded: {}",
+        refund.amount
+    ))
+}
+
+pub async fn reject_return(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
+    Path(return_id): Path<i64>,
+    Json(decision): Json<models::ReturnDecision>,
+) -> Result<String, (StatusCode, String)> {
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    resolve_return(
+        &mut transaction,
+        return_id,
+        models::ReturnStatus::Rejected,
+        &auth_user.user_id,
+        decision.note.as_deref(),
+    )
+    .await?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok("Return rejected successfully".to_owned())
+}
+
+// Approves or rejects a return that is still waiting for a decision.
+// Returns the ID of the order the return is for.
+async fn resolve_return(
+    transaction: &mut Transaction<'_, Sqlite>,
+    return_id: i64,
+    return_status: models::ReturnStatus,
+    resolved_by: &str,
+    resolution_note: Option<&str>,
+) -> Result<i64, (StatusCode, String)> {
+    let return_option = sqlx::query!(
+        r#"
+        SELECT order_id, return_status AS "return_status: models::ReturnStatus"
+        FROM returns
+        WHERE return_id = $1
+        "#,
+        return_id,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(order_return) = return_option else {
+        return Err((StatusCode::NOT_FOUND, "Return not found".to_owned()));
+    };
+
+    if order_return.return_status != models::ReturnStatus::Requested {
+        return Err((
+            StatusCode::CONFLICT,
+            format!(
+                "The return has already been {:?}",
+                order_return.return_status
+            ),
+        ));
+    }
+
+    let local_time_now = Local::now().naive_local();
+    let result = sqlx::query!(
+        "
+        UPDATE returns
+        SET return_status = $1,
+            resolved_by = $2,
+            resolved_at = $3,
+            resolution_note = $4
+        WHERE return_id = $5 AND return_status = 'Requested'
+        ",
+        return_status,
+        resolved_by,
+        local_time_now,
+        resolution_note,
+        return_id,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            "The return was changed by someone else, please try again".to_owned(),
+        ));
+    }
+
+    Ok(order_return.order_id)
+}
+
+pub async fn grant_role(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(user_id): Path<String>,
+    Json(role_assignment): Json<models::RoleAssignment>,
+) -> Result<String, (StatusCode, String)> {
+    let role_exists = sqlx::query!(
+        "SELECT role_name FROM roles WHERE role_name = $1",
+        role_assignment.role_name,
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    if role_exists.is_none() {
+        return Err((StatusCode::NOT_FOUND, "Role not found".to_owned()));
+    }
+
+    let user_exists = sqlx::query!("SELECT user_id FROM users WHERE user_id = $1", user_id)
+        .fetch_optional(&db_pool)
+        .await
+        .map_err(map_db_error)?;
+
+    if user_exists.is_none() {
+        return Err((StatusCode::NOT_FOUND, "User not found".to_owned()));
+    }
+
+    sqlx::query!(
+        "INSERT OR IGNORE INTO user_roles (user_id, role_name) VALUES ($1, $2)",
+        user_id,
+        role_assignment.role_name,
+    )
     .execute(&db_pool)
     .await
     .map_err(map_db_error)?;
 
-    sqlx::query!("DELETE FROM cart_items WHERE user_id = $1", authed_user_id,)
-        .execute(&db_pool)
+    Ok("Role granted successfully".to_owned())
+}
+
+pub async fn create_product(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Json(new_product): Json<models::NewProduct>,
+) -> Result<String, (StatusCode, String)> {
+    new_product
+        .validate()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    if !categories::check_category_exists(&db_pool, new_product.category_id)
+        .await
+        .map_err(map_db_error)?
+    {
+        return Err((StatusCode::NOT_FOUND, "Category not found".to_owned()));
+    }
+
+    if let Some(sku) = &new_product.sku {
+        if variants::check_sku_taken(&db_pool, sku, None)
+            .await
+            .map_err(map_db_error)?
+        {
+            return Err((
+                StatusCode::CONFLICT,
+                "Another variant is using the same SKU".to_owned(),
+            ));
+        }
+    }
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    let new_product_id = sqlx::query!(
+        "
+        INSERT INTO products (product_name, product_description, category_id, stock, price, img_path)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING product_id
+        ",
+        new_product.product_name,
+        new_product.product_description,
+        new_product.category_id,
+        new_product.stock,
+        new_product.price,
+        new_product.img_path,
+    )
+    .fetch_one(&mut transaction)
+    .await
+    .map_err(map_db_error)?
+    .product_id;
+
+    // Every product starts out with a single default variant
+    let sku = new_product
+        .sku
+        .unwrap_or_else(|| variants::default_sku(new_product_id));
+    sqlx::query!(
+        "
+        INSERT INTO product_variants (product_id, sku, price, stock, is_default)
+        VALUES ($1, $2, $3, $4, TRUE)
+        ",
+        new_product_id,
+        sku,
+        new_product.price,
+        new_product.stock,
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok(format!(
+        "Product created successfully. Product ID: {}",
+        new_product_id
+    ))
+}
+
+pub async fn create_variant(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(product_id): Path<i64>,
+    Json(new_variant): Json<models::NewVariant>,
+) -> Result<String, (StatusCode, String)> {
+    new_variant
+        .validate()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    let product_exists = sqlx::query!(
+        "SELECT product_id FROM products WHERE product_id = $1",
+        product_id,
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    if product_exists.is_none() {
+        return Err((StatusCode::NOT_FOUND, "Product not found".to_owned()));
+    }
+
+    if variants::check_sku_taken(&db_pool, &new_variant.sku, None)
+        .await
+        .map_err(map_db_error)?
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            "Another variant is using the same SKU".to_owned(),
+        ));
+    }
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    // A product only has one default variant, so the new default replaces the old one
+    if new_variant.is_default {
+        sqlx::query!(
+            "UPDATE product_variants SET is_default = FALSE WHERE product_id = $1",
+            product_id,
+        )
+        .execute(&mut transaction)
         .await
         .map_err(map_db_error)?;
+    }
+
+    let new_variant_id = sqlx::query!(
+        "
+        INSERT INTO product_variants (product_id, sku, options, price, stock, img_path, is_default)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING variant_id
+        ",
+        product_id,
+        new_variant.sku,
+        new_variant.options,
+        new_variant.price,
+        new_variant.stock,
+        new_variant.img_path,
+        new_variant.is_default,
+    )
+    .fetch_one(&mut transaction)
+    .await
+    .map_err(map_db_error)?
+    .variant_id;
+
+    transaction.commit().await.map_err(map_db_error)?;
 
     Ok(format!(
-        "Order created successfully. Order ID: {}",
-        new_order_id
+        "Variant created successfully. Variant ID: {}",
+        new_variant_id
     ))
 }
+
+pub async fn archive_product(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(product_id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    let local_time_now = Local::now().naive_local();
+
+    // Archiving an already archived product keeps the original archive time
+    let result = sqlx::query!(
+        "
+        UPDATE products
+        SET archived_at = COALESCE(archived_at, $1)
+        WHERE product_id = $2
+        ",
+        local_time_now,
+        product_id,
+    )
+    .execute(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "Product not found".to_owned()));
+    }
+
+    Ok("Product archived successfully".to_owned())
+}
+
+pub async fn restore_product(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(product_id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    let result = sqlx::query!(
+        "UPDATE products SET archived_at = NULL WHERE product_id = $1",
+        product_id,
+    )
+    .execute(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "Product not found".to_owned()));
+    }
+
+    Ok("Product restored successfully".to_owned())
+}
+
+pub async fn upload_product_image(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Extension(image_storage): Extension<Arc<dyn ImageStorage>>,
+    Path(product_id): Path<i64>,
+    mut multipart: Multipart,
+) -> Result<Json<models::UploadedImage>, (StatusCode, String)> {
+    let product_exists = sqlx::query!(
+        "SELECT product_id FROM products WHERE product_id = $1",
+        product_id
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    if product_exists.is_none() {
+        return Err((StatusCode::NOT_FOUND, "Product not found".to_owned()));
+    }
+
+    // The image is expected in a multipart field called "image", anything else is ignored
+    let mut image_bytes_option = None;
+    while let Some(field) = multipart.next_field().await.map_err(|error| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Unable to read the upload: {}", error),
+        )
+    })? {
+        if field.name() != Some("image") {
+            continue;
+        }
+
+        match field.content_type() {
+            Some("image/png" | "image/jpeg" | "image/webp") => {}
+            _ => {
+                return Err((
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "Only PNG, JPEG and WebP images are allowed".to_owned(),
+                ))
+            }
+        }
+
+        let image_bytes = field.bytes().await.map_err(|error| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Unable to read the upload: {}", error),
+            )
+        })?;
+        image_bytes_option = Some(image_bytes.to_vec());
+    }
+
+    let Some(image_bytes) = image_bytes_option else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The upload is missing an image field".to_owned(),
+        ));
+    };
+
+    // Decoding and resizing images is slow, so it is kept off the async worker threads
+    let processed_image = tokio::task::spawn_blocking(move || images::process_image(image_bytes))
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to process the image".to_owned(),
+            )
+        })?
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    // The product only points at the image once everything is stored. Files left behind by a
+    // failed upload aren't removed, since they're named after their contents and another
+    // product may be using the same ones. Uploading the image again writes the same files.
+    image_storage
+        .put(&processed_image.file_name, processed_image.bytes)
+        .await
+        .map_err(map_storage_error)?;
+
+    let mut thumbnail_paths = Vec::new();
+    for (thumbnail_name, thumbnail_bytes) in processed_image.thumbnails {
+        image_storage
+            .put(&thumbnail_name, thumbnail_bytes)
+            .await
+            .map_err(map_storage_error)?;
+        thumbnail_paths.push(thumbnail_name);
+    }
+
+    sqlx::query!(
+        "UPDATE products SET img_path = $1 WHERE product_id = $2",
+        processed_image.file_name,
+        product_id,
+    )
+    .execute(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(Json(models::UploadedImage {
+        img_path: processed_image.file_name,
+        thumbnail_paths,
+    }))
+}
+
+fn map_storage_error(error: std::io::Error) -> (StatusCode, String) {
+    eprintln!("Image storage error: {}", error);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Unable to save the image. Please try again later".to_owned(),
+    )
+}
+
+pub async fn create_category(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Json(new_category): Json<models::NewCategory>,
+) -> Result<String, (StatusCode, String)> {
+    new_category
+        .validate()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    let slug = new_category.slug();
+    if categories::check_slug_taken(&db_pool, &slug, None)
+        .await
+        .map_err(map_db_error)?
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            "Another category is using the same slug".to_owned(),
+        ));
+    }
+
+    if let Some(parent_id) = new_category.parent_id {
+        if !categories::check_category_exists(&db_pool, parent_id)
+            .await
+            .map_err(map_db_error)?
+        {
+            return Err((
+                StatusCode::NOT_FOUND,
+                "Parent category not found".to_owned(),
+            ));
+        }
+    }
+
+    let new_category_id = sqlx::query!(
+        "
+        INSERT INTO categories (parent_id, category_name, slug, display_order)
+        VALUES ($1, $2, $3, $4)
+        RETURNING category_id
+        ",
+        new_category.parent_id,
+        new_category.category_name,
+        slug,
+        new_category.display_order,
+    )
+    .fetch_one(&db_pool)
+    .await
+    .map_err(map_db_error)?
+    .category_id;
+
+    Ok(format!(
+        "Category created successfully. Category ID: {}",
+        new_category_id
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::utils::payments::FakePaymentGateway;
+    use crate::utils::testing;
+
+    async fn add_to_cart(db_pool: &Pool<Sqlite>, user_id: &str, variant_id: i64, quantity: i64) {
+        let cart_id = carts::find_or_create_cart(db_pool, &CartOwner::User(user_id.to_owned()))
+            .await
+            .unwrap();
+        sqlx::query!(
+            "
+            INSERT INTO cart_items (cart_id, variant_id, quantity, price_when_added)
+            SELECT $1, variant_id, $2, price FROM product_variants WHERE variant_id = $3
+            ",
+            cart_id,
+            quantity,
+            variant_id,
+        )
+        .execute(db_pool)
+        .await
+        .unwrap();
+    }
+
+    // Places an order for everything in the user's cart, shipped to a new address
+    async fn checkout(
+        db_pool: &Pool<Sqlite>,
+        user_id: &str,
+    ) -> Result<(StatusCode, Json<models::PaymentIntent>), (StatusCode, String)> {
+        let address_id = sqlx::query!(
+            "
+            INSERT INTO addresses (user_id, unit, street, city, postal_code, state_province, country)
+            VALUES ($1, '1', 'Jalan Test', 'Kuala Lumpur', 50000, 'Kuala Lumpur', 'Malaysia')
+            ",
+            user_id,
+        )
+        .execute(db_pool)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+
+        let auth_user = AuthUser {
+            user_id: user_id.to_owned(),
+            roles: vec!["customer".to_owned()],
+            session_id: "testsession".to_owned(),
+            token_id: "testtoken".to_owned(),
+            token_expires_at: Utc::now().naive_utc(),
+        };
+        let checkout = serde_json::from_value(serde_json::json!({
+            "shipping_address_id": address_id,
+            "payment": {
+                "card_number": "4242424242424242",
+                "expiry_month": 12,
+                "expiry_year": 2099,
+                "cvc": "123",
+            },
+        }))
+        .unwrap();
+
+        create_order(
+            Extension(db_pool.clone()),
+            Extension(Arc::new(FakePaymentGateway)),
+            auth_user,
+            Conversion::none(),
+            Json(checkout),
+        )
+        .await
+    }
+
+    async fn order_count(db_pool: &Pool<Sqlite>) -> i64 {
+        sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!: i64" FROM orders"#)
+            .fetch_one(db_pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn orders_for_more_than_is_in_stock_are_refused() {
+        let db_pool = testing::test_db_pool().await;
+        let user_id = testing::create_user(&db_pool, &["customer"]).await;
+        let stock = [
+            testing::variant_stock(&db_pool, 1).await,
+            testing::variant_stock(&db_pool, 2).await,
+        ];
+        add_to_cart(&db_pool, &user_id, 1, 1).await;
+        add_to_cart(&db_pool, &user_id, 2, stock[1] + 1).await;
+
+        let (status, message) = checkout(&db_pool, &user_id).await.unwrap_err();
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(message.contains(&format!(
+            "has {} left but {} were ordered",
+            stock[1],
+            stock[1] + 1
+        )));
+        assert_eq!(order_count(&db_pool).await, 0);
+        assert_eq!(testing::variant_stock(&db_pool, 1).await, stock[0]);
+        assert_eq!(testing::variant_stock(&db_pool, 2).await, stock[1]);
+    }
+
+    #[tokio::test]
+    async fn stock_sold_while_ordering_leaves_everything_as_it_was() {
+        let db_pool = testing::test_db_pool().await;
+        let user_id = testing::create_user(&db_pool, &["customer"]).await;
+        let stock = [
+            testing::variant_stock(&db_pool, 1).await,
+            testing::variant_stock(&db_pool, 2).await,
+        ];
+        add_to_cart(&db_pool, &user_id, 1, 1).await;
+        add_to_cart(&db_pool, &user_id, 2, 1).await;
+
+        // Someone else buys the last of the second variant once the cart has been checked
+        sqlx::query(
+            "
+            CREATE TRIGGER sell_out AFTER INSERT ON orders
+            BEGIN
+                UPDATE product_variants SET stock = 0 WHERE variant_id = 2;
+            END
+            ",
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+
+        let (status, message) = checkout(&db_pool, &user_id).await.unwrap_err();
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(message.contains("sold out while ordering"));
+        assert_eq!(order_count(&db_pool).await, 0);
+        assert_eq!(testing::variant_stock(&db_pool, 1).await, stock[0]);
+        assert_eq!(testing::variant_stock(&db_pool, 2).await, stock[1]);
+    }
+}
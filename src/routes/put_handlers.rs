@@ -0,0 +1,362 @@
+use axum::{extract::Path, http::StatusCode, Extension, Json};
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+
+use crate::routes::map_db_error;
+use crate::utils::auth::AuthUser;
+use crate::utils::carts::{self, CartOwner};
+use crate::utils::categories;
+use crate::utils::currencies;
+use crate::utils::models;
+use crate::utils::money::Currency;
+use crate::utils::variants;
+
+pub async fn update_product(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(product_id): Path<i64>,
+    Json(product): Json<models::NewProduct>,
+) -> Result<String, (StatusCode, String)> {
+    product
+        .validate()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    if !categories::check_category_exists(&db_pool, product.category_id)
+        .await
+        .map_err(map_db_error)?
+    {
+        return Err((StatusCode::NOT_FOUND, "Category not found".to_owned()));
+    }
+
+    let default_variant_option = sqlx::query!(
+        "SELECT variant_id FROM product_variants WHERE product_id = $1 AND is_default",
+        product_id,
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(default_variant) = default_variant_option else {
+        return Err((StatusCode::NOT_FOUND, "Product not found".to_owned()));
+    };
+
+    if let Some(sku) = &product.sku {
+        if variants::check_sku_taken(&db_pool, sku, Some(default_variant.variant_id))
+            .await
+            .map_err(map_db_error)?
+        {
+            return Err((
+                StatusCode::CONFLICT,
+                "Another variant is using the same SKU".to_owned(),
+            ));
+        }
+    }
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    sqlx::query!(
+        "
+        UPDATE products
+        SET product_name = $1,
+            product_description = $2,
+            category_id = $3,
+            img_path = $4
+        WHERE product_id = $5
+        ",
+        product.product_name,
+        product.product_description,
+        product.category_id,
+        product.img_path,
+        product_id,
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    // The price and stock belong to the default variant, the SKU is only changed when given
+    sqlx::query!(
+        "
+        UPDATE product_variants
+        SET price = $1,
+            stock = $2,
+            sku = COALESCE($3, sku)
+        WHERE variant_id = $4
+        ",
+        product.price,
+        product.stock,
+        product.sku,
+        default_variant.variant_id,
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok("Product updated successfully".to_owned())
+}
+
+pub async fn update_category(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(category_id): Path<i64>,
+    Json(category): Json<models::NewCategory>,
+) -> Result<String, (StatusCode, String)> {
+    category
+        .validate()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    if !categories::check_category_exists(&db_pool, category_id)
+        .await
+        .map_err(map_db_error)?
+    {
+        return Err((StatusCode::NOT_FOUND, "Category not found".to_owned()));
+    }
+
+    let slug = category.slug();
+    if categories::check_slug_taken(&db_pool, &slug, Some(category_id))
+        .await
+        .map_err(map_db_error)?
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            "Another category is using the same slug".to_owned(),
+        ));
+    }
+
+    if let Some(parent_id) = category.parent_id {
+        if !categories::check_category_exists(&db_pool, parent_id)
+            .await
+            .map_err(map_db_error)?
+        {
+            return Err((
+                StatusCode::NOT_FOUND,
+                "Parent category not found".to_owned(),
+            ));
+        }
+
+        // Moving a category under itself or one of its own subcategories would make a loop
+        if categories::is_in_subtree(&db_pool, category_id, parent_id)
+            .await
+            .map_err(map_db_error)?
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "A category can't be moved under itself or one of its subcategories".to_owned(),
+            ));
+        }
+    }
+
+    sqlx::query!(
+        "
+        UPDATE categories
+        SET parent_id = $1,
+            category_name = $2,
+            slug = $3,
+            display_order = $4
+        WHERE category_id = $5
+        ",
+        category.parent_id,
+        category.category_name,
+        slug,
+        category.display_order,
+        category_id,
+    )
+    .execute(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok("Category updated successfully".to_owned())
+}
+
+pub async fn update_variant(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    Path(variant_id): Path<i64>,
+    Json(variant): Json<models::NewVariant>,
+) -> Result<String, (StatusCode, String)> {
+    variant
+        .validate()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    let existing_variant_option = sqlx::query!(
+        "SELECT product_id, is_default FROM product_variants WHERE variant_id = $1",
+        variant_id,
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(existing_variant) = existing_variant_option else {
+        return Err((StatusCode::NOT_FOUND, "Variant not found".to_owned()));
+    };
+
+    // There always has to be a default, so it can only be moved by making another variant the default
+    if existing_variant.is_default && !variant.is_default {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Make another variant the default instead".to_owned(),
+        ));
+    }
+
+    if variants::check_sku_taken(&db_pool, &variant.sku, Some(variant_id))
+        .await
+        .map_err(map_db_error)?
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            "Another variant is using the same SKU".to_owned(),
+        ));
+    }
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    if variant.is_default {
+        sqlx::query!(
+            "UPDATE product_variants SET is_default = FALSE WHERE product_id = $1 AND variant_id != $2",
+            existing_variant.product_id,
+            variant_id,
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(map_db_error)?;
+    }
+
+    sqlx::query!(
+        "
+        UPDATE product_variants
+        SET sku = $1,
+            options = $2,
+            price = $3,
+            stock = $4,
+            img_path = $5,
+            is_default = $6
+        WHERE variant_id = $7
+        ",
+        variant.sku,
+        variant.options,
+        variant.price,
+        variant.stock,
+        variant.img_path,
+        variant.is_default,
+        variant_id,
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok("Variant updated successfully".to_owned())
+}
+
+// Sets the quantity of a variant in the cart, adding it if it isn't there yet.
+// The shopper has seen the current price by now, so it is no longer flagged as changed.
+pub async fn update_cart_item(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    cart_owner: CartOwner,
+    Path(variant_id): Path<i64>,
+    Json(cart_quantity): Json<models::CartQuantity>,
+) -> Result<String, (StatusCode, String)> {
+    let variant_option = carts::find_variant(&db_pool, None, Some(variant_id))
+        .await
+        .map_err(map_db_error)?;
+
+    let Some(variant) = variant_option else {
+        return Err((StatusCode::NOT_FOUND, "Product not found".to_owned()));
+    };
+
+    carts::check_quantity(cart_quantity.quantity, variant.stock)?;
+
+    let cart_id = carts::find_or_create_cart(&db_pool, &cart_owner).await?;
+
+    sqlx::query!(
+        "
+        INSERT INTO cart_items (cart_id, variant_id, quantity, price_when_added)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (cart_id, variant_id) DO UPDATE
+        SET quantity = excluded.quantity,
+            price_when_added = excluded.price_when_added
+        ",
+        cart_id,
+        variant.variant_id,
+        cart_quantity.quantity,
+        variant.price,
+    )
+    .execute(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok("Cart item updated successfully".to_owned())
+}
+
+// Prices can only be shown in currencies that have a rate, so picking one without a rate is
+// rejected. None goes back to the store's currency.
+pub async fn update_preferences(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
+    Json(preferences): Json<models::Preferences>,
+) -> Result<String, (StatusCode, String)> {
+    if let Some(currency) = preferences.currency {
+        if currencies::find_rate(&db_pool, currency).await?.is_none() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Prices aren't available in {}", currency),
+            ));
+        }
+    }
+
+    sqlx::query!(
+        "UPDATE users SET preferred_currency = $1 WHERE user_id = $2",
+        preferences.currency,
+        auth_user.user_id,
+    )
+    .execute(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok("Preferences updated successfully".to_owned())
+}
+
+// Adds the currency if it isn't offered yet. Orders already placed keep the rate they used.
+pub async fn set_exchange_rate(
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
+    Path(currency): Path<String>,
+    Json(exchange_rate): Json<models::NewExchangeRate>,
+) -> Result<String, (StatusCode, String)> {
+    let currency = currency
+        .parse::<Currency>()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+    if currency == Currency::base() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{} is the store's currency", currency),
+        ));
+    }
+
+    exchange_rate
+        .validate(currency)
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    let updated_at = Utc::now().naive_utc();
+    sqlx::query!(
+        "
+        INSERT INTO exchange_rates (currency, rate, updated_by, updated_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (currency) DO UPDATE
+        SET rate = excluded.rate,
+            updated_by = excluded.updated_by,
+            updated_at = excluded.updated_at
+        ",
+        currency,
+        exchange_rate.rate,
+        auth_user.user_id,
+        updated_at,
+    )
+    .execute(&db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(format!(
+        "Exchange rate for {} updated successfully",
+        currency
+    ))
+}
@@ -1,32 +1,177 @@
-use axum::http::StatusCode;
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequestParts, TypedHeader},
+    headers::{authorization::Bearer, Authorization},
+    http::{request::Parts, StatusCode},
+};
+use chrono::naive::NaiveDateTime;
 use sqlx::{Pool, Sqlite};
 
-use crate::{routes::ActiveUsers, utils::jwt};
+use crate::utils::{
+    jwt, models,
+    sessions::{IssuedSession, SessionStore},
+};
 
+// Tokens carry the user's identity, so only the revocation list needs to be checked
 pub async fn authenticate_user(
     token: String,
-    active_users: ActiveUsers,
-) -> Result<String, (StatusCode, String)> {
-    jwt::is_valid(&token)?;
+    session_store: SessionStore,
+) -> Result<jwt::Claims, (StatusCode, String)> {
+    let claims = jwt::is_valid(&token)?;
 
-    match active_users.lock().await.get(&token) {
-        Some(user_id) => Ok(user_id.to_string()),
-        None => Err((
+    let is_revoked = session_store.is_revoked(&claims.jti).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unable to check if the session token has been revoked".to_owned(),
+        )
+    })?;
+
+    match is_revoked {
+        true => Err((
             StatusCode::UNAUTHORIZED,
-            "A user was not found for the given session token. Please login again".to_owned(),
+            "The session for the given token has ended. Please login again".to_owned(),
         )),
+        false => Ok(claims),
+    }
+}
+
+// A user that has been authenticated with the bearer token in the Authorization header.
+// Taking this as a handler argument rejects the request if the user isn't logged in.
+#[derive(Debug)]
+pub struct AuthUser {
+    pub user_id: String,
+    pub roles: Vec<String>,
+    pub session_id: String,
+    pub token_id: String,
+    pub token_expires_at: NaiveDateTime,
+}
+
+// For endpoints that can be used both anonymously and logged in.
+// A missing token gives None, but an invalid token is still rejected.
+#[derive(Debug)]
+pub struct MaybeAuthUser(pub Option<AuthUser>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let TypedHeader(Authorization(bearer)) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+                .await
+                .map_err(|_| {
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        "A bearer token is required. Please login first".to_owned(),
+                    )
+                })?;
+
+        let Extension(session_store) = Extension::<SessionStore>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unable to access the session store".to_owned(),
+                )
+            })?;
+
+        let claims = authenticate_user(bearer.token().to_owned(), session_store).await?;
+
+        Ok(AuthUser {
+            user_id: claims.sub,
+            roles: claims.roles,
+            session_id: claims.sid,
+            token_id: claims.jti,
+            token_expires_at: NaiveDateTime::from_timestamp_opt(claims.exp as i64, 0)
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for MaybeAuthUser
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if !parts.headers.contains_key(http::header::AUTHORIZATION) {
+            return Ok(MaybeAuthUser(None));
+        }
+
+        let auth_user = AuthUser::from_request_parts(parts, state).await?;
+
+        Ok(MaybeAuthUser(Some(auth_user)))
     }
 }
 
 pub async fn remove_active_user(
-    token: String,
-    active_users: ActiveUsers,
+    auth_user: &AuthUser,
+    session_store: SessionStore,
 ) -> Result<(), (StatusCode, String)> {
-    match active_users.lock().await.remove(&token) {
-        Some(_) => Ok(()),
-        None => Err((
-            StatusCode::UNAUTHORIZED,
-            "A user was not found for the given session token.".to_owned(),
+    session_store
+        .revoke(&auth_user.token_id, auth_user.token_expires_at)
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to revoke the given session token".to_owned(),
+            )
+        })?;
+
+    session_store
+        .remove(&auth_user.session_id)
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to remove the session for the given token".to_owned(),
+            )
+        })?;
+
+    Ok(())
+}
+
+// Creates a short-lived access token to go along with the session's refresh token.
+// Roles are looked up every time so changes take effect on the next refresh.
+pub async fn create_token_pair(
+    db_pool: &Pool<Sqlite>,
+    session: IssuedSession,
+) -> Result<models::TokenPair, (StatusCode, String)> {
+    let roles = get_user_roles(db_pool, &session.user_id).await?;
+    let access_token = jwt::create_jwt(&session.user_id, &session.session_id, roles)?;
+
+    Ok(models::TokenPair {
+        access_token,
+        refresh_token: session.refresh_token,
+        token_type: "Bearer".to_owned(),
+        expires_in: jwt::ACCESS_TOKEN_LIFETIME_MINUTES * 60,
+    })
+}
+
+pub async fn get_user_roles(
+    db_pool: &Pool<Sqlite>,
+    user_id: &String,
+) -> Result<Vec<String>, (StatusCode, String)> {
+    let user_roles_result = sqlx::query!(
+        "SELECT role_name FROM user_roles WHERE user_id = $1",
+        user_id
+    )
+    .fetch_all(db_pool)
+    .await;
+
+    match user_roles_result {
+        Ok(user_roles) => Ok(user_roles
+            .into_iter()
+            .map(|user_role| user_role.role_name)
+            .collect()),
+        Err(_) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unable to look up the user's roles".to_owned(),
         )),
     }
 }
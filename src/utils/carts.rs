@@ -0,0 +1,541 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderMap, StatusCode},
+};
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+use std::{env, time::Duration};
+use uuid::Uuid;
+
+use crate::routes::map_db_error;
+use crate::utils::auth::MaybeAuthUser;
+use crate::utils::currencies::Conversion;
+use crate::utils::models::{self, Cart, CartWarning, CartWarningKind, DisplayCartItem, GuestCart};
+use crate::utils::money::{Currency, Money};
+use crate::utils::pricing::{self, LinePrice};
+use crate::utils::sessions::hash_token;
+
+// Guests identify their cart with the token in this header
+pub const CART_TOKEN_HEADER: &str = "x-cart-token";
+
+// How long a guest cart is kept after it was last changed
+pub const GUEST_CART_LIFETIME_DAYS: i64 = 30;
+
+// How often the background task clears out expired guest carts
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Whose cart a request is for. Logged in users always use their own cart,
+// otherwise it's the guest cart given by the X-Cart-Token header, if there is one.
+pub enum CartOwner {
+    User(String),
+    Guest(String),
+    Anonymous,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CartOwner
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let MaybeAuthUser(auth_user) = MaybeAuthUser::from_request_parts(parts, state).await?;
+        if let Some(auth_user) = auth_user {
+            return Ok(CartOwner::User(auth_user.user_id));
+        }
+
+        Ok(match cart_token(&parts.headers) {
+            Some(cart_token) => CartOwner::Guest(cart_token),
+            None => CartOwner::Anonymous,
+        })
+    }
+}
+
+// What to do with a variant that is in both the guest's cart and the user's cart when they login.
+// Set with the CART_MERGE_POLICY env var, which defaults to adding the quantities together.
+// Added together they're capped at the stock left, but never below what either cart had.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergePolicy {
+    Sum,
+    KeepUser,
+    KeepGuest,
+}
+
+impl MergePolicy {
+    pub fn from_env() -> MergePolicy {
+        match env::var("CART_MERGE_POLICY").as_deref() {
+            Ok("keep_user") => MergePolicy::KeepUser,
+            Ok("keep_guest") => MergePolicy::KeepGuest,
+            _ => MergePolicy::Sum,
+        }
+    }
+}
+
+pub fn cart_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CART_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+pub async fn start_guest_cart(db_pool: &Pool<Sqlite>) -> Result<GuestCart, sqlx::Error> {
+    let cart_id = Uuid::new_v4().simple().to_string();
+    // Two random UUIDs give a token that is practically impossible to guess
+    let cart_token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let token_hash = hash_token(&cart_token);
+    let now = Utc::now().naive_utc();
+    let expires_at = now + chrono::Duration::days(GUEST_CART_LIFETIME_DAYS);
+
+    sqlx::query!(
+        "
+        INSERT INTO carts (cart_id, token_hash, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        ",
+        cart_id,
+        token_hash,
+        now,
+        expires_at,
+    )
+    .execute(db_pool)
+    .await?;
+
+    Ok(GuestCart {
+        cart_token,
+        expires_at,
+    })
+}
+
+// The ID of the owner's cart, if they have one
+pub async fn find_cart(
+    db_pool: &Pool<Sqlite>,
+    cart_owner: &CartOwner,
+) -> Result<Option<String>, sqlx::Error> {
+    match cart_owner {
+        CartOwner::User(user_id) => {
+            sqlx::query_scalar!("SELECT cart_id FROM carts WHERE user_id = $1", user_id)
+                .fetch_optional(db_pool)
+                .await
+        }
+        CartOwner::Guest(cart_token) => {
+            let token_hash = hash_token(cart_token);
+            let now = Utc::now().naive_utc();
+
+            sqlx::query_scalar!(
+                "SELECT cart_id FROM carts WHERE token_hash = $1 AND expires_at > $2",
+                token_hash,
+                now,
+            )
+            .fetch_optional(db_pool)
+            .await
+        }
+        CartOwner::Anonymous => Ok(None),
+    }
+}
+
+// The ID of the cart to add items to. Users are given a cart the first time they need one,
+// while a guest cart has its expiry pushed back each time it's changed.
+pub async fn find_or_create_cart(
+    db_pool: &Pool<Sqlite>,
+    cart_owner: &CartOwner,
+) -> Result<String, (StatusCode, String)> {
+    let now = Utc::now().naive_utc();
+
+    let cart_id = match cart_owner {
+        CartOwner::User(user_id) => {
+            let new_cart_id = Uuid::new_v4().simple().to_string();
+
+            // Updating on conflict lets the existing cart be returned
+            let cart_id = sqlx::query_scalar!(
+                r#"
+                INSERT INTO carts (cart_id, user_id, created_at)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (user_id) DO UPDATE SET user_id = excluded.user_id
+                RETURNING cart_id AS "cart_id!"
+                "#,
+                new_cart_id,
+                user_id,
+                now,
+            )
+            .fetch_one(db_pool)
+            .await
+            .map_err(map_db_error)?;
+
+            Some(cart_id)
+        }
+        CartOwner::Guest(cart_token) => {
+            let token_hash = hash_token(cart_token);
+            let expires_at = now + chrono::Duration::days(GUEST_CART_LIFETIME_DAYS);
+
+            sqlx::query_scalar!(
+                r#"
+                UPDATE carts SET expires_at = $1
+                WHERE token_hash = $2 AND expires_at > $3
+                RETURNING cart_id AS "cart_id!"
+                "#,
+                expires_at,
+                token_hash,
+                now,
+            )
+            .fetch_optional(db_pool)
+            .await
+            .map_err(map_db_error)?
+        }
+        CartOwner::Anonymous => {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                "Please login or start a guest cart first".to_owned(),
+            ))
+        }
+    };
+
+    cart_id.ok_or((
+        StatusCode::NOT_FOUND,
+        "The guest cart doesn't exist or has expired".to_owned(),
+    ))
+}
+
+// Moves the items in the guest's cart into the user's cart, then removes the guest cart.
+// A missing or expired guest cart is ignored, there's just nothing to merge.
+pub async fn merge_guest_cart(
+    db_pool: &Pool<Sqlite>,
+    cart_token: &str,
+    user_id: &str,
+    merge_policy: MergePolicy,
+) -> Result<(), (StatusCode, String)> {
+    let guest_cart = CartOwner::Guest(cart_token.to_owned());
+    let Some(guest_cart_id) = find_cart(db_pool, &guest_cart)
+        .await
+        .map_err(map_db_error)?
+    else {
+        return Ok(());
+    };
+
+    let user_cart_id = find_or_create_cart(db_pool, &CartOwner::User(user_id.to_owned())).await?;
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    // The WHERE clause is needed for SQLite to parse the upsert after a SELECT
+    match merge_policy {
+        MergePolicy::Sum => {
+            sqlx::query!(
+                "
+            INSERT INTO cart_items (cart_id, variant_id, quantity, price_when_added)
+            SELECT $1, variant_id, quantity, price_when_added FROM cart_items WHERE cart_id = $2
+            ON CONFLICT (cart_id, variant_id) DO UPDATE SET quantity = MAX(
+                quantity,
+                excluded.quantity,
+                MIN(
+                    quantity + excluded.quantity,
+                    (SELECT stock FROM product_variants WHERE variant_id = excluded.variant_id)
+                )
+            )
+            ",
+                user_cart_id,
+                guest_cart_id,
+            )
+            .execute(&mut transaction)
+            .await
+        }
+        MergePolicy::KeepUser => {
+            sqlx::query!(
+                "
+            INSERT INTO cart_items (cart_id, variant_id, quantity, price_when_added)
+            SELECT $1, variant_id, quantity, price_when_added FROM cart_items WHERE cart_id = $2
+            ON CONFLICT (cart_id, variant_id) DO NOTHING
+            ",
+                user_cart_id,
+                guest_cart_id,
+            )
+            .execute(&mut transaction)
+            .await
+        }
+        MergePolicy::KeepGuest => {
+            sqlx::query!(
+                "
+            INSERT INTO cart_items (cart_id, variant_id, quantity, price_when_added)
+            SELECT $1, variant_id, quantity, price_when_added FROM cart_items WHERE cart_id = $2
+            ON CONFLICT (cart_id, variant_id) DO UPDATE
+            SET quantity = excluded.quantity,
+                price_when_added = excluded.price_when_added
+            ",
+                user_cart_id,
+                guest_cart_id,
+            )
+            .execute(&mut transaction)
+            .await
+        }
+    }
+    .map_err(map_db_error)?;
+
+    sqlx::query!("DELETE FROM carts WHERE cart_id = $1", guest_cart_id)
+        .execute(&mut transaction)
+        .await
+        .map_err(map_db_error)?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok(())
+}
+
+pub async fn prune_expired(db_pool: &Pool<Sqlite>) -> Result<u64, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+
+    let result = sqlx::query!("DELETE FROM carts WHERE expires_at <= $1", now)
+        .execute(db_pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+// Guest carts that are never used again would otherwise be kept forever
+pub fn spawn_pruning_task(db_pool: Pool<Sqlite>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(error) = prune_expired(&db_pool).await {
+                eprintln!("Unable to prune expired guest carts: {}", error);
+            }
+        }
+    });
+}
+
+// A variant that can be put in a cart, along with how many are left
+pub struct CartVariant {
+    pub variant_id: i64,
+    pub price: Money,
+    pub stock: i64,
+}
+
+// Finds the variant by its ID, or the product's default variant when no ID is given.
+// Variants of archived products can no longer be bought, so they aren't found.
+pub async fn find_variant(
+    db_pool: &Pool<Sqlite>,
+    product_id: Option<i64>,
+    variant_id: Option<i64>,
+) -> Result<Option<CartVariant>, sqlx::Error> {
+    if product_id.is_none() && variant_id.is_none() {
+        return Ok(None);
+    }
+
+    sqlx::query_as!(
+        CartVariant,
+        r#"
+        SELECT
+        product_variants.variant_id,
+        product_variants.price AS "price: Money",
+        product_variants.stock
+        FROM product_variants
+        INNER JOIN products ON products.product_id = product_variants.product_id
+        WHERE products.archived_at IS NULL
+        AND ($1 IS NULL OR products.product_id = $1)
+        AND (product_variants.variant_id = $2 OR ($2 IS NULL AND product_variants.is_default))
+        "#,
+        product_id,
+        variant_id,
+    )
+    .fetch_optional(db_pool)
+    .await
+}
+
+pub fn check_quantity(quantity: i64, stock: i64) -> Result<(), (StatusCode, String)> {
+    if quantity < 1 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The quantity must be at least 1".to_owned(),
+        ));
+    }
+    if quantity > stock {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Only {} left in stock", stock.max(0)),
+        ));
+    }
+
+    Ok(())
+}
+
+// Everything in the cart, with the totals worked out the same way an order would be.
+// Each amount is worked out in the store's currency and then converted, and the total is
+// what the converted amounts add up to so the cart always adds up for the shopper.
+pub async fn load_cart(
+    db_pool: &Pool<Sqlite>,
+    cart_id: &str,
+    conversion: &Conversion,
+) -> Result<Cart, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+        cart_items.variant_id,
+        products.product_id,
+        products.product_name,
+        product_variants.sku,
+        product_variants.options AS "options: models::VariantOptions",
+        COALESCE(product_variants.img_path, products.img_path) AS "img_path!: String",
+        product_variants.price AS "price: Money",
+        product_variants.stock,
+        products.archived_at,
+        cart_items.quantity,
+        cart_items.price_when_added AS "price_when_added: Money"
+        FROM cart_items
+        INNER JOIN product_variants ON product_variants.variant_id = cart_items.variant_id
+        INNER JOIN products ON products.product_id = product_variants.product_id
+        WHERE cart_items.cart_id = $1
+        ORDER BY products.product_name, product_variants.sku
+        "#,
+        cart_id,
+    )
+    .fetch_all(db_pool)
+    .await?;
+
+    let tax_rate = pricing::tax_rate();
+    let mut subtotal = Money::zero(Currency::base());
+    let mut discount_total = Money::zero(Currency::base());
+    let mut cart = Cart::empty(conversion.currency, conversion.rate);
+
+    for row in rows {
+        // There aren't any discounts to apply yet
+        let line_price = LinePrice::new(
+            row.price,
+            row.quantity,
+            Money::zero(row.price.currency()),
+            tax_rate,
+        );
+
+        let warning = match row.price_when_added {
+            _ if row.archived_at.is_some() => Some((
+                CartWarningKind::Discontinued,
+                format!("{} is no longer sold", row.product_name),
+            )),
+            _ if row.stock <= 0 => Some((
+                CartWarningKind::OutOfStock,
+                format!("{} is out of stock", row.product_name),
+            )),
+            _ if row.stock < row.quantity => Some((
+                CartWarningKind::NotEnoughStock,
+                format!("Only {} of {} are left", row.stock, row.product_name),
+            )),
+            Some(price_when_added) if price_when_added != row.price => Some((
+                CartWarningKind::PriceChanged,
+                format!(
+                    "The price of {} changed from {} to {}",
+                    row.product_name,
+                    conversion.convert(price_when_added),
+                    conversion.convert(row.price)
+                ),
+            )),
+            _ => None,
+        };
+        if let Some((kind, message)) = warning {
+            cart.warnings.push(CartWarning {
+                variant_id: row.variant_id,
+                kind,
+                message,
+            });
+        }
+
+        // The converted price times the quantity, so each line adds up for the shopper too
+        let unit_price = conversion.convert(row.price);
+        let line_subtotal = unit_price * row.quantity;
+        subtotal += line_price.line_subtotal;
+        discount_total += line_price.discount;
+        cart.subtotal += line_subtotal;
+        cart.discount_total += conversion.convert(line_price.discount);
+        cart.estimated_tax += conversion.convert(line_price.tax);
+        cart.items.push(DisplayCartItem {
+            variant_id: row.variant_id,
+            product_id: row.product_id,
+            product_name: row.product_name,
+            sku: row.sku,
+            options: row.options,
+            img_path: row.img_path,
+            price: unit_price,
+            quantity: row.quantity,
+            line_total: line_subtotal,
+        });
+    }
+
+    // Free shipping depends on the subtotal in the store's currency
+    cart.shipping_estimate =
+        conversion.convert(pricing::shipping_estimate(subtotal - discount_total));
+    cart.total = cart.subtotal - cart.discount_total + cart.estimated_tax + cart.shipping_estimate;
+
+    Ok(cart)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    async fn put_in_cart(db_pool: &Pool<Sqlite>, cart_owner: &CartOwner, quantity: i64) {
+        let cart_id = find_or_create_cart(db_pool, cart_owner).await.unwrap();
+        sqlx::query!(
+            "
+            INSERT INTO cart_items (cart_id, variant_id, quantity, price_when_added)
+            SELECT $1, variant_id, $2, price FROM product_variants WHERE variant_id = 1
+            ",
+            cart_id,
+            quantity,
+        )
+        .execute(db_pool)
+        .await
+        .unwrap();
+    }
+
+    async fn quantity_in_cart(db_pool: &Pool<Sqlite>, user_id: &str) -> i64 {
+        sqlx::query_scalar!(
+            "
+            SELECT quantity FROM cart_items
+            INNER JOIN carts ON carts.cart_id = cart_items.cart_id
+            WHERE carts.user_id = $1 AND cart_items.variant_id = 1
+            ",
+            user_id,
+        )
+        .fetch_one(db_pool)
+        .await
+        .unwrap()
+    }
+
+    // The user had `in_user_cart` and the guest `in_guest_cart` of a variant with `stock` left
+    async fn merged_quantity(in_user_cart: i64, in_guest_cart: i64, stock: i64) -> i64 {
+        let db_pool = testing::test_db_pool().await;
+        sqlx::query!(
+            "UPDATE product_variants SET stock = $1 WHERE variant_id = 1",
+            stock
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+
+        let user_id = testing::create_user(&db_pool, &["customer"]).await;
+        let guest_cart = start_guest_cart(&db_pool).await.unwrap();
+        put_in_cart(&db_pool, &CartOwner::User(user_id.clone()), in_user_cart).await;
+        put_in_cart(
+            &db_pool,
+            &CartOwner::Guest(guest_cart.cart_token.clone()),
+            in_guest_cart,
+        )
+        .await;
+
+        merge_guest_cart(&db_pool, &guest_cart.cart_token, &user_id, MergePolicy::Sum)
+            .await
+            .unwrap();
+
+        quantity_in_cart(&db_pool, &user_id).await
+    }
+
+    #[tokio::test]
+    async fn summed_quantities_are_capped_at_the_stock_left() {
+        assert_eq!(merged_quantity(2, 3, 10).await, 5);
+        assert_eq!(merged_quantity(2, 3, 4).await, 4);
+        // Neither cart loses what it had, even if there isn't that much left
+        assert_eq!(merged_quantity(2, 3, 1).await, 3);
+        assert_eq!(merged_quantity(6, 3, 4).await, 6);
+    }
+}
@@ -0,0 +1,87 @@
+use sqlx::{Pool, Sqlite};
+
+use crate::utils::models::{Category, CategoryTree};
+
+pub async fn check_category_exists(
+    db_pool: &Pool<Sqlite>,
+    category_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let category = sqlx::query!(
+        "SELECT category_id FROM categories WHERE category_id = $1",
+        category_id,
+    )
+    .fetch_optional(db_pool)
+    .await?;
+
+    Ok(category.is_some())
+}
+
+// Checks whether another category is already using the slug
+pub async fn check_slug_taken(
+    db_pool: &Pool<Sqlite>,
+    slug: &String,
+    category_id: Option<i64>,
+) -> Result<bool, sqlx::Error> {
+    let category = sqlx::query!(
+        "SELECT category_id FROM categories WHERE slug = $1 AND category_id IS NOT $2",
+        slug,
+        category_id,
+    )
+    .fetch_optional(db_pool)
+    .await?;
+
+    Ok(category.is_some())
+}
+
+// Whether descendant_id is the category itself or anywhere below it.
+// Used to stop a category being moved underneath itself.
+pub async fn is_in_subtree(
+    db_pool: &Pool<Sqlite>,
+    category_id: i64,
+    descendant_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let descendant = sqlx::query!(
+        r#"
+        WITH RECURSIVE subtree(category_id) AS (
+            SELECT $1
+            UNION
+            SELECT categories.category_id FROM categories
+            INNER JOIN subtree ON categories.parent_id = subtree.category_id
+        )
+        SELECT category_id AS "category_id: i64" FROM subtree WHERE category_id = $2
+        "#,
+        category_id,
+        descendant_id,
+    )
+    .fetch_optional(db_pool)
+    .await?;
+
+    Ok(descendant.is_some())
+}
+
+// Nests the categories under their parents, keeping the order they were given in
+pub fn build_tree(categories: Vec<Category>) -> Vec<CategoryTree> {
+    let (roots, mut rest): (Vec<Category>, Vec<Category>) = categories
+        .into_iter()
+        .partition(|category| category.parent_id.is_none());
+
+    roots
+        .into_iter()
+        .map(|category| attach_children(category, &mut rest))
+        .collect()
+}
+
+fn attach_children(category: Category, rest: &mut Vec<Category>) -> CategoryTree {
+    let (children, remaining): (Vec<Category>, Vec<Category>) = std::mem::take(rest)
+        .into_iter()
+        .partition(|child| child.parent_id == Some(category.category_id));
+    *rest = remaining;
+
+    CategoryTree {
+        category,
+        children: children
+            .into_iter()
+            .map(|child| attach_children(child, rest))
+            .collect(),
+    }
+}
@@ -0,0 +1,168 @@
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequestParts, Query},
+    http::{request::Parts, HeaderMap, StatusCode},
+};
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+
+use crate::routes::map_db_error;
+use crate::utils::auth::MaybeAuthUser;
+use crate::utils::models::ExchangeRate;
+use crate::utils::money::{Currency, Money};
+
+// Shoppers can ask for prices in another currency with this header, e.g. X-Currency: USD
+pub const CURRENCY_HEADER: &str = "x-currency";
+
+#[derive(Debug, Deserialize)]
+struct CurrencyQuery {
+    currency: Option<String>,
+}
+
+// The currency to show prices in and how much of it 1 of the store's currency is worth.
+// Taken from the ?currency= query parameter, then the X-Currency header, then the logged in
+// user's preferred currency, otherwise prices are shown in the store's currency.
+#[derive(Debug, Clone, Copy)]
+pub struct Conversion {
+    pub currency: Currency,
+    pub rate: f64,
+}
+
+impl Conversion {
+    pub fn none() -> Conversion {
+        Conversion {
+            currency: Currency::base(),
+            rate: 1.0,
+        }
+    }
+
+    // Amounts in another currency are left as they are
+    pub fn convert(&self, amount: Money) -> Money {
+        if amount.currency() != Currency::base() || self.currency == Currency::base() {
+            return amount;
+        }
+
+        amount.convert(self.currency, self.rate)
+    }
+
+    // The lowest price in the store's currency that's shown as at least the given amount
+    pub fn lowest_price_shown_as(&self, amount: Money) -> Money {
+        match self.currency == Currency::base() {
+            true => amount,
+            false => amount.lowest_converting_to_at_least(Currency::base(), self.rate),
+        }
+    }
+
+    // The highest price in the store's currency that's shown as at most the given amount
+    pub fn highest_price_shown_as(&self, amount: Money) -> Money {
+        match self.currency == Currency::base() {
+            true => amount,
+            false => amount.highest_converting_to_at_most(Currency::base(), self.rate),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Conversion
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(db_pool) = Extension::<Pool<Sqlite>>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unable to access the database".to_owned(),
+                )
+            })?;
+
+        let Query(query) = Query::<CurrencyQuery>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Invalid query parameters".to_owned(),
+                )
+            })?;
+
+        let requested = query
+            .currency
+            .or_else(|| requested_currency(&parts.headers));
+        if let Some(code) = requested {
+            let currency = code
+                .parse::<Currency>()
+                .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+            return match find_rate(&db_pool, currency).await? {
+                Some(rate) => Ok(Conversion { currency, rate }),
+                None => Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Prices aren't available in {}", currency),
+                )),
+            };
+        }
+
+        let MaybeAuthUser(auth_user) = MaybeAuthUser::from_request_parts(parts, state).await?;
+        let Some(auth_user) = auth_user else {
+            return Ok(Conversion::none());
+        };
+
+        let preferred_currency = sqlx::query_scalar!(
+            r#"SELECT preferred_currency AS "preferred_currency: Currency" FROM users WHERE user_id = $1"#,
+            auth_user.user_id,
+        )
+        .fetch_optional(&db_pool)
+        .await
+        .map_err(map_db_error)?
+        .flatten();
+
+        // The user's currency may have stopped being offered since they picked it
+        let Some(currency) = preferred_currency else {
+            return Ok(Conversion::none());
+        };
+        Ok(match find_rate(&db_pool, currency).await? {
+            Some(rate) => Conversion { currency, rate },
+            None => Conversion::none(),
+        })
+    }
+}
+
+fn requested_currency(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CURRENCY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+// The store's own currency is always worth 1, other currencies need a rate set by an admin
+pub async fn find_rate(
+    db_pool: &Pool<Sqlite>,
+    currency: Currency,
+) -> Result<Option<f64>, (StatusCode, String)> {
+    if currency == Currency::base() {
+        return Ok(Some(1.0));
+    }
+
+    let code = currency.code();
+    sqlx::query_scalar!("SELECT rate FROM exchange_rates WHERE currency = $1", code)
+        .fetch_optional(db_pool)
+        .await
+        .map_err(map_db_error)
+}
+
+pub async fn get_exchange_rates(db_pool: &Pool<Sqlite>) -> Result<Vec<ExchangeRate>, sqlx::Error> {
+    sqlx::query_as!(
+        ExchangeRate,
+        r#"
+        SELECT currency AS "currency: Currency", rate, updated_at
+        FROM exchange_rates
+        ORDER BY currency
+        "#,
+    )
+    .fetch_all(db_pool)
+    .await
+}
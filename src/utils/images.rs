@@ -0,0 +1,107 @@
+use axum::async_trait;
+use image::{imageops::FilterType, ImageFormat};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+// Largest image that can be uploaded, in bytes
+pub const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+
+// Widths of the smaller copies made of every uploaded image
+const THUMBNAIL_WIDTHS: [u32; 2] = [150, 400];
+
+// Somewhere to keep uploaded images. Implement this to store them somewhere other than
+// the local disk, such as an object store, and hand it to create_router instead.
+#[async_trait]
+pub trait ImageStorage: Send + Sync {
+    async fn put(&self, file_name: &str, bytes: Vec<u8>) -> Result<(), std::io::Error>;
+}
+
+// Stores images in a directory on the local disk
+pub struct LocalImageStorage {
+    directory: PathBuf,
+}
+
+impl LocalImageStorage {
+    // Uses IMAGE_STORAGE_DIR if it is set, otherwise the directory the sample product images are in.
+    // Product images are only served from this directory, so copy those over when changing it.
+    pub fn from_env() -> LocalImageStorage {
+        let directory =
+            env::var("IMAGE_STORAGE_DIR").unwrap_or_else(|_| "assets/images/products".to_owned());
+
+        LocalImageStorage {
+            directory: PathBuf::from(directory),
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+}
+
+#[async_trait]
+impl ImageStorage for LocalImageStorage {
+    async fn put(&self, file_name: &str, bytes: Vec<u8>) -> Result<(), std::io::Error> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        tokio::fs::write(self.directory.join(file_name), bytes).await
+    }
+}
+
+// An uploaded image and its thumbnails, ready to be stored
+pub struct ProcessedImage {
+    pub file_name: String,
+    pub bytes: Vec<u8>,
+    pub thumbnails: Vec<(String, Vec<u8>)>,
+}
+
+// Checks that the bytes are an image we accept, then names it after a hash of its
+// contents so the same image is only ever stored once and can be cached forever
+pub fn process_image(bytes: Vec<u8>) -> Result<ProcessedImage, String> {
+    if bytes.len() > MAX_IMAGE_BYTES {
+        return Err(format!(
+            "The image can't be larger than {} MB",
+            MAX_IMAGE_BYTES / 1024 / 1024
+        ));
+    }
+
+    // The format is worked out from the contents rather than trusting the file name
+    let format = image::guess_format(&bytes)
+        .map_err(|_| "The file is not an image we recognise".to_owned())?;
+    let extension = match format {
+        ImageFormat::Png => "png",
+        ImageFormat::Jpeg => "jpg",
+        ImageFormat::WebP => "webp",
+        _ => return Err("Only PNG, JPEG and WebP images are allowed".to_owned()),
+    };
+
+    let decoded_image = image::load_from_memory_with_format(&bytes, format)
+        .map_err(|_| "The image could not be read, it may be corrupted".to_owned())?;
+
+    let hash = format!("{:x}", Sha256::digest(&bytes));
+    let file_stem = &hash[..32];
+
+    let mut thumbnails = Vec::new();
+    for width in THUMBNAIL_WIDTHS {
+        // Small images are left as they are rather than being scaled up
+        let thumbnail = match decoded_image.width() > width {
+            true => decoded_image.resize(width, u32::MAX, FilterType::Lanczos3),
+            false => decoded_image.clone(),
+        };
+
+        let mut thumbnail_bytes = Vec::new();
+        thumbnail
+            .write_to(&mut Cursor::new(&mut thumbnail_bytes), ImageFormat::Png)
+            .map_err(|_| "Unable to create a thumbnail for the image".to_owned())?;
+
+        thumbnails.push((format!("{}_{}.png", file_stem, width), thumbnail_bytes));
+    }
+
+    Ok(ProcessedImage {
+        file_name: format!("{}.{}", file_stem, extension),
+        bytes,
+        thumbnails,
+    })
+}
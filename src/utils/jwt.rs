@@ -3,24 +3,51 @@ use chrono::{Duration, Utc};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use std::env;
+use uuid::Uuid;
+
+// Access tokens are short-lived, the refresh token is used to get a new one
+pub const ACCESS_TOKEN_LIFETIME_MINUTES: i64 = 15;
+
+// Who issued the token and who it is meant for, both are checked when decoding
+const ISSUER: &str = "makangikang";
+const AUDIENCE: &str = "makangikang-web";
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    exp: usize,
-    iat: usize,
+pub struct Claims {
+    pub sub: String,
+    pub sid: String,
+    pub jti: String,
+    pub iss: String,
+    pub aud: String,
+    pub roles: Vec<String>,
+    pub exp: usize,
+    pub iat: usize,
 }
 enum EncodeDecode {
     Encode(EncodingKey),
     Decode(DecodingKey),
 }
 
-pub fn create_jwt() -> Result<String, (StatusCode, String)> {
+pub fn create_jwt(
+    user_id: &str,
+    session_id: &str,
+    roles: Vec<String>,
+) -> Result<String, (StatusCode, String)> {
     let mut now = Utc::now();
     let iat = now.timestamp() as usize;
-    let expires_in = Duration::days(1);
+    let expires_in = Duration::minutes(ACCESS_TOKEN_LIFETIME_MINUTES);
     now += expires_in;
     let exp = now.timestamp() as usize;
-    let claims = Claims { exp, iat };
+    let claims = Claims {
+        sub: user_id.to_owned(),
+        sid: session_id.to_owned(),
+        jti: Uuid::new_v4().simple().to_string(),
+        iss: ISSUER.to_owned(),
+        aud: AUDIENCE.to_owned(),
+        roles,
+        exp,
+        iat,
+    };
 
     let EncodeDecode::Encode(key) = get_secret_key(true) else {
         unreachable!()
@@ -37,13 +64,18 @@ pub fn create_jwt() -> Result<String, (StatusCode, String)> {
     }
 }
 
-pub fn is_valid(token: &str) -> Result<(), (StatusCode, String)> {
+pub fn is_valid(token: &str) -> Result<Claims, (StatusCode, String)> {
     let EncodeDecode::Decode(key) = get_secret_key(false) else {
         unreachable!()
     };
 
-    decode::<Claims>(token, &key, &Validation::new(Algorithm::HS256)).map_err(
-        |error| match error.kind() {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_issuer(&[ISSUER]);
+    validation.set_audience(&[AUDIENCE]);
+    validation.set_required_spec_claims(&["exp", "sub", "iss", "aud"]);
+
+    let token_data =
+        decode::<Claims>(token, &key, &validation).map_err(|error| match error.kind() {
             jsonwebtoken::errors::ErrorKind::ExpiredSignature => (
                 StatusCode::UNAUTHORIZED,
                 "Your login token has expired. Please login again.".to_owned(),
@@ -52,15 +84,15 @@ pub fn is_valid(token: &str) -> Result<(), (StatusCode, String)> {
                 StatusCode::UNAUTHORIZED,
                 "The token provided is invalid. Please login again".to_owned(),
             ),
-        },
-    )?;
+        })?;
 
-    Ok(())
+    Ok(token_data.claims)
 }
 
 // Pass in true for encode, false for decode
 fn get_secret_key(encode_or_decode: bool) -> EncodeDecode {
-    dotenv::dotenv().expect("unable to load .env file");
+    // The secret can also be set in the environment, e.g. when running the tests
+    dotenv::dotenv().ok();
     let secret = &env::var("JWT_SECRET").unwrap();
 
     match encode_or_decode {
@@ -1,3 +1,19 @@
 pub mod auth;
+pub mod carts;
+pub mod categories;
+pub mod currencies;
+pub mod images;
 pub mod jwt;
 pub mod models;
+pub mod money;
+pub mod orders;
+pub mod payments;
+pub mod permissions;
+pub mod pricing;
+pub mod refunds;
+pub mod search;
+pub mod sessions;
+#[cfg(test)]
+pub mod testing;
+pub mod variants;
+pub mod webhooks;
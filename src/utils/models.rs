@@ -1,9 +1,12 @@
-use chrono::naive::NaiveDateTime;
+use chrono::{naive::NaiveDateTime, Datelike};
 use pwhash::bcrypt;
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{types::Json, FromRow};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
+use crate::utils::money::{Currency, Money};
+
 // Used when a new user signs up to create a new user in the DB
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewUser {
@@ -19,6 +22,21 @@ pub struct RequestUser {
     pub user_password: String,
 }
 
+// Sent back when a user logs in or refreshes their tokens
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+}
+
+// Used to swap a refresh token for a new pair of tokens
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
 // An exact replica of the users table in the DB so make accessing the table easier
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
@@ -26,6 +44,7 @@ pub struct User {
     pub username: String,
     pub user_email: String,
     pub user_password_hash: String,
+    pub preferred_currency: Option<Currency>,
 }
 
 impl User {
@@ -35,10 +54,17 @@ impl User {
             username: new_user.username.clone(),
             user_email: new_user.user_email.clone().to_lowercase(),
             user_password_hash: bcrypt::hash(new_user.user_password.clone()).unwrap(),
+            preferred_currency: None,
         }
     }
 }
 
+// Used by admins to give a user a role, such as "admin"
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoleAssignment {
+    pub role_name: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::Type)]
 pub enum Gender {
     Male,
@@ -65,61 +91,919 @@ pub struct Address {
     pub country: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
 pub enum OrderStatus {
     Pending,
+    Paid,
     Processing,
     Shipped,
+    Delivered,
+    Cancelled,
+    Refunded,
+}
+
+impl OrderStatus {
+    // The statuses an order can be moved to from this one.
+    // Cancelled and refunded orders are final.
+    pub fn next_statuses(&self) -> &'static [OrderStatus] {
+        match self {
+            OrderStatus::Pending => &[OrderStatus::Paid, OrderStatus::Cancelled],
+            OrderStatus::Paid => &[
+                OrderStatus::Processing,
+                OrderStatus::Cancelled,
+                OrderStatus::Refunded,
+            ],
+            OrderStatus::Processing => &[
+                OrderStatus::Shipped,
+                OrderStatus::Cancelled,
+                OrderStatus::Refunded,
+            ],
+            OrderStatus::Shipped => &[OrderStatus::Delivered, OrderStatus::Refunded],
+            OrderStatus::Delivered => &[OrderStatus::Refunded],
+            OrderStatus::Cancelled | OrderStatus::Refunded => &[],
+        }
+    }
+
+    pub fn can_change_to(&self, new_status: OrderStatus) -> bool {
+        self.next_statuses().contains(&new_status)
+    }
 }
 
+// user_id is None for orders placed before orders were linked to users.
+// amount_refunded is how much of the total_cost has been given back.
+// The amounts are in the store's currency. currency is the one the order was shown in at
+// checkout and exchange_rate is how much of it 1 of the store's currency was worth.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Order {
     pub order_id: i64,
-    // pub address_id: i64,
+    pub user_id: Option<String>,
     pub creation_time: NaiveDateTime,
-    pub total_cost: Option<f64>,
+    pub total_cost: Option<Money>,
+    pub amount_refunded: Money,
     pub order_status: OrderStatus,
+    pub currency: Currency,
+    pub exchange_rate: f64,
+}
+
+// The addresses to use when placing an order and the card to pay with.
+// The shipping address is also used for billing when no billing address is given.
+#[derive(Debug, Deserialize)]
+pub struct Checkout {
+    pub shipping_address_id: i64,
+    pub billing_address_id: Option<i64>,
+    pub payment: PaymentCard,
+}
+
+// Card details are only passed on to the payment provider, they are never stored or logged
+#[derive(Deserialize)]
+pub struct PaymentCard {
+    pub card_number: String,
+    pub expiry_month: u32,
+    pub expiry_year: i32,
+    pub cvc: String,
+}
+
+impl PaymentCard {
+    // The card number without the spaces people usually type between the groups of digits
+    pub fn number(&self) -> String {
+        self.card_number
+            .chars()
+            .filter(|character| !character.is_whitespace())
+            .collect()
+    }
+
+    pub fn last4(&self) -> String {
+        let number = self.number();
+        number[number.len().saturating_sub(4)..].to_owned()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let number = self.number();
+        let number_is_valid = (12..=19).contains(&number.len())
+            && number.chars().all(|character| character.is_ascii_digit());
+        if !number_is_valid || !passes_luhn_check(&number) {
+            return Err("The card number is not valid".to_owned());
+        }
+
+        // Cards can be used until the end of the month they expire in
+        let today = chrono::Local::now().date_naive();
+        let this_month = today.year() * 12 + today.month() as i32;
+        if !(1..=12).contains(&self.expiry_month)
+            || self.expiry_year * 12 + (self.expiry_month as i32) < this_month
+        {
+            return Err("The card has expired or the expiry date is not valid".to_owned());
+        }
+
+        let cvc_is_valid = (3..=4).contains(&self.cvc.len())
+            && self.cvc.chars().all(|character| character.is_ascii_digit());
+        if !cvc_is_valid {
+            return Err("The CVC must be 3 or 4 digits".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for PaymentCard {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter
+            .debug_struct("PaymentCard")
+            .field("last4", &self.last4())
+            .finish_non_exhaustive()
+    }
+}
+
+// Catches most typos in card numbers before they are sent to the payment provider
+fn passes_luhn_check(number: &str) -> bool {
+    let sum: u32 = number
+        .chars()
+        .rev()
+        .filter_map(|character| character.to_digit(10))
+        .enumerate()
+        .map(|(index, digit)| match index % 2 {
+            1 if digit * 2 > 9 => digit * 2 - 9,
+            1 => digit * 2,
+            _ => digit,
+        })
+        .sum();
+
+    sum.is_multiple_of(10)
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::Type)]
-pub enum ProductCategory {
-    Meat,
-    Seafood,
-    Vegetable,
-    Fruit,
+pub enum AddressType {
+    Shipping,
+    Billing,
+}
+
+// A copy of one of the user's addresses, made when the order was placed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderAddress {
+    pub address_type: AddressType,
+    pub unit: String,
+    pub street: String,
+    pub city: String,
+    pub postal_code: i64,
+    pub state_province: String,
+    pub country: String,
+}
+
+// Everything about a single order.
+// Orders placed before addresses were recorded don't have any.
+#[derive(Debug, Serialize)]
+pub struct OrderDetail {
+    #[serde(flatten)]
+    pub order: Order,
+    pub addresses: Vec<OrderAddress>,
+    pub items: Vec<OrderItem>,
+    pub status_history: Vec<OrderStatusChange>,
+}
+
+// Used by admins to move an order along, e.g. from Paid to Processing
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusChange {
+    pub order_status: OrderStatus,
+    pub note: Option<String>,
+}
+
+// One row of the order_status_history table.
+// from_status is None when the order was placed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderStatusChange {
+    pub from_status: Option<OrderStatus>,
+    pub to_status: OrderStatus,
+    pub changed_by: Option<String>,
+    pub changed_at: NaiveDateTime,
+    pub note: Option<String>,
+}
+
+// e.g. /admin/orders?status=Paid
+#[derive(Debug, Deserialize)]
+pub struct OrderQuery {
+    pub status: Option<OrderStatus>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+pub enum ReturnStatus {
+    Requested,
+    Approved,
+    Rejected,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
+pub enum ReturnReason {
+    Damaged,
+    WrongItem,
+    NotAsDescribed,
+    NoLongerNeeded,
+    Other,
+}
+
+// Used by customers to ask to send back some of the items in a delivered order.
+// Each item can be a part of what was ordered, e.g. 1 of the 3 that were bought.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewReturn {
+    pub items: Vec<NewReturnItem>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewReturnItem {
+    pub variant_id: i64,
+    pub quantity: i64,
+    pub reason_code: ReturnReason,
+}
+
+// One row of the returns table, along with the amount refunded once it is approved
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Return {
+    pub return_id: i64,
+    pub order_id: i64,
+    pub user_id: String,
+    pub return_status: ReturnStatus,
+    pub comment: Option<String>,
+    pub requested_at: NaiveDateTime,
+    pub resolved_at: Option<NaiveDateTime>,
+    pub resolution_note: Option<String>,
+    pub refund_amount: Option<Money>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReturnItem {
+    pub return_id: i64,
+    pub variant_id: i64,
+    pub quantity: i64,
+    pub reason_code: ReturnReason,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReturnDetail {
+    #[serde(flatten)]
+    pub order_return: Return,
+    pub items: Vec<ReturnItem>,
+}
+
+// e.g. /admin/returns?status=Requested
+#[derive(Debug, Deserialize)]
+pub struct ReturnQuery {
+    pub status: Option<ReturnStatus>,
+}
+
+// Used by admins to approve or reject a return.
+// restock puts the returned items back into stock when the return is approved.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReturnDecision {
+    #[serde(default)]
+    pub restock: bool,
+    pub note: Option<String>,
+}
+
+// Used by admins to refund an order. Either refund some of the items, or an amount that isn't
+// for any items in particular, or leave both out to refund everything that hasn't been yet.
+// restock puts the refunded items back into stock.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewRefund {
+    #[serde(default)]
+    pub items: Vec<NewRefundItem>,
+    pub amount: Option<Money>,
+    #[serde(default)]
+    pub restock: bool,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewRefundItem {
+    pub variant_id: i64,
+    pub quantity: i64,
+}
+
+// An exact replica of the refunds table in the DB.
+// return_id is set when the refund was for a return.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Refund {
+    pub refund_id: i64,
+    pub order_id: i64,
+    pub return_id: Option<i64>,
+    pub credit_note_number: String,
+    pub amount: Money,
+    pub restocked: bool,
+    pub note: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefundItem {
+    pub variant_id: i64,
+    pub quantity: i64,
+    pub amount: Money,
+    pub tax: Money,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RefundDetail {
+    #[serde(flatten)]
+    pub refund: Refund,
+    pub items: Vec<RefundItem>,
+}
+
+// RequiresAction means the customer has to pass a 3-D Secure challenge before it's authorized.
+// Authorized payments are captured when the order ships.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+pub enum PaymentStatus {
+    RequiresAction,
+    Authorized,
+    Captured,
+    Voided,
+    Declined,
+    Refunded,
+}
+
+// An exact replica of the payment_intents table in the DB.
+// next_action tells the customer what to do when the payment requires action.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct PaymentIntent {
+    pub payment_intent_id: i64,
+    pub order_id: i64,
+    pub provider: String,
+    #[serde(skip_serializing)]
+    pub provider_reference: Option<String>,
+    pub payment_status: PaymentStatus,
+    pub amount: Money,
+    pub amount_captured: Money,
+    pub amount_refunded: Money,
+    pub card_last4: String,
+    pub next_action: Option<String>,
+    pub failure_message: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+// The code the customer was given by their bank to pass a 3-D Secure challenge
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeResponse {
+    pub code: String,
+}
+
+// A webhook event from the payment provider, e.g.
+// {"id": "evt_123", "type": "payment.captured",
+//  "data": {"reference": "fake_abc", "amount": {"minor_units": 4240, "currency": "MYR"}}}
+// amount is how much was captured, or how much has been refunded in total for payment.refunded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub data: PaymentEventData,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentEventData {
+    pub reference: String,
+    pub amount: Option<Money>,
+    pub failure_message: Option<String>,
+}
+
+// An exact replica of the categories table in the DB.
+// Categories without a parent are at the top of the tree.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Category {
+    pub category_id: i64,
+    pub parent_id: Option<i64>,
+    pub category_name: String,
+    pub slug: String,
+    pub display_order: i64,
+}
+
+// A category along with all of the categories below it
+#[derive(Debug, Serialize)]
+pub struct CategoryTree {
+    #[serde(flatten)]
+    pub category: Category,
+    pub children: Vec<CategoryTree>,
+}
+
+// Used by admins to create a category or replace an existing category's details.
+// The slug is made from the name when it isn't given.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewCategory {
+    pub category_name: String,
+    pub slug: Option<String>,
+    pub parent_id: Option<i64>,
+    #[serde(default)]
+    pub display_order: i64,
+}
+
+impl NewCategory {
+    pub fn slug(&self) -> String {
+        match &self.slug {
+            Some(slug) => slug.clone(),
+            None => self
+                .category_name
+                .to_lowercase()
+                .split(|character: char| !character.is_ascii_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<&str>>()
+                .join("-"),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let name_length = self.category_name.trim().chars().count();
+        if name_length == 0 || name_length > 30 {
+            return Err("The category name must be between 1 and 30 characters long".to_owned());
+        }
+
+        let slug = self.slug();
+        let slug_is_valid = slug.chars().all(|character| {
+            character.is_ascii_lowercase() || character.is_ascii_digit() || character == '-'
+        });
+        if slug.is_empty() || slug.len() > 40 || !slug_is_valid {
+            return Err("The slug must be 1 to 40 lowercase letters, numbers or dashes".to_owned());
+        }
+
+        Ok(())
+    }
 }
 
+// category_name comes from joining the categories table.
+// price is the default variant's price and stock is the total across all variants.
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct Product {
     pub product_id: i64,
     pub product_name: String,
     pub product_description: Option<String>,
-    pub product_category: ProductCategory,
+    pub category_id: Option<i64>,
+    pub category_name: Option<String>,
+    pub stock: i64,
+    pub price: Money,
+    pub img_path: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProductSort {
+    #[default]
+    Id,
+    Name,
+    Price,
+    Stock,
+}
+
+impl ProductSort {
+    // Only these fixed column names are ever put into the ORDER BY clause
+    pub fn column(&self) -> &'static str {
+        match self {
+            ProductSort::Id => "product_id",
+            ProductSort::Name => "product_name",
+            ProductSort::Price => "price",
+            ProductSort::Stock => "stock",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+// The query string accepted when listing products, every field is optional
+// e.g. /get_products?category=fruit&max_price=5&sort=price&order=desc&page=2
+// category is a slug and also matches products in any of its subcategories.
+// min_price and max_price are in the currency the prices are shown in.
+#[derive(Debug, Deserialize)]
+pub struct ProductQuery {
+    pub category: Option<String>,
+    pub min_price: Option<String>,
+    pub max_price: Option<String>,
+    #[serde(default)]
+    pub in_stock: bool,
+    #[serde(default)]
+    pub sort: ProductSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+// One page of a longer list. next_page is None on the last page.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+    pub next_page: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Availability {
+    InStock,
+    LowStock,
+    OutOfStock,
+    // Archived by an admin, it can still be viewed but no longer bought
+    Discontinued,
+}
+
+// Everything the product page needs to show a single product
+#[derive(Debug, Serialize)]
+pub struct ProductDetail {
+    #[serde(flatten)]
+    pub product: Product,
+    pub availability: Availability,
+    pub variants: Vec<ProductVariant>,
+    pub related_products: Vec<Product>,
+}
+
+// Option names mapped to values, e.g. {"weight": "500g"}
+pub type VariantOptions = Json<BTreeMap<String, String>>;
+
+// An exact replica of the product_variants table in the DB.
+// A product is bought as one of its variants, and every product has one default variant.
+// When img_path is None the product's image is used.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct ProductVariant {
+    pub variant_id: i64,
+    pub product_id: i64,
+    pub sku: String,
+    pub options: VariantOptions,
+    pub price: Money,
+    pub stock: i64,
+    pub img_path: Option<String>,
+    pub is_default: bool,
+}
+
+// Used by admins to add a variant to a product or replace an existing variant's details
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewVariant {
+    pub sku: String,
+    #[serde(default)]
+    pub options: VariantOptions,
+    pub price: Money,
+    pub stock: i64,
+    pub img_path: Option<String>,
+    #[serde(default)]
+    pub is_default: bool,
+}
+
+impl NewVariant {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_sku(&self.sku)?;
+        validate_stock(self.stock)?;
+        validate_price(&self.price)?;
+        if let Some(img_path) = &self.img_path {
+            if img_path.trim().is_empty() {
+                return Err("The image path can't be empty".to_owned());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The most a product can cost and the most of it that can be in stock, in minor units.
+// Together with the limits on exchange rates, they keep any line of a cart or order well
+// within what Money can hold, in the store's currency or any other.
+const MAX_PRICE_MINOR_UNITS: i64 = 1_000_000_000;
+const MAX_STOCK: i64 = 1_000_000;
+
+// Prices are stored in the store's currency, so they have to be given in it
+fn validate_price(price: &Money) -> Result<(), String> {
+    if price.currency() != Currency::base() {
+        return Err(format!("The price must be in {}", Currency::base()));
+    }
+    if price.is_negative() {
+        return Err("The price can't be negative".to_owned());
+    }
+    let max_price = Money::new(MAX_PRICE_MINOR_UNITS, Currency::base());
+    if *price > max_price {
+        return Err(format!(
+            "The price can't be more than {} {}",
+            max_price.to_decimal_string(),
+            Currency::base()
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_stock(stock: i64) -> Result<(), String> {
+    if !(0..=MAX_STOCK).contains(&stock) {
+        return Err(format!("The stock must be between 0 and {}", MAX_STOCK));
+    }
+
+    Ok(())
+}
+
+// SKUs are printed on labels, so they are kept short and simple
+fn validate_sku(sku: &str) -> Result<(), String> {
+    let sku_is_valid = sku.chars().all(|character| {
+        character.is_ascii_uppercase() || character.is_ascii_digit() || character == '-'
+    });
+    if sku.is_empty() || sku.len() > 40 || !sku_is_valid {
+        return Err("The SKU must be 1 to 40 uppercase letters, numbers or dashes".to_owned());
+    }
+
+    Ok(())
+}
+
+// e.g. /products/search?q=chicken&limit=5
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+// A product that matched a search. The matching words in highlighted_name
+// and snippet are wrapped in <mark> tags.
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub product: Product,
+    pub highlighted_name: String,
+    pub snippet: Option<String>,
+}
+
+// fuzzy is true when nothing matched exactly and the results allow for typos instead
+#[derive(Debug, Serialize)]
+pub struct SearchResults {
+    pub query: String,
+    pub fuzzy: bool,
+    pub results: Vec<SearchResult>,
+}
+
+// Used by admins to create a product or replace an existing product's details.
+// The stock, price and SKU belong to the product's default variant.
+// A SKU is made up from the product ID when one isn't given for a new product.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewProduct {
+    pub product_name: String,
+    pub product_description: Option<String>,
+    pub category_id: i64,
     pub stock: i64,
-    pub price: f64,
+    pub price: Money,
     pub img_path: String,
+    pub sku: Option<String>,
 }
 
+impl NewProduct {
+    // Whether the category exists is checked against the database separately
+    pub fn validate(&self) -> Result<(), String> {
+        let name_length = self.product_name.trim().chars().count();
+        if name_length == 0 || name_length > 20 {
+            return Err("The product name must be between 1 and 20 characters long".to_owned());
+        }
+        validate_stock(self.stock)?;
+        validate_price(&self.price)?;
+        if self.img_path.trim().is_empty() {
+            return Err("The product needs an image".to_owned());
+        }
+        if let Some(sku) = &self.sku {
+            validate_sku(sku)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Sent back after a product image is uploaded.
+// All paths are relative to /images/products
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadedImage {
+    pub img_path: String,
+    pub thumbnail_paths: Vec<String>,
+}
+
+// Used when adding a product to the cart.
+// The product's default variant is added when variant_id isn't given.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CartItem {
-    #[serde(skip_deserializing)]
-    pub user_id: String,
     pub product_id: i64,
+    pub variant_id: Option<i64>,
+    pub quantity: i64,
+}
+
+// Used to set how many of a variant are in the cart
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CartQuantity {
     pub quantity: i64,
 }
 
+// Sent back when a guest starts a cart. The token is sent in the X-Cart-Token header
+// to use the cart, and when logging in to move its items into the user's cart.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GuestCart {
+    pub cart_token: String,
+    pub expires_at: NaiveDateTime,
+}
+
 // Used to show each cart item to the user
-// Grabbed from joining the cart_items and products tables
+// Grabbed from joining the cart_items, product_variants and products tables.
+// img_path is the variant's image, or the product's when the variant doesn't have one.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DisplayCartItem {
+    pub variant_id: i64,
+    pub product_id: i64,
     pub product_name: String,
-    pub price: f64,
+    pub sku: String,
+    pub options: VariantOptions,
+    pub img_path: String,
+    pub price: Money,
     pub quantity: i64,
+    pub line_total: Money,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CartWarningKind {
+    PriceChanged,
+    NotEnoughStock,
+    OutOfStock,
+    // Archived by an admin, so it can't be ordered any more
+    Discontinued,
+}
+
+// Something about an item that changed since it was put in the cart
+#[derive(Debug, Serialize)]
+pub struct CartWarning {
+    pub variant_id: i64,
+    pub kind: CartWarningKind,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppliedDiscount {
+    pub description: String,
+    pub amount: Money,
+}
+
+// The cart along with what the order would cost.
+// The tax and shipping are estimates, the order records the actual amounts.
+// The amounts are in the shopper's currency, which 1 of the store's currency is worth
+// exchange_rate of.
+#[derive(Debug, Serialize)]
+pub struct Cart {
+    pub items: Vec<DisplayCartItem>,
+    pub subtotal: Money,
+    pub discounts: Vec<AppliedDiscount>,
+    pub discount_total: Money,
+    pub estimated_tax: Money,
+    pub shipping_estimate: Money,
+    pub total: Money,
+    pub warnings: Vec<CartWarning>,
+    pub exchange_rate: f64,
+}
+
+impl Cart {
+    pub fn empty(currency: Currency, exchange_rate: f64) -> Cart {
+        Cart {
+            items: Vec::new(),
+            subtotal: Money::zero(currency),
+            discounts: Vec::new(),
+            discount_total: Money::zero(currency),
+            estimated_tax: Money::zero(currency),
+            shipping_estimate: Money::zero(currency),
+            total: Money::zero(currency),
+            warnings: Vec::new(),
+            exchange_rate,
+        }
+    }
 }
 
+// The prices are what was paid when the order was placed
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OrderItem {
     pub order_id: i64,
     pub product_id: i64,
+    pub variant_id: i64,
     pub quantity: i64,
+    pub unit_price: Money,
+    pub line_subtotal: Money,
+    pub discount: Money,
+    pub tax: Money,
+}
+
+// How much of a currency 1 of the store's currency is worth
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExchangeRate {
+    pub currency: Currency,
+    pub rate: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+// Used by admins to set a currency's exchange rate
+#[derive(Debug, Deserialize)]
+pub struct NewExchangeRate {
+    pub rate: f64,
+}
+
+// How many minor units of another currency a minor unit of the store's currency can be worth,
+// e.g. sen to cents. It's far wider than any real rate between the currencies on offer, and
+// keeps converted prices from overflowing.
+const MIN_MINOR_UNIT_RATE: f64 = 0.001;
+const MAX_MINOR_UNIT_RATE: f64 = 1000.0;
+
+impl NewExchangeRate {
+    // The rate is for the given currency per 1 of the store's currency
+    pub fn validate(&self, currency: Currency) -> Result<(), String> {
+        let extra_decimal_places =
+            Currency::base().decimal_places() as i32 - currency.decimal_places() as i32;
+        let scale = 10_f64.powi(extra_decimal_places);
+        let (min_rate, max_rate) = (MIN_MINOR_UNIT_RATE * scale, MAX_MINOR_UNIT_RATE * scale);
+
+        if !self.rate.is_finite() || self.rate < min_rate || self.rate > max_rate {
+            return Err(format!(
+                "The exchange rate for {} must be between {} and {}",
+                currency, min_rate, max_rate
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// The store's currency and the other currencies prices can be shown in
+#[derive(Debug, Serialize)]
+pub struct Currencies {
+    pub base: Currency,
+    pub exchange_rates: Vec<ExchangeRate>,
+}
+
+// currency is the one prices are shown in when a request doesn't ask for one,
+// None to use the store's currency
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Preferences {
+    pub currency: Option<Currency>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_variant(price: &str, stock: i64) -> NewVariant {
+        serde_json::from_value(serde_json::json!({
+            "sku": "TEST-1",
+            "price": price,
+            "stock": stock,
+            "img_path": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn prices_up_to_the_limit_are_accepted() {
+        assert_eq!(new_variant("0", 10).validate(), Ok(()));
+        assert_eq!(new_variant("10000000.00", 10).validate(), Ok(()));
+    }
+
+    #[test]
+    fn oversized_prices_are_rejected() {
+        assert!(new_variant("10000000.01", 10).validate().is_err());
+        assert!(new_variant("92233720368547758.07", 10).validate().is_err());
+    }
+
+    fn rate(rate: f64, currency: Currency) -> Result<(), String> {
+        NewExchangeRate { rate }.validate(currency)
+    }
+
+    #[test]
+    fn exchange_rates_within_the_limits_are_accepted() {
+        assert_eq!(rate(0.001, Currency::Usd), Ok(()));
+        assert_eq!(rate(0.21, Currency::Usd), Ok(()));
+        assert_eq!(rate(1000.0, Currency::Usd), Ok(()));
+        // Yen don't have any decimal places, so there are a lot more of them per ringgit
+        assert_eq!(rate(33.0, Currency::Jpy), Ok(()));
+        assert_eq!(rate(100_000.0, Currency::Jpy), Ok(()));
+    }
+
+    #[test]
+    fn exchange_rates_outside_the_limits_are_rejected() {
+        assert!(rate(0.0009, Currency::Usd).is_err());
+        assert!(rate(1000.1, Currency::Usd).is_err());
+        assert!(rate(1e17, Currency::Usd).is_err());
+        assert!(rate(0.0, Currency::Usd).is_err());
+        assert!(rate(f64::NAN, Currency::Usd).is_err());
+        assert!(rate(0.09, Currency::Jpy).is_err());
+        assert!(rate(100_001.0, Currency::Jpy).is_err());
+    }
+
+    #[test]
+    fn oversized_stock_is_rejected() {
+        assert_eq!(new_variant("1.00", MAX_STOCK).validate(), Ok(()));
+        assert!(new_variant("1.00", MAX_STOCK + 1).validate().is_err());
+        assert!(new_variant("1.00", -1).validate().is_err());
+    }
 }
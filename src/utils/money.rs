@@ -0,0 +1,741 @@
+use std::cmp::Ordering;
+use std::env;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use serde::de::{self, MapAccess, Visitor};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::sqlite::{Sqlite, SqliteArgumentValue, SqliteTypeInfo, SqliteValueRef};
+use sqlx::{Decode, Encode, Pool, Type};
+
+// Rates, e.g. a tax rate, are applied to this many decimal places
+const RATE_SCALE: i64 = 1_000_000_000;
+
+// The ISO 4217 currencies the store knows about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "UPPERCASE")]
+#[sqlx(rename_all = "UPPERCASE")]
+pub enum Currency {
+    Myr,
+    Sgd,
+    Usd,
+    Eur,
+    Gbp,
+    Jpy,
+}
+
+impl Currency {
+    // The currency every price is stored in. Set with STORE_CURRENCY, e.g. MYR, and read once
+    // since amounts already in the database would be wrong if it changed while running.
+    pub fn base() -> Currency {
+        static BASE: OnceLock<Currency> = OnceLock::new();
+
+        *BASE.get_or_init(|| {
+            env::var("STORE_CURRENCY")
+                .ok()
+                .and_then(|code| code.parse().ok())
+                .unwrap_or(Currency::Myr)
+        })
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Currency::Myr => "MYR",
+            Currency::Sgd => "SGD",
+            Currency::Usd => "USD",
+            Currency::Eur => "EUR",
+            Currency::Gbp => "GBP",
+            Currency::Jpy => "JPY",
+        }
+    }
+
+    // How many decimal places the currency has, e.g. 2 for cents and 0 for yen
+    pub fn decimal_places(&self) -> u32 {
+        match self {
+            Currency::Jpy => 0,
+            _ => 2,
+        }
+    }
+
+    fn minor_units_per_major(&self) -> i64 {
+        10_i64.pow(self.decimal_places())
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(code: &str) -> Result<Currency, String> {
+        match code.trim().to_ascii_uppercase().as_str() {
+            "MYR" => Ok(Currency::Myr),
+            "SGD" => Ok(Currency::Sgd),
+            "USD" => Ok(Currency::Usd),
+            "EUR" => Ok(Currency::Eur),
+            "GBP" => Ok(Currency::Gbp),
+            "JPY" => Ok(Currency::Jpy),
+            _ => Err(format!("{} is not a supported currency", code)),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+// An amount of money as a whole number of the currency's smallest unit, e.g. cents, so adding
+// it up never loses a fraction of a cent the way floats do.
+// Amounts of different currencies can't be added together or compared, and trying to is a bug,
+// so the arithmetic panics instead of quietly giving a wrong answer. The same goes for amounts
+// that don't fit in an i64, about 92 quadrillion ringgit. Prices, stock and exchange rates are
+// limited when they're set so that no order comes anywhere near it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Money {
+    minor_units: i64,
+    currency: Currency,
+}
+
+impl Money {
+    pub fn new(minor_units: i64, currency: Currency) -> Money {
+        Money {
+            minor_units,
+            currency,
+        }
+    }
+
+    pub fn zero(currency: Currency) -> Money {
+        Money::new(0, currency)
+    }
+
+    // e.g. from_major(10, Currency::Myr) is 10.00 ringgit
+    pub fn from_major(whole: i64, currency: Currency) -> Money {
+        let minor_units = whole
+            .checked_mul(currency.minor_units_per_major())
+            .expect("The amount is too large");
+        Money::new(minor_units, currency)
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.minor_units == 0
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.minor_units > 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.minor_units < 0
+    }
+
+    // Reads a decimal amount such as "12.50" exactly. Having more decimal places than the
+    // currency does is an error rather than being rounded away.
+    pub fn parse(amount: &str, currency: Currency) -> Result<Money, String> {
+        let invalid = || format!("{} is not a valid amount of {}", amount, currency);
+
+        let trimmed = amount.trim();
+        let (negative, digits) = match trimmed.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, trimmed),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+
+        let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+            return Err(invalid());
+        }
+        if fraction.len() > currency.decimal_places() as usize {
+            return Err(format!(
+                "{} only has {} decimal places",
+                currency,
+                currency.decimal_places()
+            ));
+        }
+
+        let whole: i64 = whole.parse().map_err(|_| invalid())?;
+        let fraction: i64 = format!(
+            "{:0<width$}",
+            fraction,
+            width = currency.decimal_places() as usize
+        )
+        .parse()
+        .unwrap_or(0);
+        let minor_units = whole
+            .checked_mul(currency.minor_units_per_major())
+            .and_then(|minor_units| minor_units.checked_add(fraction))
+            .ok_or_else(invalid)?;
+
+        Ok(Money::new(
+            if negative { -minor_units } else { minor_units },
+            currency,
+        ))
+    }
+
+    // The amount without the currency, e.g. "12.50"
+    pub fn to_decimal_string(self) -> String {
+        let places = self.currency.decimal_places() as usize;
+        let per_major = self.currency.minor_units_per_major();
+        let sign = if self.is_negative() { "-" } else { "" };
+        let whole = (self.minor_units / per_major).unsigned_abs();
+        let fraction = (self.minor_units % per_major).unsigned_abs();
+
+        match places {
+            0 => format!("{}{}", sign, whole),
+            _ => format!("{}{}.{:0places$}", sign, whole, fraction),
+        }
+    }
+
+    // Multiplies by numerator / denominator, rounding half away from zero.
+    // Used to split an amount, e.g. the share of a line for some of its items.
+    pub fn mul_ratio(self, numerator: i64, denominator: i64) -> Money {
+        assert!(denominator != 0, "Can't split an amount into 0 parts");
+
+        let product = self.minor_units as i128 * numerator as i128;
+        let denominator = denominator as i128;
+        let quotient = product / denominator;
+        let remainder = product % denominator;
+
+        // Round half away from zero, whichever signs the numbers have
+        let rounded = if 2 * remainder.abs() >= denominator.abs() {
+            quotient + (product.signum() * denominator.signum())
+        } else {
+            quotient
+        };
+
+        Money::new(
+            i64::try_from(rounded).expect("The amount is too large"),
+            self.currency,
+        )
+    }
+
+    // Multiplies by a rate such as a tax rate of 0.06, rounded to the nearest minor unit
+    pub fn apply_rate(self, rate: f64) -> Money {
+        self.mul_ratio((rate * RATE_SCALE as f64).round() as i64, RATE_SCALE)
+    }
+
+    // Converts to another currency at a rate of that currency per 1 of this one, e.g. 0.21 USD
+    // per ringgit, rounded to the nearest minor unit of the other currency
+    pub fn convert(self, to: Currency, rate: f64) -> Money {
+        let (numerator, denominator) = conversion_ratio(self.currency, to, rate);
+        let converted = self.mul_ratio(numerator, denominator);
+
+        Money::new(converted.minor_units, to)
+    }
+
+    // The lowest amount of another currency that convert() turns into at least this amount,
+    // e.g. the lowest price in ringgit shown as at least 5.00 USD. Only for amounts that
+    // aren't negative, since convert() rounds negative amounts the other way.
+    pub fn lowest_converting_to_at_least(self, from: Currency, rate: f64) -> Money {
+        let (numerator, denominator) = conversion_ratio(from, self.currency, rate);
+        if numerator == 0 {
+            // Everything converts to zero
+            let lowest = if self.is_positive() { i64::MAX } else { 0 };
+            return Money::new(lowest, from);
+        }
+
+        // Rounding half up, x * n / d reaches the amount once 2 * x * n >= (2 * amount - 1) * d
+        let bound = (2 * self.minor_units as i128 - 1) * denominator as i128;
+        let lowest = -(-bound).div_euclid(2 * numerator as i128);
+        Money::new(saturate(lowest), from)
+    }
+
+    // The highest amount of another currency that convert() turns into at most this amount,
+    // e.g. the highest price in ringgit shown as at most 5.00 USD. It's negative when no
+    // amount that isn't negative does.
+    pub fn highest_converting_to_at_most(self, from: Currency, rate: f64) -> Money {
+        let (numerator, denominator) = conversion_ratio(from, self.currency, rate);
+        if numerator == 0 {
+            let highest = if self.is_negative() { -1 } else { i64::MAX };
+            return Money::new(highest, from);
+        }
+
+        // Rounding half up, x * n / d stays within the amount while 2 * x * n < (2 * amount + 1) * d
+        let bound = (2 * self.minor_units as i128 + 1) * denominator as i128;
+        let highest = (bound - 1).div_euclid(2 * numerator as i128);
+        Money::new(saturate(highest), from)
+    }
+
+    pub fn min(self, other: Money) -> Money {
+        self.check_currency(other);
+        if other.minor_units < self.minor_units {
+            other
+        } else {
+            self
+        }
+    }
+
+    pub fn max(self, other: Money) -> Money {
+        self.check_currency(other);
+        if other.minor_units > self.minor_units {
+            other
+        } else {
+            self
+        }
+    }
+
+    fn check_currency(&self, other: Money) {
+        assert_eq!(
+            self.currency, other.currency,
+            "Can't mix amounts of {} and {}",
+            self.currency, other.currency
+        );
+    }
+}
+
+// Prices are given in the store's currency when nothing says otherwise
+impl Default for Money {
+    fn default() -> Money {
+        Money::zero(Currency::base())
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.currency, self.to_decimal_string())
+    }
+}
+
+impl PartialOrd for Money {
+    // Amounts of different currencies can't be compared without an exchange rate
+    fn partial_cmp(&self, other: &Money) -> Option<Ordering> {
+        match self.currency == other.currency {
+            true => Some(self.minor_units.cmp(&other.minor_units)),
+            false => None,
+        }
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        self.check_currency(other);
+        let minor_units = self
+            .minor_units
+            .checked_add(other.minor_units)
+            .expect("The amount is too large");
+        Money::new(minor_units, self.currency)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        self.check_currency(other);
+        let minor_units = self
+            .minor_units
+            .checked_sub(other.minor_units)
+            .expect("The amount is too large");
+        Money::new(minor_units, self.currency)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        *self = *self - other;
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        let minor_units = self
+            .minor_units
+            .checked_neg()
+            .expect("The amount is too large");
+        Money::new(minor_units, self.currency)
+    }
+}
+
+// e.g. the unit price times the quantity
+impl Mul<i64> for Money {
+    type Output = Money;
+
+    fn mul(self, quantity: i64) -> Money {
+        let minor_units = self
+            .minor_units
+            .checked_mul(quantity)
+            .expect("The amount is too large");
+        Money::new(minor_units, self.currency)
+    }
+}
+
+// Adding up nothing gives zero in the store's currency
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(mut amounts: I) -> Money {
+        match amounts.next() {
+            Some(first) => amounts.fold(first, Add::add),
+            None => Money::default(),
+        }
+    }
+}
+
+// Sent as {"minor_units": 1250, "currency": "MYR"} for 12.50 ringgit
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut money = serializer.serialize_struct("Money", 2)?;
+        money.serialize_field("minor_units", &self.minor_units)?;
+        money.serialize_field("currency", &self.currency)?;
+        money.end()
+    }
+}
+
+// The numerator and denominator that turn minor units of one currency into minor units of
+// another at a rate of that currency per 1 of this one
+fn conversion_ratio(from: Currency, to: Currency, rate: f64) -> (i64, i64) {
+    let numerator = ((rate * RATE_SCALE as f64).round() as i64)
+        .checked_mul(to.minor_units_per_major())
+        .expect("The exchange rate is too large");
+
+    (numerator, RATE_SCALE * from.minor_units_per_major())
+}
+
+fn saturate(minor_units: i128) -> i64 {
+    minor_units.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+// Accepts the same shape it's sent as, or a decimal string such as "12.50" in the store's
+// currency. Plain JSON numbers aren't accepted since they're floats.
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Money, D::Error> {
+        struct MoneyVisitor;
+
+        impl<'de> Visitor<'de> for MoneyVisitor {
+            type Value = Money;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str(
+                    "an object with minor_units and currency, or a decimal string such as \"12.50\"",
+                )
+            }
+
+            fn visit_str<E: de::Error>(self, amount: &str) -> Result<Money, E> {
+                Money::parse(amount, Currency::base()).map_err(E::custom)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Money, A::Error> {
+                let mut minor_units = None;
+                let mut currency = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "minor_units" => minor_units = Some(map.next_value()?),
+                        "currency" => currency = Some(map.next_value()?),
+                        _ => {
+                            map.next_value::<de::IgnoredAny>()?;
+                        }
+                    }
+                }
+
+                Ok(Money::new(
+                    minor_units.ok_or_else(|| de::Error::missing_field("minor_units"))?,
+                    currency.ok_or_else(|| de::Error::missing_field("currency"))?,
+                ))
+            }
+        }
+
+        deserializer.deserialize_any(MoneyVisitor)
+    }
+}
+
+// The database keeps the currency its amounts are in, since changing STORE_CURRENCY doesn't
+// convert them. Returns an error if it isn't the store's currency.
+pub async fn check_store_currency(db_pool: &Pool<Sqlite>) -> Result<(), String> {
+    let stored_currency =
+        sqlx::query_scalar!(r#"SELECT currency AS "currency: Currency" FROM store_currency"#)
+            .fetch_one(db_pool)
+            .await
+            .map_err(|error| format!("Unable to read the store's currency: {}", error))?;
+
+    match stored_currency == Currency::base() {
+        true => Ok(()),
+        false => Err(format!(
+            "STORE_CURRENCY is {} but the amounts in the database are in {}",
+            Currency::base(),
+            stored_currency
+        )),
+    }
+}
+
+// Stored as an INTEGER of minor units. Every amount in the database is in the store's currency.
+impl Type<Sqlite> for Money {
+    fn type_info() -> SqliteTypeInfo {
+        <i64 as Type<Sqlite>>::type_info()
+    }
+
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <i64 as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'q> Encode<'q, Sqlite> for Money {
+    fn encode_by_ref(&self, args: &mut Vec<SqliteArgumentValue<'q>>) -> IsNull {
+        <i64 as Encode<Sqlite>>::encode_by_ref(&self.minor_units, args)
+    }
+}
+
+// Amounts are read as the store's currency, which check_store_currency makes sure they're in
+impl<'r> Decode<'r, Sqlite> for Money {
+    fn decode(value: SqliteValueRef<'r>) -> Result<Money, BoxDynError> {
+        let minor_units = <i64 as Decode<Sqlite>>::decode(value)?;
+        Ok(Money::new(minor_units, Currency::base()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    // Comfortably more than any order, while leaving room to add a few together
+    const LIMIT: i64 = 1_000_000_000_000_000;
+
+    fn currency() -> impl Strategy<Value = Currency> {
+        prop_oneof![
+            Just(Currency::Myr),
+            Just(Currency::Sgd),
+            Just(Currency::Usd),
+            Just(Currency::Eur),
+            Just(Currency::Gbp),
+            Just(Currency::Jpy),
+        ]
+    }
+
+    fn money() -> impl Strategy<Value = Money> {
+        (-LIMIT..LIMIT, currency())
+            .prop_map(|(minor_units, currency)| Money::new(minor_units, currency))
+    }
+
+    fn ringgit(minor_units: i64) -> Money {
+        Money::new(minor_units, Currency::Myr)
+    }
+
+    proptest! {
+        #[test]
+        fn mul_ratio_rounds_to_the_nearest_minor_unit(
+            minor_units in -LIMIT..LIMIT,
+            numerator in -1_000_000_i64..1_000_000,
+            denominator in (1_i64..1_000_000).prop_union(-1_000_000_i64..-1),
+        ) {
+            let result = ringgit(minor_units).mul_ratio(numerator, denominator).minor_units as i128;
+            let exact = minor_units as i128 * numerator as i128;
+            let scaled = result * denominator as i128;
+
+            // Within half a minor unit of the exact answer, and halves go away from zero
+            let error = (exact - scaled).abs() * 2;
+            prop_assert!(error <= (denominator as i128).abs());
+            if error == (denominator as i128).abs() {
+                prop_assert!(scaled.abs() > exact.abs());
+            }
+        }
+
+        #[test]
+        fn mul_ratio_of_one_changes_nothing(amount in money(), parts in 1_i64..1_000_000) {
+            prop_assert_eq!(amount.mul_ratio(parts, parts), amount);
+        }
+
+        #[test]
+        fn convert_rounds_to_the_nearest_minor_unit(
+            minor_units in -1_000_000_000_i64..1_000_000_000,
+            rate_in_thousandths in 1_i64..100_000,
+        ) {
+            let rate = rate_in_thousandths as f64 / 1000.0;
+            let converted = ringgit(minor_units).convert(Currency::Jpy, rate);
+
+            // Ringgit have 2 decimal places and yen have none
+            let exact = minor_units as i128 * rate_in_thousandths as i128;
+            let error = (exact - converted.minor_units as i128 * 100_000).abs() * 2;
+            prop_assert_eq!(converted.currency(), Currency::Jpy);
+            prop_assert!(error <= 100_000);
+        }
+
+        #[test]
+        fn lowest_converting_to_at_least_is_the_boundary(
+            minor_units in 0_i64..1_000_000_000,
+            offset in -2_i64..=2,
+            rate_in_thousandths in 1_i64..100_000,
+            to in currency(),
+        ) {
+            let rate = rate_in_thousandths as f64 / 1000.0;
+            let price = ringgit(minor_units);
+            // Close to what the price is shown as, so the rounding matters
+            let shown = (price.convert(to, rate).minor_units + offset).max(0);
+            let lowest = Money::new(shown, to).lowest_converting_to_at_least(Currency::Myr, rate);
+
+            prop_assert_eq!(lowest.currency(), Currency::Myr);
+            prop_assert_eq!(price.convert(to, rate).minor_units >= shown, price >= lowest);
+        }
+
+        #[test]
+        fn highest_converting_to_at_most_is_the_boundary(
+            minor_units in 0_i64..1_000_000_000,
+            offset in -2_i64..=2,
+            rate_in_thousandths in 1_i64..100_000,
+            to in currency(),
+        ) {
+            let rate = rate_in_thousandths as f64 / 1000.0;
+            let price = ringgit(minor_units);
+            let shown = price.convert(to, rate).minor_units + offset;
+            let highest = Money::new(shown, to).highest_converting_to_at_most(Currency::Myr, rate);
+
+            prop_assert_eq!(highest.currency(), Currency::Myr);
+            prop_assert_eq!(price.convert(to, rate).minor_units <= shown, price <= highest);
+        }
+
+        #[test]
+        fn convert_at_a_rate_of_one_keeps_the_amount(minor_units in -LIMIT..LIMIT) {
+            let converted = ringgit(minor_units).convert(Currency::Usd, 1.0);
+
+            prop_assert_eq!(converted, Money::new(minor_units, Currency::Usd));
+        }
+
+        #[test]
+        fn parse_reads_back_the_decimal_string(amount in money()) {
+            let parsed = Money::parse(&amount.to_decimal_string(), amount.currency());
+
+            prop_assert_eq!(parsed, Ok(amount));
+        }
+
+        #[test]
+        fn add_and_sub_undo_each_other(a in money(), b in -LIMIT..LIMIT) {
+            let b = Money::new(b, a.currency());
+
+            prop_assert_eq!(a + b - b, a);
+            prop_assert_eq!(a + b, b + a);
+        }
+
+        #[test]
+        fn sum_adds_up_every_amount(amounts in prop::collection::vec(-LIMIT..LIMIT, 1..20)) {
+            let total: Money = amounts.iter().map(|minor_units| ringgit(*minor_units)).sum();
+
+            prop_assert_eq!(total, ringgit(amounts.iter().sum()));
+        }
+
+        #[test]
+        fn mul_is_repeated_add(amount in money(), quantity in 0_i64..100) {
+            let added = (0..quantity).fold(Money::zero(amount.currency()), |total, _| total + amount);
+
+            prop_assert_eq!(amount * quantity, added);
+        }
+    }
+
+    #[test]
+    fn converts_between_currencies() {
+        assert_eq!(
+            ringgit(4000).convert(Currency::Usd, 0.2134),
+            Money::new(854, Currency::Usd)
+        );
+        assert_eq!(
+            ringgit(4000).convert(Currency::Jpy, 31.7),
+            Money::new(1268, Currency::Jpy)
+        );
+        assert_eq!(
+            Money::new(1268, Currency::Jpy).convert(Currency::Myr, 1.0 / 31.7),
+            ringgit(4000)
+        );
+    }
+
+    #[test]
+    fn rounds_halves_away_from_zero() {
+        assert_eq!(ringgit(5).mul_ratio(1, 2), ringgit(3));
+        assert_eq!(ringgit(-5).mul_ratio(1, 2), ringgit(-3));
+        assert_eq!(ringgit(5).mul_ratio(1, -2), ringgit(-3));
+        assert_eq!(ringgit(1000).apply_rate(0.06), ringgit(60));
+        assert_eq!(ringgit(1042).apply_rate(0.06), ringgit(63));
+    }
+
+    #[test]
+    fn parses_decimal_amounts() {
+        assert_eq!(Money::parse("12.5", Currency::Myr), Ok(ringgit(1250)));
+        assert_eq!(Money::parse("-0.05", Currency::Myr), Ok(ringgit(-5)));
+        assert_eq!(
+            Money::parse("1200", Currency::Jpy),
+            Ok(Money::new(1200, Currency::Jpy))
+        );
+        assert!(Money::parse("12.505", Currency::Myr).is_err());
+        assert!(Money::parse("12.5", Currency::Jpy).is_err());
+        assert!(Money::parse("1e3", Currency::Myr).is_err());
+        assert!(Money::parse(".5", Currency::Myr).is_err());
+        assert!(Money::parse("99999999999999999999", Currency::Myr).is_err());
+    }
+
+    #[test]
+    fn sums_nothing_to_zero() {
+        let total: Money = Vec::new().into_iter().sum();
+
+        assert_eq!(total, Money::zero(Currency::base()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't mix amounts of MYR and USD")]
+    fn panics_when_adding_different_currencies() {
+        let _ = ringgit(100) + Money::new(100, Currency::Usd);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't mix amounts of MYR and USD")]
+    fn panics_when_summing_different_currencies() {
+        let _: Money = [ringgit(100), Money::new(100, Currency::Usd)]
+            .into_iter()
+            .sum();
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't mix amounts of MYR and JPY")]
+    fn panics_when_comparing_different_currencies_with_min() {
+        let _ = ringgit(100).min(Money::new(100, Currency::Jpy));
+    }
+
+    #[tokio::test]
+    async fn new_databases_are_in_the_store_currency() {
+        let db_pool = crate::utils::testing::test_db_pool().await;
+
+        assert_eq!(check_store_currency(&db_pool).await, Ok(()));
+    }
+
+    #[test]
+    fn different_currencies_are_not_ordered() {
+        assert_eq!(
+            ringgit(100).partial_cmp(&Money::new(100, Currency::Usd)),
+            None
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The amount is too large")]
+    fn panics_when_adding_overflows() {
+        let _ = ringgit(i64::MAX) + ringgit(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "The amount is too large")]
+    fn panics_when_multiplying_overflows() {
+        let _ = ringgit(i64::MAX / 2) * 3;
+    }
+
+    #[test]
+    fn shows_the_smallest_amount() {
+        assert_eq!(
+            ringgit(i64::MIN).to_decimal_string(),
+            "-92233720368547758.08"
+        );
+    }
+}
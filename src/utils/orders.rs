@@ -0,0 +1,168 @@
+use axum::http::StatusCode;
+use chrono::Local;
+use sqlx::{Sqlite, Transaction};
+
+use crate::routes::map_db_error;
+use crate::utils::models::OrderStatus;
+
+// Moves an order to a new status if the state machine allows it, and records the change
+// in the order's history. It runs inside the caller's transaction so the status only
+// changes if everything else that goes with it does too.
+// Returns the status the order was in before.
+pub async fn change_status(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    new_status: OrderStatus,
+    changed_by: Option<&str>,
+    note: Option<&str>,
+) -> Result<OrderStatus, (StatusCode, String)> {
+    let order_option = sqlx::query!(
+        r#"SELECT order_status AS "order_status: OrderStatus" FROM orders WHERE order_id = $1"#,
+        order_id,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(order) = order_option else {
+        return Err((StatusCode::NOT_FOUND, "Order not found".to_owned()));
+    };
+
+    if !order.order_status.can_change_to(new_status) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!(
+                "A {:?} order can't be changed to {:?}",
+                order.order_status, new_status
+            ),
+        ));
+    }
+
+    // Only change the status if nobody else has changed it since it was read
+    let result = sqlx::query!(
+        "UPDATE orders SET order_status = $1 WHERE order_id = $2 AND order_status = $3",
+        new_status,
+        order_id,
+        order.order_status,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            "The order was changed by someone else, please try again".to_owned(),
+        ));
+    }
+
+    // Nothing in a cancelled order is going to be sent, so it all goes back into stock
+    if new_status == OrderStatus::Cancelled {
+        restock_order(transaction, order_id).await?;
+    }
+
+    let local_time_now = Local::now().naive_local();
+    sqlx::query!(
+        "
+        INSERT INTO order_status_history (order_id, from_status, to_status, changed_by, changed_at, note)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ",
+        order_id,
+        order.order_status,
+        new_status,
+        changed_by,
+        local_time_now,
+        note,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(order.order_status)
+}
+
+// Items that were refunded and put back into stock already aren't put back again
+async fn restock_order(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+) -> Result<(), (StatusCode, String)> {
+    sqlx::query!(
+        "
+        UPDATE product_variants
+        SET stock = stock + (
+            SELECT quantity FROM order_items
+            WHERE order_items.order_id = $1 AND order_items.variant_id = product_variants.variant_id
+        ) - (
+            SELECT COALESCE(SUM(refund_items.quantity), 0) FROM refund_items
+            INNER JOIN refunds ON refunds.refund_id = refund_items.refund_id
+            WHERE refund_items.order_id = $1
+            AND refund_items.variant_id = product_variants.variant_id
+            AND refunds.restocked
+        )
+        WHERE variant_id IN (SELECT variant_id FROM order_items WHERE order_id = $1)
+        ",
+        order_id,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::models::NewRefundItem;
+    use crate::utils::payments::FakePaymentGateway;
+    use crate::utils::refunds::{self, RefundRequest, RefundScope};
+    use crate::utils::testing;
+
+    #[tokio::test]
+    async fn cancelling_doesnt_restock_items_that_were_already_restocked() {
+        let db_pool = testing::test_db_pool().await;
+        let user_id = testing::create_user(&db_pool, &["customer"]).await;
+        let stock_before = testing::variant_stock(&db_pool, 1).await;
+        let (order_id, _) = testing::create_order(&db_pool, &user_id, 1, 3).await;
+
+        let mut transaction = db_pool.begin().await.unwrap();
+        change_status(&mut transaction, order_id, OrderStatus::Paid, None, None)
+            .await
+            .unwrap();
+
+        // One of them is sent back and put back into stock, another is refunded but kept
+        for restock in [true, false] {
+            let refund_request = RefundRequest {
+                scope: RefundScope::Items(vec![NewRefundItem {
+                    variant_id: 1,
+                    quantity: 1,
+                }]),
+                restock,
+                note: None,
+                return_id: None,
+            };
+            refunds::create_refund(
+                &FakePaymentGateway,
+                &mut transaction,
+                order_id,
+                refund_request,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+
+        change_status(
+            &mut transaction,
+            order_id,
+            OrderStatus::Cancelled,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        transaction.commit().await.unwrap();
+
+        assert_eq!(testing::variant_stock(&db_pool, 1).await, stock_before);
+    }
+}
@@ -0,0 +1,1310 @@
+use axum::{async_trait, http::StatusCode};
+use chrono::Local;
+use sqlx::{Pool, Sqlite, Transaction};
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use crate::routes::map_db_error;
+use crate::utils::models::{OrderStatus, PaymentCard, PaymentEvent, PaymentIntent, PaymentStatus};
+use crate::utils::money::Money;
+use crate::utils::orders;
+
+// Test card numbers that make the fake gateway act the way a real provider would.
+// Any other valid card number is authorized.
+pub const DECLINED_CARD: &str = "4000000000000002";
+pub const INSUFFICIENT_FUNDS_CARD: &str = "4000000000009995";
+pub const CHALLENGE_CARD: &str = "4000000000003220";
+
+// The code that passes the fake gateway's 3-D Secure challenge
+pub const CHALLENGE_CODE: &str = "123456";
+
+// How long an order can wait for its payment before it's cancelled and its stock is given back
+pub const UNPAID_ORDER_LIFETIME_MINUTES: i64 = 30;
+
+// How often the background task looks for unpaid orders that have expired
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub enum AuthorizeOutcome {
+    Authorized,
+    // The customer has to pass a 3-D Secure challenge, the message tells them how
+    ChallengeRequired(String),
+    Declined(String),
+}
+
+pub struct Authorization {
+    pub reference: String,
+    pub outcome: AuthorizeOutcome,
+}
+
+// The provider couldn't do what it was asked to, e.g. because it couldn't be reached
+#[derive(Debug)]
+pub struct PaymentError(pub String);
+
+// Takes card payments. Implement this for a real payment provider and hand it to create_router
+// instead of the fake gateway. Payments are authorized when an order is placed, captured when
+// it ships, and voided or refunded if it's cancelled.
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn authorize(
+        &self,
+        amount: Money,
+        card: &PaymentCard,
+    ) -> Result<Authorization, PaymentError>;
+
+    async fn confirm_challenge(
+        &self,
+        reference: &str,
+        code: &str,
+    ) -> Result<AuthorizeOutcome, PaymentError>;
+
+    async fn capture(&self, reference: &str, amount: Money) -> Result<(), PaymentError>;
+
+    async fn void(&self, reference: &str) -> Result<(), PaymentError>;
+
+    async fn refund(&self, reference: &str, amount: Money) -> Result<(), PaymentError>;
+}
+
+// A payment provider for development that never moves any real money
+pub struct FakePaymentGateway;
+
+#[async_trait]
+impl PaymentProvider for FakePaymentGateway {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    async fn authorize(
+        &self,
+        amount: Money,
+        card: &PaymentCard,
+    ) -> Result<Authorization, PaymentError> {
+        if !amount.is_positive() {
+            return Err(PaymentError("The amount must be more than zero".to_owned()));
+        }
+
+        let outcome = match card.number().as_str() {
+            DECLINED_CARD => AuthorizeOutcome::Declined("The card was declined".to_owned()),
+            INSUFFICIENT_FUNDS_CARD => {
+                AuthorizeOutcome::Declined("The card has insufficient funds".to_owned())
+            }
+            CHALLENGE_CARD => AuthorizeOutcome::ChallengeRequired(format!(
+                "Enter the code sent by your bank to confirm the payment. The test code is {}",
+                CHALLENGE_CODE
+            )),
+            _ => AuthorizeOutcome::Authorized,
+        };
+
+        Ok(Authorization {
+            reference: format!("fake_{}", Uuid::new_v4().simple()),
+            outcome,
+        })
+    }
+
+    async fn confirm_challenge(
+        &self,
+        _reference: &str,
+        code: &str,
+    ) -> Result<AuthorizeOutcome, PaymentError> {
+        match code.trim() == CHALLENGE_CODE {
+            true => Ok(AuthorizeOutcome::Authorized),
+            false => Ok(AuthorizeOutcome::Declined(
+                "The 3-D Secure challenge failed".to_owned(),
+            )),
+        }
+    }
+
+    async fn capture(&self, _reference: &str, amount: Money) -> Result<(), PaymentError> {
+        match amount.is_positive() {
+            true => Ok(()),
+            false => Err(PaymentError("The amount must be more than zero".to_owned())),
+        }
+    }
+
+    async fn void(&self, _reference: &str) -> Result<(), PaymentError> {
+        Ok(())
+    }
+
+    async fn refund(&self, _reference: &str, amount: Money) -> Result<(), PaymentError> {
+        match amount.is_positive() {
+            true => Ok(()),
+            false => Err(PaymentError("The amount must be more than zero".to_owned())),
+        }
+    }
+}
+
+// Records the result of authorizing the payment for a new order, then moves the order along to match
+pub async fn record_authorization(
+    db_pool: &Pool<Sqlite>,
+    payment_provider: &dyn PaymentProvider,
+    order_id: i64,
+    amount: Money,
+    card_last4: &str,
+    authorization: Result<Authorization, PaymentError>,
+) -> Result<PaymentIntent, (StatusCode, String)> {
+    let (provider_reference, outcome) = match authorization {
+        Ok(authorization) => (Some(authorization.reference), authorization.outcome),
+        // The payment can't go through if the provider can't be reached either
+        Err(PaymentError(message)) => (None, AuthorizeOutcome::Declined(message)),
+    };
+
+    let result = save_authorization(
+        db_pool,
+        payment_provider.name(),
+        order_id,
+        amount,
+        card_last4,
+        provider_reference.as_deref(),
+        &outcome,
+    )
+    .await;
+
+    // Nothing here knows about the payment if it couldn't be saved, so the money would stay
+    // held on the card. The order is left pending until it expires and gives back its stock.
+    if result.is_err() && !matches!(outcome, AuthorizeOutcome::Declined(_)) {
+        if let Some(reference) = &provider_reference {
+            if let Err(PaymentError(message)) = payment_provider.void(reference).await {
+                eprintln!("Unable to void payment {}: {}", reference, message);
+            }
+        }
+    }
+
+    result
+}
+
+async fn save_authorization(
+    db_pool: &Pool<Sqlite>,
+    provider_name: &str,
+    order_id: i64,
+    amount: Money,
+    card_last4: &str,
+    provider_reference: Option<&str>,
+    outcome: &AuthorizeOutcome,
+) -> Result<PaymentIntent, (StatusCode, String)> {
+    let (payment_status, next_action, failure_message) = outcome_columns(outcome);
+    let local_time_now = Local::now().naive_local();
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    let payment_intent_id = sqlx::query!(
+        "
+        INSERT INTO payment_intents
+        (order_id, provider, provider_reference, payment_status, amount, card_last4, next_action, failure_message, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9)
+        RETURNING payment_intent_id
+        ",
+        order_id,
+        provider_name,
+        provider_reference,
+        payment_status,
+        amount,
+        card_last4,
+        next_action,
+        failure_message,
+        local_time_now,
+    )
+    .fetch_one(&mut transaction)
+    .await
+    .map_err(map_db_error)?
+    .payment_intent_id;
+
+    update_order(&mut transaction, order_id, outcome).await?;
+    let payment_intent = get_intent(&mut transaction, payment_intent_id).await?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok(payment_intent)
+}
+
+// Records the result of a 3-D Secure challenge, then moves the order along to match
+pub async fn record_challenge_result(
+    db_pool: &Pool<Sqlite>,
+    payment_intent: &PaymentIntent,
+    outcome: AuthorizeOutcome,
+) -> Result<PaymentIntent, (StatusCode, String)> {
+    let (payment_status, next_action, failure_message) = outcome_columns(&outcome);
+    let local_time_now = Local::now().naive_local();
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    // Only one attempt at the challenge counts, even if the customer sends it twice
+    let result = sqlx::query!(
+        "
+        UPDATE payment_intents
+        SET payment_status = $1, next_action = $2, failure_message = $3, updated_at = $4
+        WHERE payment_intent_id = $5 AND payment_status = $6
+        ",
+        payment_status,
+        next_action,
+        failure_message,
+        local_time_now,
+        payment_intent.payment_intent_id,
+        PaymentStatus::RequiresAction,
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            "The payment has already been confirmed".to_owned(),
+        ));
+    }
+
+    update_order(&mut transaction, payment_intent.order_id, &outcome).await?;
+    let payment_intent = get_intent(&mut transaction, payment_intent.payment_intent_id).await?;
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok(payment_intent)
+}
+
+// Moves the money for an order that has just changed status. Authorized payments are captured
+// when the order ships, and voided if it's cancelled or refunded before then. Captured payments
+// are paid back in full when the whole order is cancelled or refunded.
+// The provider is called before the caller's transaction is committed, so a failure leaves the
+// order as it was.
+pub async fn settle_for_status(
+    payment_provider: &dyn PaymentProvider,
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    new_status: OrderStatus,
+) -> Result<(), (StatusCode, String)> {
+    let Some(payment_intent) = find_active_intent(transaction, order_id).await? else {
+        return Ok(());
+    };
+    let provider_reference = payment_intent
+        .provider_reference
+        .as_deref()
+        .unwrap_or_default();
+    let local_time_now = Local::now().naive_local();
+
+    match (new_status, payment_intent.payment_status) {
+        (OrderStatus::Shipped, PaymentStatus::Authorized) => {
+            // Anything refunded before the order shipped is never captured
+            let amount_refunded = sqlx::query!(
+                r#"SELECT amount_refunded AS "amount_refunded: Money" FROM orders WHERE order_id = $1"#,
+                order_id,
+            )
+            .fetch_one(&mut *transaction)
+            .await
+            .map_err(map_db_error)?
+            .amount_refunded;
+            let amount = payment_intent.amount - amount_refunded;
+
+            payment_provider
+                .capture(provider_reference, amount)
+                .await
+                .map_err(map_provider_error)?;
+
+            sqlx::query!(
+                "
+                UPDATE payment_intents
+                SET payment_status = $1, amount_captured = $2, updated_at = $3
+                WHERE payment_intent_id = $4
+                ",
+                PaymentStatus::Captured,
+                amount,
+                local_time_now,
+                payment_intent.payment_intent_id,
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_db_error)?;
+        }
+        (OrderStatus::Cancelled | OrderStatus::Refunded, PaymentStatus::Authorized) => {
+            payment_provider
+                .void(provider_reference)
+                .await
+                .map_err(map_provider_error)?;
+
+            sqlx::query!(
+                "UPDATE payment_intents SET payment_status = $1, updated_at = $2 WHERE payment_intent_id = $3",
+                PaymentStatus::Voided,
+                local_time_now,
+                payment_intent.payment_intent_id,
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_db_error)?;
+        }
+        (OrderStatus::Cancelled | OrderStatus::Refunded, PaymentStatus::Captured) => {
+            let remaining = payment_intent.amount_captured - payment_intent.amount_refunded;
+            refund_payment(payment_provider, transaction, order_id, remaining).await?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+// Pays back some of what was captured for an order, e.g. for items that were returned.
+// Nothing is paid back if the payment was never captured.
+// Returns how much was refunded, which is never more than what is left of the payment.
+pub async fn refund_payment(
+    payment_provider: &dyn PaymentProvider,
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    amount: Money,
+) -> Result<Money, (StatusCode, String)> {
+    let Some(payment_intent) = find_active_intent(transaction, order_id).await? else {
+        return Ok(Money::zero(amount.currency()));
+    };
+    if payment_intent.payment_status != PaymentStatus::Captured {
+        return Ok(Money::zero(amount.currency()));
+    }
+
+    let remaining = payment_intent.amount_captured - payment_intent.amount_refunded;
+    let amount = amount.min(remaining);
+    if !amount.is_positive() {
+        return Ok(Money::zero(amount.currency()));
+    }
+
+    payment_provider
+        .refund(
+            payment_intent
+                .provider_reference
+                .as_deref()
+                .unwrap_or_default(),
+            amount,
+        )
+        .await
+        .map_err(map_provider_error)?;
+
+    let local_time_now = Local::now().naive_local();
+    let payment_status = match amount < remaining {
+        true => PaymentStatus::Captured,
+        false => PaymentStatus::Refunded,
+    };
+    sqlx::query!(
+        "
+        UPDATE payment_intents
+        SET amount_refunded = amount_refunded + $1, payment_status = $2, updated_at = $3
+        WHERE payment_intent_id = $4
+        ",
+        amount,
+        payment_status,
+        local_time_now,
+        payment_intent.payment_intent_id,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(amount)
+}
+
+// Applies a webhook event from the provider. Each event is only applied once however many times
+// it's sent, and events the payment has already moved past, such as a capture that has already
+// been recorded, are ignored. Events that arrive before the payment is ready for them, including
+// ones for payments that haven't been recorded yet, are turned away without being recorded so
+// the provider sends them again later.
+// Returns false if the event had already been processed.
+pub async fn process_event(
+    db_pool: &Pool<Sqlite>,
+    provider_name: &str,
+    event: &PaymentEvent,
+) -> Result<bool, (StatusCode, String)> {
+    let local_time_now = Local::now().naive_local();
+
+    let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+    let payment_intent = sqlx::query!(
+        r#"
+        SELECT
+        payment_intent_id,
+        order_id,
+        payment_status AS "payment_status: PaymentStatus",
+        amount AS "amount: Money",
+        amount_captured AS "amount_captured: Money",
+        amount_refunded AS "amount_refunded: Money"
+        FROM payment_intents
+        WHERE provider = $1 AND provider_reference = $2
+        "#,
+        provider_name,
+        event.data.reference,
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(payment_intent) = payment_intent else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "No payment matches the event yet".to_owned(),
+        ));
+    };
+
+    // Nothing is recorded, so the provider can send it again once it's fixed
+    if let Some(amount) = event.data.amount {
+        if amount.currency() != payment_intent.amount.currency() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "The amount is in {} but the payment is in {}",
+                    amount.currency(),
+                    payment_intent.amount.currency()
+                ),
+            ));
+        }
+    }
+
+    // The event is recorded in the same transaction as its changes, so if anything fails
+    // it's processed again when the provider retries it
+    let insert_result = sqlx::query!(
+        "
+        INSERT INTO processed_webhook_events (provider, event_id, event_type, payment_intent_id, processed_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (provider, event_id) DO NOTHING
+        ",
+        provider_name,
+        event.id,
+        event.event_type,
+        payment_intent.payment_intent_id,
+        local_time_now,
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    if insert_result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    // The outcome is set for events that finish a payment that was waiting on a challenge,
+    // as the order then needs to be paid or cancelled too
+    let (outcome, mut new_status) = match (event.event_type.as_str(), payment_intent.payment_status)
+    {
+        ("payment.authorized", PaymentStatus::RequiresAction) => (
+            Some(AuthorizeOutcome::Authorized),
+            PaymentStatus::Authorized,
+        ),
+        ("payment.failed", PaymentStatus::RequiresAction) => {
+            let message = event
+                .data
+                .failure_message
+                .clone()
+                .unwrap_or_else(|| "The payment failed".to_owned());
+            (
+                Some(AuthorizeOutcome::Declined(message)),
+                PaymentStatus::Declined,
+            )
+        }
+        ("payment.captured", PaymentStatus::Authorized) => (None, PaymentStatus::Captured),
+        ("payment.voided", PaymentStatus::Authorized) => (None, PaymentStatus::Voided),
+        ("payment.refunded", PaymentStatus::Captured) => (None, PaymentStatus::Captured),
+        // e.g. a capture sent before the authorization it follows
+        ("payment.captured" | "payment.voided", PaymentStatus::RequiresAction)
+        | ("payment.refunded", PaymentStatus::RequiresAction | PaymentStatus::Authorized) => {
+            return Err((
+                StatusCode::CONFLICT,
+                "The payment isn't ready for the event yet".to_owned(),
+            ));
+        }
+        _ => {
+            transaction.commit().await.map_err(map_db_error)?;
+            return Ok(true);
+        }
+    };
+
+    let failure_message = match &outcome {
+        Some(AuthorizeOutcome::Declined(message)) => Some(message.as_str()),
+        _ => None,
+    };
+    let mut amount_captured = payment_intent.amount_captured;
+    let mut amount_refunded = payment_intent.amount_refunded;
+    match event.event_type.as_str() {
+        "payment.captured" => {
+            amount_captured = event.data.amount.unwrap_or(payment_intent.amount);
+        }
+        // Refund events carry the total refunded so far, which includes any refunds we made
+        // ourselves, so the amount refunded only ever goes up
+        "payment.refunded" => {
+            amount_refunded = amount_refunded.max(event.data.amount.unwrap_or(amount_captured));
+            if amount_refunded >= amount_captured {
+                new_status = PaymentStatus::Refunded;
+            }
+        }
+        _ => {}
+    }
+
+    // Only move the payment along if nothing else has since, e.g. the customer confirming
+    // the payment at the same time as the provider telling us about it
+    let update_result = sqlx::query!(
+        "
+        UPDATE payment_intents
+        SET payment_status = $1,
+            amount_captured = $2,
+            amount_refunded = $3,
+            next_action = NULL,
+            failure_message = $4,
+            updated_at = $5
+        WHERE payment_intent_id = $6 AND payment_status = $7
+        ",
+        new_status,
+        amount_captured,
+        amount_refunded,
+        failure_message,
+        local_time_now,
+        payment_intent.payment_intent_id,
+        payment_intent.payment_status,
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    if update_result.rows_affected() == 1 {
+        if let Some(outcome) = outcome {
+            update_order(&mut transaction, payment_intent.order_id, &outcome).await?;
+        }
+
+        match new_status {
+            PaymentStatus::Voided => {
+                follow_payment(
+                    &mut transaction,
+                    payment_intent.order_id,
+                    OrderStatus::Cancelled,
+                    "Payment voided by the provider",
+                )
+                .await?;
+            }
+            PaymentStatus::Captured | PaymentStatus::Refunded
+                if amount_refunded > payment_intent.amount_refunded =>
+            {
+                // Refunds made here have already been recorded by the time the provider tells
+                // us about them, so only what was refunded some other way is recorded
+                let amount = amount_refunded - payment_intent.amount_refunded;
+                sqlx::query!(
+                    "
+                    INSERT INTO refunds (order_id, amount, note, created_at)
+                    VALUES ($1, $2, $3, $4)
+                    ",
+                    payment_intent.order_id,
+                    amount,
+                    "Refunded through the payment provider",
+                    local_time_now,
+                )
+                .execute(&mut transaction)
+                .await
+                .map_err(map_db_error)?;
+
+                if new_status == PaymentStatus::Refunded {
+                    follow_payment(
+                        &mut transaction,
+                        payment_intent.order_id,
+                        OrderStatus::Refunded,
+                        "Payment refunded by the provider",
+                    )
+                    .await?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    transaction.commit().await.map_err(map_db_error)?;
+
+    Ok(true)
+}
+
+// A payment for one of the user's orders
+pub async fn find_user_intent(
+    db_pool: &Pool<Sqlite>,
+    payment_intent_id: i64,
+    user_id: &str,
+) -> Result<Option<PaymentIntent>, sqlx::Error> {
+    sqlx::query_as!(
+        PaymentIntent,
+        r#"
+        SELECT
+        payment_intents.payment_intent_id,
+        payment_intents.order_id,
+        payment_intents.provider,
+        payment_intents.provider_reference,
+        payment_intents.payment_status AS "payment_status: PaymentStatus",
+        payment_intents.amount AS "amount: Money",
+        payment_intents.amount_captured AS "amount_captured: Money",
+        payment_intents.amount_refunded AS "amount_refunded: Money",
+        payment_intents.card_last4,
+        payment_intents.next_action,
+        payment_intents.failure_message,
+        payment_intents.created_at,
+        payment_intents.updated_at
+        FROM payment_intents
+        INNER JOIN orders ON orders.order_id = payment_intents.order_id
+        WHERE payment_intents.payment_intent_id = $1 AND orders.user_id = $2
+        "#,
+        payment_intent_id,
+        user_id,
+    )
+    .fetch_optional(db_pool)
+    .await
+}
+
+fn outcome_columns(outcome: &AuthorizeOutcome) -> (PaymentStatus, Option<&str>, Option<&str>) {
+    match outcome {
+        AuthorizeOutcome::Authorized => (PaymentStatus::Authorized, None, None),
+        AuthorizeOutcome::ChallengeRequired(next_action) => {
+            (PaymentStatus::RequiresAction, Some(next_action), None)
+        }
+        AuthorizeOutcome::Declined(message) => (PaymentStatus::Declined, None, Some(message)),
+    }
+}
+
+// Authorized orders are paid for, so the cart they came from is emptied.
+// Declined orders are cancelled, which puts their stock back.
+async fn update_order(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    outcome: &AuthorizeOutcome,
+) -> Result<(), (StatusCode, String)> {
+    match outcome {
+        AuthorizeOutcome::Authorized => {
+            orders::change_status(
+                transaction,
+                order_id,
+                OrderStatus::Paid,
+                None,
+                Some("Payment authorized"),
+            )
+            .await?;
+
+            sqlx::query!(
+                "
+                DELETE FROM cart_items WHERE cart_id IN (
+                    SELECT carts.cart_id FROM carts
+                    INNER JOIN orders ON orders.user_id = carts.user_id
+                    WHERE orders.order_id = $1
+                )
+                ",
+                order_id,
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_db_error)?;
+        }
+        AuthorizeOutcome::ChallengeRequired(_) => {}
+        AuthorizeOutcome::Declined(message) => {
+            orders::change_status(
+                transaction,
+                order_id,
+                OrderStatus::Cancelled,
+                None,
+                Some(&format!("Payment declined: {}", message)),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Moves the order along after the provider changed its payment without being asked to,
+// unless the order has already moved on, e.g. it was cancelled here first
+async fn follow_payment(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    new_status: OrderStatus,
+    note: &str,
+) -> Result<(), (StatusCode, String)> {
+    let order_status = sqlx::query!(
+        r#"SELECT order_status AS "order_status: OrderStatus" FROM orders WHERE order_id = $1"#,
+        order_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(map_db_error)?
+    .order_status;
+
+    if order_status.can_change_to(new_status) {
+        orders::change_status(transaction, order_id, new_status, None, Some(note)).await?;
+    }
+
+    Ok(())
+}
+
+async fn get_intent(
+    transaction: &mut Transaction<'_, Sqlite>,
+    payment_intent_id: i64,
+) -> Result<PaymentIntent, (StatusCode, String)> {
+    sqlx::query_as!(
+        PaymentIntent,
+        r#"
+        SELECT
+        payment_intent_id,
+        order_id,
+        provider,
+        provider_reference,
+        payment_status AS "payment_status: PaymentStatus",
+        amount AS "amount: Money",
+        amount_captured AS "amount_captured: Money",
+        amount_refunded AS "amount_refunded: Money",
+        card_last4,
+        next_action,
+        failure_message,
+        created_at,
+        updated_at
+        FROM payment_intents
+        WHERE payment_intent_id = $1
+        "#,
+        payment_intent_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(map_db_error)
+}
+
+// The payment that is holding or has taken the customer's money for the order, if there is one
+async fn find_active_intent(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+) -> Result<Option<PaymentIntent>, (StatusCode, String)> {
+    sqlx::query_as!(
+        PaymentIntent,
+        r#"
+        SELECT
+        payment_intent_id,
+        order_id,
+        provider,
+        provider_reference,
+        payment_status AS "payment_status: PaymentStatus",
+        amount AS "amount: Money",
+        amount_captured AS "amount_captured: Money",
+        amount_refunded AS "amount_refunded: Money",
+        card_last4,
+        next_action,
+        failure_message,
+        created_at,
+        updated_at
+        FROM payment_intents
+        WHERE order_id = $1 AND payment_status IN ('Authorized', 'Captured')
+        ORDER BY payment_intent_id DESC
+        "#,
+        order_id,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_db_error)
+}
+
+fn map_provider_error(PaymentError(message): PaymentError) -> (StatusCode, String) {
+    (
+        StatusCode::BAD_GATEWAY,
+        format!(
+            "The payment provider couldn't process the request: {}",
+            message
+        ),
+    )
+}
+
+// Cancels orders that are still waiting for their payment after UNPAID_ORDER_LIFETIME_MINUTES,
+// e.g. because the customer never finished the 3-D Secure challenge or the authorization
+// couldn't be saved. Returns how many were cancelled.
+pub async fn expire_unpaid_orders(
+    db_pool: &Pool<Sqlite>,
+    payment_provider: &dyn PaymentProvider,
+) -> Result<u64, (StatusCode, String)> {
+    let cutoff =
+        Local::now().naive_local() - chrono::Duration::minutes(UNPAID_ORDER_LIFETIME_MINUTES);
+
+    let unpaid_orders = sqlx::query!(
+        "
+        SELECT orders.order_id, payment_intents.payment_intent_id, payment_intents.provider_reference
+        FROM orders
+        LEFT JOIN payment_intents ON payment_intents.order_id = orders.order_id
+            AND payment_intents.payment_status = $1
+        WHERE orders.order_status = $2 AND orders.creation_time <= $3
+        ",
+        PaymentStatus::RequiresAction,
+        OrderStatus::Pending,
+        cutoff,
+    )
+    .fetch_all(db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let mut expired = 0;
+    for unpaid_order in unpaid_orders {
+        // The provider may still authorize the payment if the challenge is finished late,
+        // so it's voided first. It's tried again next time if the provider can't be reached.
+        if let Some(reference) = &unpaid_order.provider_reference {
+            if let Err(PaymentError(message)) = payment_provider.void(reference).await {
+                eprintln!("Unable to void payment {}: {}", reference, message);
+                continue;
+            }
+        }
+
+        let mut transaction = db_pool.begin().await.map_err(map_db_error)?;
+
+        let local_time_now = Local::now().naive_local();
+        sqlx::query!(
+            "
+            UPDATE payment_intents SET payment_status = $1, next_action = NULL, updated_at = $2
+            WHERE payment_intent_id = $3 AND payment_status = $4
+            ",
+            PaymentStatus::Voided,
+            local_time_now,
+            unpaid_order.payment_intent_id,
+            PaymentStatus::RequiresAction,
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(map_db_error)?;
+
+        // The order may have been paid for or cancelled since it was read
+        match orders::change_status(
+            &mut transaction,
+            unpaid_order.order_id,
+            OrderStatus::Cancelled,
+            None,
+            Some("Payment not completed in time"),
+        )
+        .await
+        {
+            Ok(_) => {
+                transaction.commit().await.map_err(map_db_error)?;
+                expired += 1;
+            }
+            Err((_, message)) => {
+                eprintln!(
+                    "Unable to expire order {}: {}",
+                    unpaid_order.order_id, message
+                );
+            }
+        }
+    }
+
+    Ok(expired)
+}
+
+// Unpaid orders would otherwise hold on to their stock forever
+pub fn spawn_expiry_task(db_pool: Pool<Sqlite>, payment_provider: Arc<dyn PaymentProvider>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err((_, message)) =
+                expire_unpaid_orders(&db_pool, payment_provider.as_ref()).await
+            {
+                eprintln!("Unable to expire unpaid orders: {}", message);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::utils::testing::{self, sign_webhook};
+    use crate::utils::webhooks::verify_signature;
+
+    const SECRET: &str = "testsecret";
+
+    // One of the recorded events in dev/webhooks, for the payment with the given reference
+    fn recorded_event(file_name: &str, reference: &str) -> String {
+        let path = format!("{}/dev/webhooks/{}", env!("CARGO_MANIFEST_DIR"), file_name);
+        std::fs::read_to_string(path)
+            .unwrap()
+            .replace("{{reference}}", reference)
+    }
+
+    // Handles the event the way the webhook endpoint does
+    async fn deliver(
+        db_pool: &Pool<Sqlite>,
+        body: &str,
+        signature_header: &str,
+    ) -> Result<bool, (StatusCode, String)> {
+        verify_signature(
+            SECRET,
+            signature_header,
+            body.as_bytes(),
+            Utc::now().timestamp(),
+        )
+        .map_err(|error| (StatusCode::UNAUTHORIZED, error))?;
+
+        let event: PaymentEvent = serde_json::from_str(body).unwrap();
+        process_event(db_pool, FakePaymentGateway.name(), &event).await
+    }
+
+    async fn deliver_signed(
+        db_pool: &Pool<Sqlite>,
+        body: &str,
+    ) -> Result<bool, (StatusCode, String)> {
+        let timestamp = Utc::now().timestamp().to_string();
+        deliver(
+            db_pool,
+            body,
+            &sign_webhook(SECRET, &timestamp, body.as_bytes()),
+        )
+        .await
+    }
+
+    // An order for one of the first variant, paid for with the given card
+    async fn place_order(db_pool: &Pool<Sqlite>, card_number: &str) -> (i64, String) {
+        let user_id = testing::create_user(db_pool, &["customer"]).await;
+        let (order_id, total_cost) = testing::create_order(db_pool, &user_id, 1, 1).await;
+
+        let card: PaymentCard = serde_json::from_value(serde_json::json!({
+            "card_number": card_number,
+            "expiry_month": 12,
+            "expiry_year": 2099,
+            "cvc": "123",
+        }))
+        .unwrap();
+        let authorization = FakePaymentGateway.authorize(total_cost, &card).await;
+        let payment_intent = record_authorization(
+            db_pool,
+            &FakePaymentGateway,
+            order_id,
+            total_cost,
+            "0000",
+            authorization,
+        )
+        .await
+        .unwrap();
+
+        (order_id, payment_intent.provider_reference.unwrap())
+    }
+
+    async fn payment_status(db_pool: &Pool<Sqlite>, order_id: i64) -> PaymentStatus {
+        sqlx::query_scalar!(
+            r#"SELECT payment_status AS "payment_status: PaymentStatus" FROM payment_intents WHERE order_id = $1"#,
+            order_id,
+        )
+        .fetch_one(db_pool)
+        .await
+        .unwrap()
+    }
+
+    async fn processed_events(db_pool: &Pool<Sqlite>) -> i64 {
+        sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!: i64" FROM processed_webhook_events"#)
+            .fetch_one(db_pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn authorizes_a_challenged_payment() {
+        let db_pool = testing::test_db_pool().await;
+        let (order_id, reference) = place_order(&db_pool, CHALLENGE_CARD).await;
+        assert_eq!(
+            testing::order_status(&db_pool, order_id).await,
+            OrderStatus::Pending
+        );
+
+        let body = recorded_event("payment-authorized.json", &reference);
+        assert_eq!(deliver_signed(&db_pool, &body).await, Ok(true));
+
+        assert_eq!(
+            payment_status(&db_pool, order_id).await,
+            PaymentStatus::Authorized
+        );
+        assert_eq!(
+            testing::order_status(&db_pool, order_id).await,
+            OrderStatus::Paid
+        );
+    }
+
+    #[tokio::test]
+    async fn cancels_the_order_when_a_challenged_payment_fails() {
+        let db_pool = testing::test_db_pool().await;
+        let (order_id, reference) = place_order(&db_pool, CHALLENGE_CARD).await;
+        let stock = testing::variant_stock(&db_pool, 1).await;
+
+        let body = recorded_event("payment-failed.json", &reference);
+        assert_eq!(deliver_signed(&db_pool, &body).await, Ok(true));
+
+        assert_eq!(
+            payment_status(&db_pool, order_id).await,
+            PaymentStatus::Declined
+        );
+        assert_eq!(
+            testing::order_status(&db_pool, order_id).await,
+            OrderStatus::Cancelled
+        );
+        assert_eq!(testing::variant_stock(&db_pool, 1).await, stock + 1);
+    }
+
+    #[tokio::test]
+    async fn applies_a_duplicate_delivery_once() {
+        let db_pool = testing::test_db_pool().await;
+        let (order_id, reference) = place_order(&db_pool, CHALLENGE_CARD).await;
+
+        let body = recorded_event("payment-authorized.json", &reference);
+        assert_eq!(deliver_signed(&db_pool, &body).await, Ok(true));
+        assert_eq!(deliver_signed(&db_pool, &body).await, Ok(false));
+
+        let times_paid = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!: i64" FROM order_status_history WHERE order_id = $1 AND to_status = 'Paid'"#,
+            order_id,
+        )
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+        assert_eq!(times_paid, 1);
+        assert_eq!(processed_events(&db_pool).await, 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_and_stale_signatures() {
+        let db_pool = testing::test_db_pool().await;
+        let (order_id, reference) = place_order(&db_pool, CHALLENGE_CARD).await;
+        let body = recorded_event("payment-authorized.json", &reference);
+        let now = Utc::now().timestamp();
+
+        let wrong_secret = sign_webhook("someothersecret", &now.to_string(), body.as_bytes());
+        let stale = sign_webhook(SECRET, &(now - 60 * 60).to_string(), body.as_bytes());
+        for signature_header in [wrong_secret, stale] {
+            let result = deliver(&db_pool, &body, &signature_header).await;
+            assert_eq!(result.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        }
+
+        assert_eq!(
+            payment_status(&db_pool, order_id).await,
+            PaymentStatus::RequiresAction
+        );
+        assert_eq!(processed_events(&db_pool).await, 0);
+    }
+
+    #[tokio::test]
+    async fn asks_for_an_early_event_to_be_sent_again() {
+        let db_pool = testing::test_db_pool().await;
+        let (order_id, reference) = place_order(&db_pool, CHALLENGE_CARD).await;
+        let captured = recorded_event("payment-captured.json", &reference);
+
+        // The capture arrives before the authorization it follows
+        let result = deliver_signed(&db_pool, &captured).await;
+        assert_eq!(result.unwrap_err().0, StatusCode::CONFLICT);
+        assert_eq!(processed_events(&db_pool).await, 0);
+
+        let authorized = recorded_event("payment-authorized.json", &reference);
+        assert_eq!(deliver_signed(&db_pool, &authorized).await, Ok(true));
+        assert_eq!(deliver_signed(&db_pool, &captured).await, Ok(true));
+
+        assert_eq!(
+            payment_status(&db_pool, order_id).await,
+            PaymentStatus::Captured
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_an_event_the_payment_has_moved_past() {
+        let db_pool = testing::test_db_pool().await;
+        let (order_id, reference) = place_order(&db_pool, "4242424242424242").await;
+
+        let body = recorded_event("payment-failed.json", &reference);
+        assert_eq!(deliver_signed(&db_pool, &body).await, Ok(true));
+
+        assert_eq!(
+            payment_status(&db_pool, order_id).await,
+            PaymentStatus::Authorized
+        );
+        assert_eq!(
+            testing::order_status(&db_pool, order_id).await,
+            OrderStatus::Paid
+        );
+    }
+
+    #[tokio::test]
+    async fn asks_for_an_event_for_an_unknown_payment_to_be_sent_again() {
+        let db_pool = testing::test_db_pool().await;
+
+        let body = recorded_event("payment-authorized.json", "fake_notrecordedyet");
+        let result = deliver_signed(&db_pool, &body).await;
+
+        assert_eq!(result.unwrap_err().0, StatusCode::NOT_FOUND);
+        assert_eq!(processed_events(&db_pool).await, 0);
+    }
+
+    #[tokio::test]
+    async fn cancels_the_order_when_the_payment_is_voided() {
+        let db_pool = testing::test_db_pool().await;
+        let (order_id, reference) = place_order(&db_pool, "4242424242424242").await;
+        let stock = testing::variant_stock(&db_pool, 1).await;
+
+        let body = recorded_event("payment-voided.json", &reference);
+        assert_eq!(deliver_signed(&db_pool, &body).await, Ok(true));
+
+        assert_eq!(
+            payment_status(&db_pool, order_id).await,
+            PaymentStatus::Voided
+        );
+        assert_eq!(
+            testing::order_status(&db_pool, order_id).await,
+            OrderStatus::Cancelled
+        );
+        assert_eq!(testing::variant_stock(&db_pool, 1).await, stock + 1);
+    }
+
+    #[tokio::test]
+    async fn records_refunds_made_through_the_provider() {
+        let db_pool = testing::test_db_pool().await;
+        let (order_id, reference) = place_order(&db_pool, "4242424242424242").await;
+        let captured = recorded_event("payment-captured.json", &reference);
+        assert_eq!(deliver_signed(&db_pool, &captured).await, Ok(true));
+
+        // RM 10.00 of the order is refunded
+        let partial = recorded_event("payment-refunded.json", &reference);
+        assert_eq!(deliver_signed(&db_pool, &partial).await, Ok(true));
+        assert_eq!(
+            payment_status(&db_pool, order_id).await,
+            PaymentStatus::Captured
+        );
+        assert_eq!(
+            testing::order_status(&db_pool, order_id).await,
+            OrderStatus::Paid
+        );
+
+        // Then the rest of it
+        let order = sqlx::query!(
+            r#"SELECT total_cost AS "total_cost!: Money", amount_refunded AS "amount_refunded: Money" FROM orders WHERE order_id = $1"#,
+            order_id,
+        )
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            order.amount_refunded,
+            Money::from_major(10, order.total_cost.currency())
+        );
+
+        let mut full: serde_json::Value = serde_json::from_str(&partial).unwrap();
+        full["id"] = "evt_refunded_in_full".into();
+        full["data"]["amount"] = serde_json::to_value(order.total_cost).unwrap();
+        assert_eq!(deliver_signed(&db_pool, &full.to_string()).await, Ok(true));
+
+        assert_eq!(
+            payment_status(&db_pool, order_id).await,
+            PaymentStatus::Refunded
+        );
+        assert_eq!(
+            testing::order_status(&db_pool, order_id).await,
+            OrderStatus::Refunded
+        );
+        let amount_refunded = sqlx::query_scalar!(
+            r#"SELECT amount_refunded AS "amount_refunded: Money" FROM orders WHERE order_id = $1"#,
+            order_id,
+        )
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+        assert_eq!(amount_refunded, order.total_cost);
+    }
+
+    // Remembers which payments it was asked to void, and otherwise acts like the fake gateway
+    #[derive(Default)]
+    struct VoidRecorder(std::sync::Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl PaymentProvider for VoidRecorder {
+        fn name(&self) -> &'static str {
+            FakePaymentGateway.name()
+        }
+
+        async fn authorize(
+            &self,
+            amount: Money,
+            card: &PaymentCard,
+        ) -> Result<Authorization, PaymentError> {
+            FakePaymentGateway.authorize(amount, card).await
+        }
+
+        async fn confirm_challenge(
+            &self,
+            reference: &str,
+            code: &str,
+        ) -> Result<AuthorizeOutcome, PaymentError> {
+            FakePaymentGateway.confirm_challenge(reference, code).await
+        }
+
+        async fn capture(&self, reference: &str, amount: Money) -> Result<(), PaymentError> {
+            FakePaymentGateway.capture(reference, amount).await
+        }
+
+        async fn void(&self, reference: &str) -> Result<(), PaymentError> {
+            self.0.lock().unwrap().push(reference.to_owned());
+            Ok(())
+        }
+
+        async fn refund(&self, reference: &str, amount: Money) -> Result<(), PaymentError> {
+            FakePaymentGateway.refund(reference, amount).await
+        }
+    }
+
+    async fn backdate_order(db_pool: &Pool<Sqlite>, order_id: i64) {
+        let creation_time = Local::now().naive_local()
+            - chrono::Duration::minutes(UNPAID_ORDER_LIFETIME_MINUTES + 1);
+        sqlx::query!(
+            "UPDATE orders SET creation_time = $1 WHERE order_id = $2",
+            creation_time,
+            order_id,
+        )
+        .execute(db_pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_authorization_that_cant_be_saved_is_voided() {
+        let db_pool = testing::test_db_pool().await;
+        let provider = VoidRecorder::default();
+
+        let authorization = Ok(Authorization {
+            reference: "fake_unsaved".to_owned(),
+            outcome: AuthorizeOutcome::Authorized,
+        });
+        let amount = Money::from_major(10, crate::utils::money::Currency::base());
+        let result =
+            record_authorization(&db_pool, &provider, 404, amount, "0000", authorization).await;
+
+        assert!(result.is_err());
+        assert_eq!(*provider.0.lock().unwrap(), vec!["fake_unsaved".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn unpaid_orders_expire_and_give_back_their_stock() {
+        let db_pool = testing::test_db_pool().await;
+        let provider = VoidRecorder::default();
+        let stock_before = testing::variant_stock(&db_pool, 1).await;
+
+        let (challenged_order_id, reference) = place_order(&db_pool, CHALLENGE_CARD).await;
+        let (paid_order_id, _) = place_order(&db_pool, "4242424242424242").await;
+        let (recent_order_id, _) = place_order(&db_pool, CHALLENGE_CARD).await;
+        let user_id = testing::create_user(&db_pool, &["customer"]).await;
+        let (unrecorded_order_id, _) = testing::create_order(&db_pool, &user_id, 1, 1).await;
+
+        backdate_order(&db_pool, challenged_order_id).await;
+        backdate_order(&db_pool, paid_order_id).await;
+        backdate_order(&db_pool, unrecorded_order_id).await;
+
+        assert_eq!(expire_unpaid_orders(&db_pool, &provider).await, Ok(2));
+
+        assert_eq!(*provider.0.lock().unwrap(), vec![reference]);
+        assert_eq!(
+            payment_status(&db_pool, challenged_order_id).await,
+            PaymentStatus::Voided
+        );
+        for (order_id, order_status) in [
+            (challenged_order_id, OrderStatus::Cancelled),
+            (unrecorded_order_id, OrderStatus::Cancelled),
+            (paid_order_id, OrderStatus::Paid),
+            (recent_order_id, OrderStatus::Pending),
+        ] {
+            assert_eq!(
+                testing::order_status(&db_pool, order_id).await,
+                order_status
+            );
+        }
+        // Only the paid and the recent order still have their stock
+        assert_eq!(testing::variant_stock(&db_pool, 1).await, stock_before - 2);
+
+        // Nothing is left to expire
+        assert_eq!(expire_unpaid_orders(&db_pool, &provider).await, Ok(0));
+    }
+}
@@ -0,0 +1,82 @@
+use axum::{
+    extract::{Extension, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use sqlx::{Pool, Sqlite};
+
+use crate::utils::auth::AuthUser;
+
+// Mirrors the permissions table. Roles are granted permissions in the role_permissions table.
+// Each permission lets the role manage one part of the store, e.g. Permission::Orders is
+// manage_orders in the database.
+#[derive(Debug, Clone, Copy)]
+pub enum Permission {
+    ExchangeRates,
+    Orders,
+    Products,
+    Users,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::ExchangeRates => "manage_exchange_rates",
+            Permission::Orders => "manage_orders",
+            Permission::Products => "manage_products",
+            Permission::Users => "manage_users",
+        }
+    }
+}
+
+// Middleware that only lets the request through if one of the user's roles has the permission.
+// Routes declare what they need with
+// `.route_layer(middleware::from_fn_with_state(Permission::Users, require_permission))`
+pub async fn require_permission<B>(
+    State(permission): State<Permission>,
+    Extension(db_pool): Extension<Pool<Sqlite>>,
+    auth_user: AuthUser,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, (StatusCode, String)> {
+    if !has_permission(&db_pool, &auth_user.roles, permission).await? {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("You need the {} permission to do that", permission.as_str()),
+        ));
+    }
+
+    Ok(next.run(request).await)
+}
+
+async fn has_permission(
+    db_pool: &Pool<Sqlite>,
+    roles: &[String],
+    permission: Permission,
+) -> Result<bool, (StatusCode, String)> {
+    // SQLite can't bind a list directly, so the roles are passed in as a JSON array
+    let roles_json = serde_json::to_string(roles).unwrap_or_default();
+    let permission_name = permission.as_str();
+
+    let granted_permission = sqlx::query!(
+        "
+        SELECT permission_name FROM role_permissions
+        WHERE permission_name = $1
+        AND role_name IN (SELECT value FROM json_each($2))
+        LIMIT 1
+        ",
+        permission_name,
+        roles_json,
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unable to check the user's permissions".to_owned(),
+        )
+    })?;
+
+    Ok(granted_permission.is_some())
+}
@@ -0,0 +1,60 @@
+use std::env;
+
+use crate::utils::money::{Currency, Money};
+
+// The price of one line of an order, worked out when the order is placed and then
+// stored with the order so it never changes, even when the product's price does
+pub struct LinePrice {
+    pub unit_price: Money,
+    pub line_subtotal: Money,
+    pub discount: Money,
+    pub tax: Money,
+}
+
+impl LinePrice {
+    // Tax is charged on the price after the discount
+    pub fn new(unit_price: Money, quantity: i64, discount: Money, tax_rate: f64) -> LinePrice {
+        let line_subtotal = unit_price * quantity;
+        let discount = discount.min(line_subtotal);
+        let tax = (line_subtotal - discount).apply_rate(tax_rate);
+
+        LinePrice {
+            unit_price,
+            line_subtotal,
+            discount,
+            tax,
+        }
+    }
+}
+
+// Uses TAX_RATE if it is set, e.g. 0.06 for 6%, otherwise no tax is charged
+pub fn tax_rate() -> f64 {
+    env::var("TAX_RATE")
+        .ok()
+        .and_then(|rate| rate.parse::<f64>().ok())
+        .filter(|rate| rate.is_finite() && *rate >= 0.0)
+        .unwrap_or(0.0)
+}
+
+// A flat rate of SHIPPING_FLAT_RATE, which is waived for subtotals of at least
+// FREE_SHIPPING_THRESHOLD. Only an estimate, since it doesn't know where the order is going.
+pub fn shipping_estimate(subtotal: Money) -> Money {
+    let currency = subtotal.currency();
+    if !subtotal.is_positive()
+        || subtotal >= env_amount("FREE_SHIPPING_THRESHOLD", Money::from_major(100, currency))
+    {
+        return Money::zero(currency);
+    }
+
+    env_amount("SHIPPING_FLAT_RATE", Money::from_major(10, currency))
+}
+
+// Reads a non-negative amount in the store's currency, e.g. 10.50, from the environment,
+// falling back to the default
+fn env_amount(name: &str, default: Money) -> Money {
+    env::var(name)
+        .ok()
+        .and_then(|amount| Money::parse(&amount, Currency::base()).ok())
+        .filter(|amount| !amount.is_negative())
+        .unwrap_or(default)
+}
@@ -0,0 +1,556 @@
+use axum::http::StatusCode;
+use chrono::{Local, NaiveDateTime};
+use sqlx::{Pool, Sqlite, Transaction};
+
+use crate::routes::map_db_error;
+use crate::utils::models::{NewRefundItem, OrderStatus, Refund, RefundDetail, RefundItem};
+use crate::utils::money::Money;
+use crate::utils::orders;
+use crate::utils::payments::{self, PaymentProvider};
+
+// What a refund is for
+pub enum RefundScope {
+    // Some of the items in the order
+    Items(Vec<NewRefundItem>),
+    // An amount that isn't for any items in particular, e.g. to make up for a late delivery
+    Amount(Money),
+    // Whatever hasn't been refunded yet
+    Everything,
+}
+
+// What to refund and how. return_id is set when the refund is for a return.
+pub struct RefundRequest<'a> {
+    pub scope: RefundScope,
+    pub restock: bool,
+    pub note: Option<&'a str>,
+    pub return_id: Option<i64>,
+}
+
+// A line of the order with what has already been refunded for it
+struct RefundableLine {
+    variant_id: i64,
+    quantity: i64,
+    line_total: Money,
+    tax: Money,
+    refunded_quantity: i64,
+    refunded_amount: Money,
+    refunded_tax: Money,
+}
+
+impl RefundableLine {
+    fn remaining_quantity(&self) -> i64 {
+        self.quantity - self.refunded_quantity
+    }
+
+    // The share of the line being refunded and its tax. Refunding the last of a line gives back
+    // whatever is left of it, so rounding never leaves a cent behind.
+    fn refund_for(&self, quantity: i64) -> (Money, Money) {
+        if quantity == self.remaining_quantity() {
+            return (
+                self.line_total - self.refunded_amount,
+                self.tax - self.refunded_tax,
+            );
+        }
+
+        (
+            self.line_total.mul_ratio(quantity, self.quantity),
+            self.tax.mul_ratio(quantity, self.quantity),
+        )
+    }
+}
+
+// Refunds some or all of an order: records the refund and the items it was for, puts the items
+// back into stock if asked, and pays the money back through the payment provider. Once
+// everything has been refunded the order is moved to Refunded.
+// Item refunds are capped at what is left of the order, since an amount may already have been
+// given back for it.
+pub async fn create_refund(
+    payment_provider: &dyn PaymentProvider,
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+    refund_request: RefundRequest<'_>,
+    created_by: Option<&str>,
+) -> Result<Refund, (StatusCode, String)> {
+    let order_option = sqlx::query!(
+        r#"
+        SELECT
+        total_cost AS "total_cost: Money",
+        amount_refunded AS "amount_refunded: Money",
+        order_status AS "order_status: OrderStatus"
+        FROM orders
+        WHERE order_id = $1
+        "#,
+        order_id,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(order) = order_option else {
+        return Err((StatusCode::NOT_FOUND, "Order not found".to_owned()));
+    };
+
+    // Orders that haven't been paid for have nothing to refund, and cancelled ones were already
+    // paid back when they were cancelled
+    if !matches!(
+        order.order_status,
+        OrderStatus::Paid | OrderStatus::Processing | OrderStatus::Shipped | OrderStatus::Delivered
+    ) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("A {:?} order can't be refunded", order.order_status),
+        ));
+    }
+
+    let remaining_amount = order.total_cost.unwrap_or_default() - order.amount_refunded;
+    if !remaining_amount.is_positive() {
+        return Err((
+            StatusCode::CONFLICT,
+            "The order has already been fully refunded".to_owned(),
+        ));
+    }
+
+    let lines = refundable_lines(transaction, order_id).await?;
+
+    // The items being refunded, with their share of the line and its tax
+    let mut refund_items = Vec::new();
+    let amount = match refund_request.scope {
+        RefundScope::Items(items) => {
+            if items.is_empty() {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Choose at least one item to refund".to_owned(),
+                ));
+            }
+
+            for item in items {
+                let Some(line) = lines.iter().find(|line| line.variant_id == item.variant_id)
+                else {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        format!("Variant {} is not in the order", item.variant_id),
+                    ));
+                };
+                if item.quantity <= 0 {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        "Quantities must be at least 1".to_owned(),
+                    ));
+                }
+                if refund_items
+                    .iter()
+                    .any(|refund_item: &RefundItem| refund_item.variant_id == item.variant_id)
+                {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        format!("Variant {} is listed more than once", item.variant_id),
+                    ));
+                }
+                if item.quantity > line.remaining_quantity() {
+                    return Err((
+                        StatusCode::CONFLICT,
+                        format!(
+                            "Only {} of variant {} can still be refunded",
+                            line.remaining_quantity(),
+                            item.variant_id
+                        ),
+                    ));
+                }
+
+                let (amount, tax) = line.refund_for(item.quantity);
+                refund_items.push(RefundItem {
+                    variant_id: item.variant_id,
+                    quantity: item.quantity,
+                    amount,
+                    tax,
+                });
+            }
+
+            let items_total: Money = refund_items.iter().map(|item| item.amount).sum();
+            items_total.min(remaining_amount)
+        }
+        RefundScope::Amount(amount) => {
+            if amount.currency() != remaining_amount.currency() {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("The amount must be in {}", remaining_amount.currency()),
+                ));
+            }
+            if !amount.is_positive() {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "The amount to refund must be more than 0".to_owned(),
+                ));
+            }
+            if amount > remaining_amount {
+                return Err((
+                    StatusCode::CONFLICT,
+                    format!("Only {} can still be refunded", remaining_amount),
+                ));
+            }
+            amount
+        }
+        RefundScope::Everything => {
+            for line in lines.iter().filter(|line| line.remaining_quantity() > 0) {
+                let (amount, tax) = line.refund_for(line.remaining_quantity());
+                refund_items.push(RefundItem {
+                    variant_id: line.variant_id,
+                    quantity: line.remaining_quantity(),
+                    amount,
+                    tax,
+                });
+            }
+            remaining_amount
+        }
+    };
+
+    let local_time_now = Local::now().naive_local();
+    let refund_id = sqlx::query!(
+        "
+        INSERT INTO refunds (order_id, return_id, amount, restocked, note, created_by, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING refund_id
+        ",
+        order_id,
+        refund_request.return_id,
+        amount,
+        refund_request.restock,
+        refund_request.note,
+        created_by,
+        local_time_now,
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(map_db_error)?
+    .refund_id;
+
+    for refund_item in &refund_items {
+        sqlx::query!(
+            "
+            INSERT INTO refund_items (refund_id, order_id, variant_id, quantity, amount, tax)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ",
+            refund_id,
+            order_id,
+            refund_item.variant_id,
+            refund_item.quantity,
+            refund_item.amount,
+            refund_item.tax,
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_db_error)?;
+
+        if refund_request.restock {
+            sqlx::query!(
+                "UPDATE product_variants SET stock = stock + $1 WHERE variant_id = $2",
+                refund_item.quantity,
+                refund_item.variant_id,
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_db_error)?;
+        }
+    }
+
+    // Payments that haven't been captured yet have nothing to pay back. Less is captured when
+    // the order ships instead.
+    payments::refund_payment(payment_provider, transaction, order_id, amount).await?;
+
+    if amount >= remaining_amount {
+        orders::change_status(
+            transaction,
+            order_id,
+            OrderStatus::Refunded,
+            created_by,
+            Some("Everything was refunded"),
+        )
+        .await?;
+
+        payments::settle_for_status(
+            payment_provider,
+            transaction,
+            order_id,
+            OrderStatus::Refunded,
+        )
+        .await?;
+    }
+
+    sqlx::query_as!(
+        Refund,
+        r#"
+        SELECT
+        refund_id,
+        order_id,
+        return_id,
+        credit_note_number AS "credit_note_number!",
+        amount AS "amount: Money",
+        restocked,
+        note,
+        created_by,
+        created_at AS "created_at: NaiveDateTime"
+        FROM refunds
+        WHERE refund_id = $1
+        "#,
+        refund_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(map_db_error)
+}
+
+// Every refund made for an order, oldest first, with the items each one was for
+pub async fn get_refunds(
+    db_pool: &Pool<Sqlite>,
+    order_id: i64,
+) -> Result<Vec<RefundDetail>, (StatusCode, String)> {
+    let refunds = sqlx::query_as!(
+        Refund,
+        r#"
+        SELECT
+        refund_id,
+        order_id,
+        return_id,
+        credit_note_number AS "credit_note_number!",
+        amount AS "amount: Money",
+        restocked,
+        note,
+        created_by,
+        created_at AS "created_at: NaiveDateTime"
+        FROM refunds
+        WHERE order_id = $1
+        ORDER BY refund_id
+        "#,
+        order_id,
+    )
+    .fetch_all(db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let refund_items = sqlx::query!(
+        r#"
+        SELECT
+        refund_id,
+        variant_id,
+        quantity,
+        amount AS "amount: Money",
+        tax AS "tax: Money"
+        FROM refund_items
+        WHERE order_id = $1
+        ORDER BY refund_id, variant_id
+        "#,
+        order_id,
+    )
+    .fetch_all(db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(refunds
+        .into_iter()
+        .map(|refund| RefundDetail {
+            items: refund_items
+                .iter()
+                .filter(|item| item.refund_id == refund.refund_id)
+                .map(|item| RefundItem {
+                    variant_id: item.variant_id,
+                    quantity: item.quantity,
+                    amount: item.amount,
+                    tax: item.tax,
+                })
+                .collect(),
+            refund,
+        })
+        .collect())
+}
+
+// A plain text credit note for a refund, to be printed or attached to an email
+pub async fn credit_note(
+    db_pool: &Pool<Sqlite>,
+    order_id: i64,
+    refund_id: i64,
+) -> Result<String, (StatusCode, String)> {
+    let refund_option = sqlx::query!(
+        r#"
+        SELECT
+        refunds.credit_note_number AS "credit_note_number!",
+        refunds.amount AS "amount: Money",
+        refunds.note,
+        refunds.created_at AS "created_at: NaiveDateTime",
+        orders.creation_time AS "order_time: NaiveDateTime",
+        users.username AS "username?",
+        users.user_email AS "user_email?",
+        personal_info.first_name AS "first_name?",
+        personal_info.last_name AS "last_name?"
+        FROM refunds
+        INNER JOIN orders ON orders.order_id = refunds.order_id
+        LEFT JOIN users ON users.user_id = orders.user_id
+        LEFT JOIN personal_info ON personal_info.user_id = orders.user_id
+        WHERE refunds.refund_id = $1 AND refunds.order_id = $2
+        "#,
+        refund_id,
+        order_id,
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let Some(refund) = refund_option else {
+        return Err((StatusCode::NOT_FOUND, "Refund not found".to_owned()));
+    };
+
+    // The shipping address is used for billing when no billing address was given
+    let address = sqlx::query!(
+        r#"
+        SELECT
+        unit AS "unit!",
+        street AS "street!",
+        city AS "city!",
+        postal_code AS "postal_code!",
+        state_province AS "state_province!",
+        country AS "country!"
+        FROM order_addresses
+        WHERE order_id = $1
+        ORDER BY address_type = 'Billing' DESC
+        LIMIT 1
+        "#,
+        order_id,
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let items = sqlx::query!(
+        r#"
+        SELECT
+        products.product_name,
+        product_variants.sku,
+        refund_items.quantity,
+        refund_items.amount AS "amount: Money",
+        refund_items.tax AS "tax: Money"
+        FROM refund_items
+        INNER JOIN product_variants ON product_variants.variant_id = refund_items.variant_id
+        INNER JOIN products ON products.product_id = product_variants.product_id
+        WHERE refund_items.refund_id = $1
+        ORDER BY refund_items.variant_id
+        "#,
+        refund_id,
+    )
+    .fetch_all(db_pool)
+    .await
+    .map_err(map_db_error)?;
+
+    let mut document = Vec::new();
+    document.push(format!("CREDIT NOTE {}", refund.credit_note_number));
+    document.push("Makang Ikang".to_owned());
+    document.push(String::new());
+    document.push(format!("Date: {}", refund.created_at.format("%Y-%m-%d")));
+    document.push(format!(
+        "Order: #{} placed {}",
+        order_id,
+        refund.order_time.format("%Y-%m-%d")
+    ));
+
+    let customer_name = match (refund.first_name, refund.last_name) {
+        (Some(first_name), Some(last_name)) => Some(format!("{} {}", first_name, last_name)),
+        _ => refund.username,
+    };
+    if let Some(customer_name) = customer_name {
+        document.push(format!("Customer: {}", customer_name));
+    }
+    if let Some(user_email) = refund.user_email {
+        document.push(format!("Email: {}", user_email));
+    }
+    if let Some(address) = address {
+        document.push(format!(
+            "Billing address: {}, {}",
+            address.unit, address.street
+        ));
+        document.push(format!(
+            "                 {} {}, {}, {}",
+            address.postal_code, address.city, address.state_province, address.country
+        ));
+    }
+
+    document.push(String::new());
+    document.push(format!(
+        "{:<40} {:>5} {:>10} {:>10}",
+        "Item", "Qty", "Tax", "Amount"
+    ));
+    let mut items_total = Money::zero(refund.amount.currency());
+    for item in &items {
+        document.push(format!(
+            "{:<40} {:>5} {:>10} {:>10}",
+            format!("{} ({})", item.product_name, item.sku),
+            item.quantity,
+            item.tax.to_decimal_string(),
+            item.amount.to_decimal_string()
+        ));
+        items_total += item.amount;
+    }
+
+    // Amount refunds, and item refunds that were capped, don't add up to the items
+    let adjustment = refund.amount - items_total;
+    if !adjustment.is_zero() {
+        document.push(format!(
+            "{:<40} {:>5} {:>10} {:>10}",
+            "Adjustment",
+            "",
+            "",
+            adjustment.to_decimal_string()
+        ));
+    }
+
+    document.push(String::new());
+    document.push(format!(
+        "{:<57} {:>10}",
+        format!("Total refunded ({})", refund.amount.currency()),
+        refund.amount.to_decimal_string()
+    ));
+    if let Some(note) = refund.note {
+        document.push(String::new());
+        document.push(format!("Note: {}", note));
+    }
+
+    Ok(document.join("\n") + "\n")
+}
+
+async fn refundable_lines(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: i64,
+) -> Result<Vec<RefundableLine>, (StatusCode, String)> {
+    let lines = sqlx::query!(
+        r#"
+        SELECT
+        order_items.variant_id,
+        order_items.quantity AS "quantity: i64",
+        order_items.line_subtotal - order_items.discount + order_items.tax AS "line_total!: Money",
+        order_items.tax AS "tax: Money",
+        COALESCE(SUM(refund_items.quantity), 0) AS "refunded_quantity!: i64",
+        COALESCE(SUM(refund_items.amount), 0) AS "refunded_amount!: Money",
+        COALESCE(SUM(refund_items.tax), 0) AS "refunded_tax!: Money"
+        FROM order_items
+        LEFT JOIN refund_items
+        ON refund_items.order_id = order_items.order_id
+        AND refund_items.variant_id = order_items.variant_id
+        WHERE order_items.order_id = $1
+        GROUP BY order_items.variant_id
+        "#,
+        order_id,
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(map_db_error)?;
+
+    Ok(lines
+        .into_iter()
+        .map(|line| RefundableLine {
+            variant_id: line.variant_id,
+            quantity: line.quantity,
+            line_total: line.line_total,
+            tax: line.tax,
+            refunded_quantity: line.refunded_quantity,
+            refunded_amount: line.refunded_amount,
+            refunded_tax: line.refunded_tax,
+        })
+        .collect())
+}
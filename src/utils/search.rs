@@ -0,0 +1,81 @@
+// Splits what the user typed into words, dropping punctuation so that it can't be
+// used to inject FTS5 query syntax
+pub fn search_terms(query: &str) -> Vec<String> {
+    query
+        .split(|character: char| !character.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase())
+        .collect()
+}
+
+// Builds an FTS5 MATCH expression where every term has to appear, either as a whole
+// word or as the start of one, e.g. "chick" matches "chicken"
+pub fn fts_match_expression(terms: &[String]) -> String {
+    terms
+        .iter()
+        .map(|term| format!("\"{}\"*", term))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// How far a product's text is from the search terms, allowing for typos.
+// Returns None if any of the terms isn't close to a word in the text.
+pub fn fuzzy_distance(terms: &[String], text: &str) -> Option<usize> {
+    let words = search_terms(text);
+    let mut total_distance = 0;
+
+    for term in terms {
+        let closest_distance = words
+            .iter()
+            .map(|word| {
+                // Typing the start of a word is as good as typing all of it
+                let word_start: String = word.chars().take(term.chars().count()).collect();
+                levenshtein(term, word).min(levenshtein(term, &word_start))
+            })
+            .min()?;
+
+        if closest_distance > allowed_typos(term) {
+            return None;
+        }
+        total_distance += closest_distance;
+    }
+
+    Some(total_distance)
+}
+
+// Short words only get one typo, otherwise nearly everything would match
+fn allowed_typos(term: &str) -> usize {
+    match term.chars().count() {
+        0..=2 => 0,
+        3..=5 => 1,
+        _ => 2,
+    }
+}
+
+// The number of single character insertions, deletions or substitutions
+// needed to turn one string into the other
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut previous_row: Vec<usize> = (0..=b_chars.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current_row = vec![i + 1];
+
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution_cost = match a_char == *b_char {
+                true => 0,
+                false => 1,
+            };
+
+            current_row.push(
+                (previous_row[j] + substitution_cost)
+                    .min(previous_row[j + 1] + 1)
+                    .min(current_row[j] + 1),
+            );
+        }
+
+        previous_row = current_row;
+    }
+
+    previous_row[b_chars.len()]
+}
@@ -0,0 +1,337 @@
+use chrono::{naive::NaiveDateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+use std::time::Duration;
+use uuid::Uuid;
+
+// How long a refresh token can be used for before the user has to login again
+pub const REFRESH_TOKEN_LIFETIME_DAYS: i64 = 30;
+
+// How often the background task clears out expired sessions
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Details about the client that are recorded alongside a new session
+pub struct SessionInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+// A session that was just created or refreshed, along with its new refresh token
+pub struct IssuedSession {
+    pub session_id: String,
+    pub user_id: String,
+    pub refresh_token: String,
+}
+
+pub enum RefreshOutcome {
+    Rotated(IssuedSession),
+    // The token had already been rotated, so the whole session has been revoked
+    Reused,
+    // The token doesn't exist or has expired
+    Invalid,
+}
+
+// Keeps track of logged in users in the sessions table so that sessions survive
+// restarts and can be shared between multiple server processes.
+// Each session is a family of refresh tokens, only the newest of which can be used.
+#[derive(Clone)]
+pub struct SessionStore {
+    db_pool: Pool<Sqlite>,
+}
+
+impl SessionStore {
+    pub fn new(db_pool: Pool<Sqlite>) -> SessionStore {
+        SessionStore { db_pool }
+    }
+
+    pub async fn insert(
+        &self,
+        user_id: &str,
+        session_info: SessionInfo,
+    ) -> Result<IssuedSession, sqlx::Error> {
+        let session_id = Uuid::new_v4().simple().to_string();
+        let refresh_token = new_refresh_token();
+        let token_hash = hash_token(&refresh_token);
+        let now = Utc::now().naive_utc();
+        let expires_at = now + chrono::Duration::days(REFRESH_TOKEN_LIFETIME_DAYS);
+
+        let mut transaction = self.db_pool.begin().await?;
+
+        sqlx::query!(
+            "
+            INSERT INTO sessions
+            (session_id, token_hash, user_id, created_at, expires_at, last_seen_at, user_agent, ip_address)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ",
+            session_id,
+            token_hash,
+            user_id,
+            now,
+            expires_at,
+            now,
+            session_info.user_agent,
+            session_info.ip_address,
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query!(
+            "
+            INSERT INTO refresh_tokens (token_hash, session_id, created_at, expires_at)
+            VALUES ($1, $2, $3, $4)
+            ",
+            token_hash,
+            session_id,
+            now,
+            expires_at,
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(IssuedSession {
+            session_id,
+            user_id: user_id.to_owned(),
+            refresh_token,
+        })
+    }
+
+    // Swaps the given refresh token for a new one in the same session
+    pub async fn rotate(&self, refresh_token: &str) -> Result<RefreshOutcome, sqlx::Error> {
+        let token_hash = hash_token(refresh_token);
+        let now = Utc::now().naive_utc();
+
+        let mut transaction = self.db_pool.begin().await?;
+
+        let issued_token = sqlx::query!(
+            "
+            SELECT
+            refresh_tokens.session_id,
+            refresh_tokens.expires_at,
+            refresh_tokens.used_at,
+            sessions.user_id
+            FROM refresh_tokens
+            INNER JOIN sessions ON sessions.session_id = refresh_tokens.session_id
+            WHERE refresh_tokens.token_hash = $1
+            ",
+            token_hash,
+        )
+        .fetch_optional(&mut transaction)
+        .await?;
+
+        let Some(issued_token) = issued_token else {
+            return Ok(RefreshOutcome::Invalid);
+        };
+
+        // Only one request can mark the token as used, so a concurrent replay counts as reuse too
+        let mark_used_result = sqlx::query!(
+            "UPDATE refresh_tokens SET used_at = $1 WHERE token_hash = $2 AND used_at IS NULL",
+            now,
+            token_hash,
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        if issued_token.used_at.is_some() || mark_used_result.rows_affected() == 0 {
+            // An old token is being replayed, it may have been stolen so end the whole family
+            sqlx::query!(
+                "DELETE FROM sessions WHERE session_id = $1",
+                issued_token.session_id,
+            )
+            .execute(&mut transaction)
+            .await?;
+            transaction.commit().await?;
+
+            return Ok(RefreshOutcome::Reused);
+        }
+
+        if issued_token.expires_at <= now {
+            return Ok(RefreshOutcome::Invalid);
+        }
+
+        let new_refresh_token = new_refresh_token();
+        let new_token_hash = hash_token(&new_refresh_token);
+        let expires_at = now + chrono::Duration::days(REFRESH_TOKEN_LIFETIME_DAYS);
+
+        sqlx::query!(
+            "
+            INSERT INTO refresh_tokens (token_hash, session_id, created_at, expires_at)
+            VALUES ($1, $2, $3, $4)
+            ",
+            new_token_hash,
+            issued_token.session_id,
+            now,
+            expires_at,
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query!(
+            "
+            UPDATE sessions
+            SET token_hash = $1,
+                expires_at = $2,
+                last_seen_at = $3
+            WHERE session_id = $4
+            ",
+            new_token_hash,
+            expires_at,
+            now,
+            issued_token.session_id,
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(RefreshOutcome::Rotated(IssuedSession {
+            session_id: issued_token.session_id,
+            user_id: issued_token.user_id,
+            refresh_token: new_refresh_token,
+        }))
+    }
+
+    // Revoked tokens are rejected until they would have expired on their own
+    pub async fn revoke(&self, jti: &str, expires_at: NaiveDateTime) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO revoked_tokens (jti, expires_at) VALUES ($1, $2)",
+            jti,
+            expires_at,
+        )
+        .execute(&self.db_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn is_revoked(&self, jti: &str) -> Result<bool, sqlx::Error> {
+        let revoked_token = sqlx::query!("SELECT jti FROM revoked_tokens WHERE jti = $1", jti)
+            .fetch_optional(&self.db_pool)
+            .await?;
+
+        Ok(revoked_token.is_some())
+    }
+
+    // Removing a session also removes all of its refresh tokens
+    pub async fn remove(&self, session_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM sessions WHERE session_id = $1", session_id)
+            .execute(&self.db_pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn prune_expired(&self) -> Result<u64, sqlx::Error> {
+        let now = Utc::now().naive_utc();
+
+        let sessions_result = sqlx::query!("DELETE FROM sessions WHERE expires_at <= $1", now)
+            .execute(&self.db_pool)
+            .await?;
+
+        let revoked_tokens_result =
+            sqlx::query!("DELETE FROM revoked_tokens WHERE expires_at <= $1", now)
+                .execute(&self.db_pool)
+                .await?;
+
+        Ok(sessions_result.rows_affected() + revoked_tokens_result.rows_affected())
+    }
+}
+
+// Periodically deletes expired sessions and revocations so the tables don't grow forever
+pub fn spawn_pruning_task(session_store: SessionStore) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(error) = session_store.prune_expired().await {
+                eprintln!("Unable to prune expired sessions: {}", error);
+            }
+        }
+    });
+}
+
+// Two random UUIDs give a token that is practically impossible to guess
+fn new_refresh_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+// Only a hash of the token is stored so a leaked database can't be used to hijack sessions
+pub(crate) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing;
+
+    async fn start_session(db_pool: &Pool<Sqlite>) -> (SessionStore, IssuedSession) {
+        let user_id = testing::create_user(db_pool, &["customer"]).await;
+        let session_store = SessionStore::new(db_pool.clone());
+        let session_info = SessionInfo {
+            user_agent: None,
+            ip_address: None,
+        };
+        let session = session_store.insert(&user_id, session_info).await.unwrap();
+
+        (session_store, session)
+    }
+
+    async fn rotate(session_store: &SessionStore, refresh_token: &str) -> IssuedSession {
+        match session_store.rotate(refresh_token).await.unwrap() {
+            RefreshOutcome::Rotated(session) => session,
+            _ => panic!("The refresh token wasn't rotated"),
+        }
+    }
+
+    async fn session_exists(db_pool: &Pool<Sqlite>, session_id: &str) -> bool {
+        sqlx::query!(
+            "SELECT session_id FROM sessions WHERE session_id = $1",
+            session_id
+        )
+        .fetch_optional(db_pool)
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    #[tokio::test]
+    async fn rotating_keeps_the_session() {
+        let db_pool = testing::test_db_pool().await;
+        let (session_store, session) = start_session(&db_pool).await;
+
+        let rotated = rotate(&session_store, &session.refresh_token).await;
+        let rotated_again = rotate(&session_store, &rotated.refresh_token).await;
+
+        assert_eq!(rotated.session_id, session.session_id);
+        assert_eq!(rotated_again.session_id, session.session_id);
+        assert_ne!(rotated.refresh_token, session.refresh_token);
+    }
+
+    #[tokio::test]
+    async fn reusing_a_refresh_token_revokes_the_session() {
+        let db_pool = testing::test_db_pool().await;
+        let (session_store, session) = start_session(&db_pool).await;
+        let rotated = rotate(&session_store, &session.refresh_token).await;
+
+        let outcome = session_store.rotate(&session.refresh_token).await.unwrap();
+        assert!(matches!(outcome, RefreshOutcome::Reused));
+        assert!(!session_exists(&db_pool, &session.session_id).await);
+
+        // The token it was swapped for went with the rest of the session
+        let outcome = session_store.rotate(&rotated.refresh_token).await.unwrap();
+        assert!(matches!(outcome, RefreshOutcome::Invalid));
+    }
+
+    #[tokio::test]
+    async fn unknown_refresh_tokens_are_invalid() {
+        let db_pool = testing::test_db_pool().await;
+        let session_store = SessionStore::new(db_pool);
+
+        let outcome = session_store.rotate("not a token").await.unwrap();
+        assert!(matches!(outcome, RefreshOutcome::Invalid));
+    }
+}
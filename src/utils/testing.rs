@@ -0,0 +1,149 @@
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
+use uuid::Uuid;
+
+use crate::utils::models::OrderStatus;
+use crate::utils::money::Money;
+
+// A fresh database with every migration run, including the sample products.
+// Each connection to an in-memory database gets its own, so the pool only has one.
+pub async fn test_db_pool() -> Pool<Sqlite> {
+    let db_pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Unable to create the test database");
+
+    sqlx::migrate!("./migrations")
+        .run(&db_pool)
+        .await
+        .expect("Unable to run migrations");
+
+    db_pool
+}
+
+// A user with the given roles, e.g. "customer" or "admin"
+pub async fn create_user(db_pool: &Pool<Sqlite>, roles: &[&str]) -> String {
+    let user_id = Uuid::new_v4().simple().to_string();
+    let user_email = format!("{}@example.com", user_id);
+
+    sqlx::query!(
+        "INSERT INTO users (user_id, username, user_email, user_password_hash) VALUES ($1, $2, $3, $4)",
+        user_id,
+        "tester",
+        user_email,
+        "not a real hash",
+    )
+    .execute(db_pool)
+    .await
+    .unwrap();
+
+    for role in roles {
+        sqlx::query!(
+            "INSERT INTO user_roles (user_id, role_name) VALUES ($1, $2)",
+            user_id,
+            role,
+        )
+        .execute(db_pool)
+        .await
+        .unwrap();
+    }
+
+    user_id
+}
+
+// A pending order for some of the given variant at its current price, with its stock taken
+pub async fn create_order(
+    db_pool: &Pool<Sqlite>,
+    user_id: &str,
+    variant_id: i64,
+    quantity: i64,
+) -> (i64, Money) {
+    let unit_price = sqlx::query!(
+        r#"SELECT price AS "price: Money" FROM product_variants WHERE variant_id = $1"#,
+        variant_id,
+    )
+    .fetch_one(db_pool)
+    .await
+    .unwrap()
+    .price;
+    let total_cost = unit_price * quantity;
+
+    let local_time_now = Local::now().naive_local();
+    let order_id = sqlx::query!(
+        "
+        INSERT INTO orders (user_id, creation_time, order_status, total_cost)
+        VALUES ($1, $2, $3, $4)
+        ",
+        user_id,
+        local_time_now,
+        OrderStatus::Pending,
+        total_cost,
+    )
+    .execute(db_pool)
+    .await
+    .unwrap()
+    .last_insert_rowid();
+
+    sqlx::query!(
+        "
+        INSERT INTO order_items (order_id, variant_id, quantity, unit_price, line_subtotal)
+        VALUES ($1, $2, $3, $4, $5)
+        ",
+        order_id,
+        variant_id,
+        quantity,
+        unit_price,
+        total_cost,
+    )
+    .execute(db_pool)
+    .await
+    .unwrap();
+
+    sqlx::query!(
+        "UPDATE product_variants SET stock = stock - $1 WHERE variant_id = $2",
+        quantity,
+        variant_id,
+    )
+    .execute(db_pool)
+    .await
+    .unwrap();
+
+    (order_id, total_cost)
+}
+
+pub async fn variant_stock(db_pool: &Pool<Sqlite>, variant_id: i64) -> i64 {
+    sqlx::query_scalar!(
+        "SELECT stock FROM product_variants WHERE variant_id = $1",
+        variant_id
+    )
+    .fetch_one(db_pool)
+    .await
+    .unwrap()
+}
+
+pub async fn order_status(db_pool: &Pool<Sqlite>, order_id: i64) -> OrderStatus {
+    sqlx::query_scalar!(
+        r#"SELECT order_status AS "order_status: OrderStatus" FROM orders WHERE order_id = $1"#,
+        order_id
+    )
+    .fetch_one(db_pool)
+    .await
+    .unwrap()
+}
+
+// The X-Webhook-Signature header the payment provider would send with the body
+pub fn sign_webhook(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    format!(
+        "t={},v1={}",
+        timestamp,
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
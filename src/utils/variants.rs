@@ -0,0 +1,23 @@
+use sqlx::{Pool, Sqlite};
+
+// The SKU given to a product's default variant when the admin doesn't pick one
+pub fn default_sku(product_id: i64) -> String {
+    format!("MI-{:05}", product_id)
+}
+
+// Checks whether another variant is already using the SKU
+pub async fn check_sku_taken(
+    db_pool: &Pool<Sqlite>,
+    sku: &String,
+    variant_id: Option<i64>,
+) -> Result<bool, sqlx::Error> {
+    let variant = sqlx::query!(
+        "SELECT variant_id FROM product_variants WHERE sku = $1 AND variant_id IS NOT $2",
+        sku,
+        variant_id,
+    )
+    .fetch_optional(db_pool)
+    .await?;
+
+    Ok(variant.is_some())
+}
@@ -0,0 +1,146 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+
+// Holds the timestamp and signature of a webhook, e.g. "t=1700000000,v1=5257a869..."
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+// Older webhooks are rejected so a captured request can't be replayed later
+const SIGNATURE_TOLERANCE_SECONDS: u64 = 5 * 60;
+
+// The secret shared with the payment provider, set with PAYMENT_WEBHOOK_SECRET
+pub fn webhook_secret() -> Option<String> {
+    env::var("PAYMENT_WEBHOOK_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+}
+
+// Checks the webhook was signed by the provider. The signature is an HMAC-SHA256 of the
+// timestamp and the body joined by a dot, so neither can be changed without the secret.
+pub fn verify_signature(
+    secret: &str,
+    signature_header: &str,
+    body: &[u8],
+    now: i64,
+) -> Result<(), String> {
+    let mut signed_timestamp = None;
+    let mut signatures = Vec::new();
+    for part in signature_header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => signed_timestamp = Some(value),
+            Some(("v1", value)) => signatures.push(value),
+            _ => {}
+        }
+    }
+
+    // The timestamp is signed exactly as it was sent, e.g. "0123" isn't the same as "123"
+    let Some((signed_timestamp, Ok(timestamp))) =
+        signed_timestamp.map(|value| (value, value.parse::<i64>()))
+    else {
+        return Err("The signature doesn't have a timestamp".to_owned());
+    };
+    if now.abs_diff(timestamp) > SIGNATURE_TOLERANCE_SECONDS {
+        return Err("The signature has expired".to_owned());
+    }
+
+    // The provider can send more than one signature while it is rotating secrets
+    let signature_matches = signatures.into_iter().any(|signature| {
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(signed_timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+
+        // Compared in constant time so the signature can't be guessed a byte at a time
+        mac.verify_slice(&signature).is_ok()
+    });
+
+    match signature_matches {
+        true => Ok(()),
+        false => Err("The signature is not valid".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::sign_webhook;
+
+    const SECRET: &str = "testsecret";
+    const BODY: &[u8] =
+        br#"{"id": "evt_1", "type": "payment.captured", "data": {"reference": "fake_1"}}"#;
+    const NOW: i64 = 1_700_000_000;
+
+    #[test]
+    fn accepts_a_signed_webhook() {
+        let header = sign_webhook(SECRET, &NOW.to_string(), BODY);
+
+        assert_eq!(verify_signature(SECRET, &header, BODY, NOW), Ok(()));
+    }
+
+    #[test]
+    fn accepts_any_of_several_signatures() {
+        let header = sign_webhook(SECRET, &NOW.to_string(), BODY);
+        let header = header.replace("v1=", "v1=00ff,v1=");
+
+        assert_eq!(verify_signature(SECRET, &header, BODY, NOW), Ok(()));
+    }
+
+    #[test]
+    fn rejects_the_wrong_secret() {
+        let header = sign_webhook("someothersecret", &NOW.to_string(), BODY);
+
+        assert!(verify_signature(SECRET, &header, BODY, NOW).is_err());
+    }
+
+    #[test]
+    fn rejects_a_changed_body() {
+        let header = sign_webhook(SECRET, &NOW.to_string(), BODY);
+        let body = String::from_utf8_lossy(BODY).replace("fake_1", "fake_2");
+
+        assert!(verify_signature(SECRET, &header, body.as_bytes(), NOW).is_err());
+    }
+
+    #[test]
+    fn rejects_a_stale_signature() {
+        let timestamp = NOW - SIGNATURE_TOLERANCE_SECONDS as i64 - 1;
+        let header = sign_webhook(SECRET, &timestamp.to_string(), BODY);
+
+        assert_eq!(
+            verify_signature(SECRET, &header, BODY, NOW),
+            Err("The signature has expired".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_extreme_timestamps_without_overflowing() {
+        let cases = [
+            (i64::MIN, NOW),
+            (i64::MAX, NOW),
+            (i64::MIN, i64::MAX),
+            (i64::MAX, i64::MIN),
+        ];
+        for (timestamp, now) in cases {
+            let header = sign_webhook(SECRET, &timestamp.to_string(), BODY);
+
+            assert!(verify_signature(SECRET, &header, BODY, now).is_err());
+        }
+    }
+
+    #[test]
+    fn checks_the_timestamp_as_it_was_sent() {
+        let header = sign_webhook(SECRET, &format!("0{}", NOW), BODY);
+
+        assert_eq!(verify_signature(SECRET, &header, BODY, NOW), Ok(()));
+    }
+
+    #[test]
+    fn rejects_a_missing_timestamp() {
+        assert!(verify_signature(SECRET, "v1=00ff", BODY, NOW).is_err());
+        assert!(verify_signature(SECRET, "t=soon,v1=00ff", BODY, NOW).is_err());
+    }
+}